@@ -49,6 +49,10 @@ MARK_FIX_753(UBLK_U_CMD_GET_PARAMS);
 MARK_FIX_753(UBLK_U_CMD_START_USER_RECOVERY);
 MARK_FIX_753(UBLK_U_CMD_END_USER_RECOVERY);
 MARK_FIX_753(UBLK_U_CMD_GET_DEV_INFO2);
+MARK_FIX_753(UBLK_U_CMD_GET_FEATURES);
+MARK_FIX_753(UBLK_U_IO_FETCH_REQ);
+MARK_FIX_753(UBLK_U_IO_COMMIT_AND_FETCH_REQ);
+MARK_FIX_753(UBLK_U_IO_NEED_GET_DATA);
 const int Fix753_UBLK_IO_RES_ABORT = UBLK_IO_RES_ABORT;
     "#;
 
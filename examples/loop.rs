@@ -1,21 +1,31 @@
 use anyhow::Result;
 use io_uring::{opcode, squeue, types};
-use libublk::io::{UblkDev, UblkIOCtx, UblkQueueCtx};
-use libublk::{ctrl::UblkCtrl, UblkError};
+use libublk::ctrl::{UblkCtrl, UblkCtrlBuilder, UblkParamsBuilder};
+use libublk::exec::{UblkAsyncHandler, UblkIoTask};
+use libublk::io::{UblkDev, UblkIOCtx, UblkIoOp, UblkQueueCtx, UblkQueueHandler, UblkTarget};
+use libublk::UblkError;
 use log::trace;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::os::unix::io::AsRawFd;
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct LoJson {
     back_file_path: String,
     direct_io: i32,
 }
 
+// exported as `target_data`
+#[derive(Debug, Serialize, Deserialize)]
+struct LoData {
+    #[serde(rename = "loop")]
+    lo: LoJson,
+}
+
 struct LoopTgt {
     back_file_path: String,
     back_file: std::fs::File,
     direct_io: i32,
+    async_io: bool,
 }
 
 fn lo_file_size(f: &std::fs::File) -> Result<u64> {
@@ -31,7 +41,7 @@ fn lo_file_size(f: &std::fs::File) -> Result<u64> {
 }
 
 // setup loop target
-fn lo_init_tgt(dev: &mut UblkDev, lo: &LoopTgt) -> Result<serde_json::Value, UblkError> {
+fn lo_init_tgt(dev: &mut UblkDev, lo: &LoopTgt) -> Result<LoData, UblkError> {
     trace!("loop: init_tgt {}", dev.dev_info.dev_id);
     if lo.direct_io != 0 {
         unsafe {
@@ -48,31 +58,28 @@ fn lo_init_tgt(dev: &mut UblkDev, lo: &LoopTgt) -> Result<serde_json::Value, Ubl
         tgt.dev_size = lo_file_size(&lo.back_file).unwrap();
         tgt.dev_size
     };
-    dev.set_default_params(dev_size);
+    dev.set_params(UblkParamsBuilder::new(dev_size))?;
 
-    Ok(
-        serde_json::json!({"loop": LoJson { back_file_path: lo.back_file_path.clone(), direct_io: 1 } }),
-    )
+    Ok(LoData {
+        lo: LoJson {
+            back_file_path: lo.back_file_path.clone(),
+            direct_io: 1,
+        },
+    })
 }
 
-fn loop_queue_tgt_io(
-    io: &mut UblkIOCtx,
-    tag: u32,
-    iod: &libublk::sys::ublksrv_io_desc,
-) -> Result<i32, UblkError> {
-    let off = (iod.start_sector << 9) as u64;
-    let bytes = (iod.nr_sectors << 9) as u32;
-    let op = iod.op_flags & 0xff;
-    let data = UblkIOCtx::build_user_data(tag as u16, op, 0, true);
+fn loop_queue_tgt_io(io: &mut UblkIOCtx, tag: u32) -> Result<i32, UblkError> {
+    let desc = io.io_desc().ok_or(UblkError::OtherError(-libc::EINVAL))?;
+    let off = desc.offset();
+    let bytes = desc.len() as u32;
+    let op = desc.op();
+    let data = UblkIOCtx::build_user_data(tag as u16, op.into(), 0, true);
     let buf_addr = io.io_buf_addr();
+    let buf_index = io.io_buf_index();
     let r = io.get_ring();
 
-    if op == libublk::sys::UBLK_IO_OP_WRITE_ZEROES || op == libublk::sys::UBLK_IO_OP_DISCARD {
-        return Err(UblkError::OtherError(-libc::EINVAL));
-    }
-
     match op {
-        libublk::sys::UBLK_IO_OP_FLUSH => {
+        UblkIoOp::Flush => {
             let sqe = &opcode::SyncFileRange::new(types::Fixed(1), bytes)
                 .offset(off)
                 .build()
@@ -82,22 +89,32 @@ fn loop_queue_tgt_io(
                 r.submission().push(sqe).expect("submission fail");
             }
         }
-        libublk::sys::UBLK_IO_OP_READ => {
-            let sqe = &opcode::Read::new(types::Fixed(1), buf_addr, bytes)
-                .offset(off)
-                .build()
-                .flags(squeue::Flags::FIXED_FILE)
-                .user_data(data);
+        UblkIoOp::Read => {
+            let sqe = &match buf_index {
+                Some(index) => opcode::ReadFixed::new(types::Fixed(1), buf_addr, bytes, index)
+                    .offset(off)
+                    .build(),
+                None => opcode::Read::new(types::Fixed(1), buf_addr, bytes)
+                    .offset(off)
+                    .build(),
+            }
+            .flags(squeue::Flags::FIXED_FILE)
+            .user_data(data);
             unsafe {
                 r.submission().push(sqe).expect("submission fail");
             }
         }
-        libublk::sys::UBLK_IO_OP_WRITE => {
-            let sqe = &opcode::Write::new(types::Fixed(1), buf_addr, bytes)
-                .offset(off)
-                .build()
-                .flags(squeue::Flags::FIXED_FILE)
-                .user_data(data);
+        UblkIoOp::Write => {
+            let sqe = &match buf_index {
+                Some(index) => opcode::WriteFixed::new(types::Fixed(1), buf_addr, bytes, index)
+                    .offset(off)
+                    .build(),
+                None => opcode::Write::new(types::Fixed(1), buf_addr, bytes)
+                    .offset(off)
+                    .build(),
+            }
+            .flags(squeue::Flags::FIXED_FILE)
+            .user_data(data);
             unsafe {
                 r.submission().push(sqe).expect("submission fail");
             }
@@ -108,11 +125,18 @@ fn loop_queue_tgt_io(
     Ok(1)
 }
 
-fn loop_handle_io(ctx: &UblkQueueCtx, i: &mut UblkIOCtx) -> Result<i32, UblkError> {
-    let tag = i.get_tag();
+struct LoopQueue {}
+
+impl UblkQueueHandler for LoopQueue {
+    fn handle_io(&mut self, _ctx: &UblkQueueCtx, i: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        let tag = i.get_tag();
+
+        loop_queue_tgt_io(i, tag)
+    }
 
     // our IO on backing file is done
-    if i.is_tgt_io() {
+    fn handle_tgt_io(&mut self, ctx: &UblkQueueCtx, i: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        let tag = i.get_tag();
         let user_data = i.user_data();
         let res = i.result();
         let cqe_tag = UblkIOCtx::user_data_to_tag(user_data);
@@ -124,21 +148,65 @@ fn loop_handle_io(ctx: &UblkQueueCtx, i: &mut UblkIOCtx) -> Result<i32, UblkErro
 
             return Ok(0);
         }
+
+        // retry
+        self.handle_io(ctx, i)
+    }
+}
+
+// same with LoopQueue, but IO on backing file is awaited in one async task
+async fn lo_handle_io_async(io: UblkIoTask) -> i32 {
+    let desc = io.io_desc();
+    let off = desc.offset();
+    let bytes = desc.len() as u32;
+    let fd = types::Fixed(1);
+
+    let buf = io.io_buf_addr();
+
+    loop {
+        // io buffer covers `bytes`, and is live until the io is completed
+        let res = match (desc.op(), io.io_buf_index()) {
+            (UblkIoOp::Flush, _) => io.fsync(fd).await,
+            (UblkIoOp::Read, Some(i)) => unsafe { io.read_fixed(fd, buf, bytes, off, i) }.await,
+            (UblkIoOp::Read, None) => unsafe { io.read(fd, buf, bytes, off) }.await,
+            (UblkIoOp::Write, Some(i)) => unsafe { io.write_fixed(fd, buf, bytes, off, i) }.await,
+            (UblkIoOp::Write, None) => unsafe { io.write(fd, buf, bytes, off) }.await,
+            _ => -libc::EINVAL,
+        };
+
+        if res != -libc::EAGAIN {
+            return res;
+        }
     }
+}
+
+impl UblkTarget for LoopTgt {
+    type Data = LoData;
 
-    // either start to handle or retry
-    let _iod = ctx.get_iod(tag);
-    let iod = unsafe { &*_iod };
+    fn init(&mut self, dev: &mut UblkDev) -> Result<LoData, UblkError> {
+        lo_init_tgt(dev, self)
+    }
 
-    loop_queue_tgt_io(i, tag, iod)
+    fn queue_handler(
+        &self,
+        _dev: &UblkDev,
+        _q_id: u16,
+    ) -> Result<Box<dyn UblkQueueHandler>, UblkError> {
+        if self.async_io {
+            Ok(Box::new(UblkAsyncHandler::new(lo_handle_io_async)))
+        } else {
+            Ok(Box::new(LoopQueue {}))
+        }
+    }
 }
 
 fn test_add() {
     let back_file = std::env::args().nth(2).unwrap();
+    let async_io = std::env::args().nth(3).as_deref() == Some("async");
     let _pid = unsafe { libc::fork() };
 
     if _pid == 0 {
-        // LooTgt has to live in the whole device lifetime
+        // LoopTgt is owned by the device, and lives in the whole device lifetime
         let lo = LoopTgt {
             back_file: std::fs::OpenOptions::new()
                 .read(true)
@@ -146,21 +214,19 @@ fn test_add() {
                 .open(&back_file)
                 .unwrap(),
             direct_io: 1,
+            async_io,
             back_file_path: back_file.clone(),
         };
         libublk::ublk_tgt_worker(
-            "loop".to_string(),
-            -1,
-            1,
-            64,
-            512_u32 * 1024,
-            0,
-            true,
-            0,
-            |dev: &mut UblkDev| lo_init_tgt(dev, &lo),
-            loop_handle_io,
+            UblkCtrlBuilder::default()
+                .name("loop")
+                .nr_queues(1)
+                .depth(64)
+                .io_buf_bytes(512_u32 * 1024)
+                .dev_flags(libublk::io::UBLK_DEV_F_FIXED_BUF),
+            lo,
             |dev_id| {
-                let mut ctrl = UblkCtrl::new(dev_id, 0, 0, 0, 0, false).unwrap();
+                let mut ctrl = UblkCtrl::new_simple(dev_id).unwrap();
 
                 ctrl.dump();
             },
@@ -174,7 +240,7 @@ fn test_add() {
 fn test_del() {
     let s = std::env::args().nth(2).unwrap_or_else(|| "0".to_string());
     let dev_id = s.parse::<i32>().unwrap();
-    let mut ctrl = UblkCtrl::new(dev_id as i32, 0, 0, 0, 0, false).unwrap();
+    let mut ctrl = UblkCtrl::new_simple(dev_id).unwrap();
 
     ctrl.del().unwrap();
 }
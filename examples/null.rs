@@ -1,10 +1,11 @@
-use libublk::io::{UblkDev, UblkIOCtx, UblkQueue, UblkQueueCtx};
-use libublk::{ctrl::UblkCtrl, UblkError};
+use libublk::ctrl::{UblkCtrl, UblkCtrlBuilder};
+use libublk::io::{UblkDev, UblkIOCtx, UblkQueue};
+use libublk::UblkError;
 use std::sync::Arc;
 
-fn null_handle_io(ctx: &UblkQueueCtx, io: &mut UblkIOCtx, park: bool) -> Result<i32, UblkError> {
-    let iod = ctx.get_iod(io.get_tag());
-    let bytes = unsafe { (*iod).nr_sectors << 9 } as i32;
+fn null_handle_io(io: &mut UblkIOCtx, park: bool) -> Result<i32, UblkError> {
+    let desc = io.io_desc().ok_or(UblkError::OtherError(-libc::EINVAL))?;
+    let bytes = desc.len() as i32;
 
     if !park {
         io.complete_io(bytes);
@@ -19,7 +20,14 @@ fn test_add(dev_id: i32) {
     let park = s.parse::<i32>().unwrap();
     let nr_queues = 2; //two queues
                        //io depth: 64, max buf size: 512KB
-    let mut ctrl = UblkCtrl::new(dev_id, nr_queues, 64, 512 << 10, 0, true).unwrap();
+    let mut ctrl = UblkCtrlBuilder::default()
+        .name("null")
+        .id(dev_id)
+        .nr_queues(nr_queues)
+        .depth(64)
+        .io_buf_bytes(512 << 10)
+        .build()
+        .unwrap();
 
     //target specific initialization is done in this closure
     let tgt_init = |dev: &mut UblkDev| {
@@ -47,12 +55,11 @@ fn test_add(dev_id: i32) {
         let dev = Arc::clone(&ublk_dev);
         threads.push(std::thread::spawn(move || {
             let mut queue = UblkQueue::new(q as u16, &dev).unwrap();
-            let ctx = queue.make_queue_ctx();
 
             //IO handling closure(FnMut), we are driven by io_uring CQE, and
             //this closure is called for every incoming CQE(io command or
             //target io completion)
-            let io_handler = move |io: &mut UblkIOCtx| null_handle_io(&ctx, io, park != 0);
+            let io_handler = move |io: &mut UblkIOCtx| null_handle_io(io, park != 0);
             queue.wait_and_handle_io(io_handler);
         }));
     }
@@ -67,11 +74,27 @@ fn test_add(dev_id: i32) {
 fn test_del() {
     let s = std::env::args().nth(2).unwrap_or_else(|| "0".to_string());
     let dev_id = s.parse::<i32>().unwrap();
-    let mut ctrl = UblkCtrl::new(dev_id as i32, 0, 0, 0, 0, false).unwrap();
+    let mut ctrl = UblkCtrl::new_simple(dev_id).unwrap();
 
     ctrl.del().unwrap();
 }
 
+fn test_list() {
+    for d in libublk::ctrl::list_devices().unwrap() {
+        println!(
+            "dev id {}: state {} queues {} depth {} flags 0x{:x} pid {} capacity {} target {}",
+            d.id,
+            d.state,
+            d.nr_queues,
+            d.depth,
+            d.flags.bits(),
+            d.pid,
+            d.capacity,
+            d.tgt_type.as_deref().unwrap_or("-")
+        );
+    }
+}
+
 fn main() {
     if let Some(cmd) = std::env::args().nth(1) {
         match cmd.as_str() {
@@ -84,6 +107,7 @@ fn main() {
                 }
             }
             "del" => test_del(),
+            "list" => test_list(),
             _ => todo!(),
         }
     }
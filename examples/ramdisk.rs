@@ -1,31 +1,17 @@
-use libublk::io::{UblkDev, UblkIOCtx, UblkQueue};
-use libublk::{ctrl::UblkCtrl, UblkError};
-
-fn handle_io(
-    io: &mut UblkIOCtx,
-    iod: &libublk::sys::ublksrv_io_desc,
-    start: u64,
-) -> Result<i32, UblkError> {
-    let off = (iod.start_sector << 9) as u64;
-    let bytes = (iod.nr_sectors << 9) as u32;
-    let op = iod.op_flags & 0xff;
-    let buf_addr = io.io_buf_addr();
-
-    match op {
-        libublk::sys::UBLK_IO_OP_READ => unsafe {
-            libc::memcpy(
-                buf_addr as *mut libc::c_void,
-                (start + off) as *mut libc::c_void,
-                bytes as usize,
-            );
-        },
-        libublk::sys::UBLK_IO_OP_WRITE => unsafe {
-            libc::memcpy(
-                (start + off) as *mut libc::c_void,
-                buf_addr as *mut libc::c_void,
-                bytes as usize,
-            );
-        },
+use libublk::ctrl::{UblkCtrl, UblkCtrlBuilder, UblkRecoveryMode};
+use libublk::io::{
+    UblkDev, UblkIOCtx, UblkInflightIo, UblkIoOp, UblkQueueCtx, UblkQueueHandler, UblkTarget,
+};
+use libublk::UblkError;
+
+fn handle_io(io: &mut UblkIOCtx, start: u64) -> Result<i32, UblkError> {
+    let desc = io.io_desc().ok_or(UblkError::OtherError(-libc::EINVAL))?;
+    let bytes = desc.len();
+    let data = unsafe { std::slice::from_raw_parts_mut((start + desc.offset()) as *mut u8, bytes) };
+
+    match desc.op() {
+        UblkIoOp::Read => io.io_buf_mut().copy_from_slice(data),
+        UblkIoOp::Write => data.copy_from_slice(io.io_buf()),
         _ => return Err(UblkError::OtherError(-libc::EINVAL)),
     }
 
@@ -33,57 +19,76 @@ fn handle_io(
     Ok(0)
 }
 
-///run this ramdisk ublk daemon completely in single context with
-///async control command, no need Rust async any more
-fn rd_add_dev(dev_id: i32, buf_addr: u64, size: u64, for_add: bool) {
-    let depth = 128;
-    let nr_queues = 1;
-    let mut ctrl = UblkCtrl::new(
-        dev_id,
-        nr_queues,
-        depth,
-        512 << 10,
-        libublk::sys::UBLK_F_USER_RECOVERY as u64,
-        for_add,
-    )
-    .unwrap();
-    let ublk_dev = UblkDev::new(
-        "ramdisk".to_string(),
-        |dev: &mut UblkDev| {
-            dev.set_default_params(size);
-            Ok(serde_json::json!({}))
-        },
-        &mut ctrl,
-        0,
-    )
-    .unwrap();
-
-    let mut queue = UblkQueue::new(0, &ublk_dev).unwrap();
-    let ctx = queue.make_queue_ctx();
-    let qc = move |i: &mut UblkIOCtx| {
-        let _iod = ctx.get_iod(i.get_tag());
-        let iod = unsafe { &*_iod };
-
-        handle_io(i, iod, buf_addr)
-    };
-    ctrl.configure_queue(&ublk_dev, 0, unsafe { libc::gettid() });
-
-    ctrl.start_dev_in_queue(&ublk_dev, &mut queue, &qc).unwrap();
-    ctrl.dump();
-    queue.wait_and_handle_io(&qc);
-    ctrl.stop_dev(&ublk_dev).unwrap();
+// ramdisk buffer is allocated in init() or recover(), and freed in deinit()
+struct RamdiskTgt {
+    start: u64,
+    size: u64,
 }
 
-fn rd_get_device_size(ctrl: &mut UblkCtrl) -> u64 {
-    ctrl.reload_json().unwrap();
+impl UblkTarget for RamdiskTgt {
+    type Data = ();
+
+    fn init(&mut self, dev: &mut UblkDev) -> Result<(), UblkError> {
+        self.start = libublk::ublk_alloc_buf(self.size as usize, 4096) as u64;
+        dev.set_default_params(self.size);
+        Ok(())
+    }
 
-    let tgt_val = &ctrl.json["target"];
-    let tgt: Result<libublk::io::UblkTgt, _> = serde_json::from_value(tgt_val.clone());
-    if let Ok(p) = tgt {
-        p.dev_size
-    } else {
-        0
+    // device size is restored from exported json, and data is lost
+    // since ramdisk buffer is gone with the previous daemon
+    fn recover(&mut self, dev: &mut UblkDev, _target_data: ()) -> Result<(), UblkError> {
+        self.size = dev.tgt.dev_size;
+        self.start = libublk::ublk_alloc_buf(self.size as usize, 4096) as u64;
+        Ok(())
     }
+
+    // ramdisk is idempotent, so just show IOs in flight when the previous
+    // daemon died
+    fn reconcile(&mut self, dev: &UblkDev, inflight: &[UblkInflightIo]) -> Result<(), UblkError> {
+        println!(
+            "recovery mode {:?}, {} IOs in flight",
+            dev.recovery_mode(),
+            inflight.len()
+        );
+        Ok(())
+    }
+
+    fn deinit(&self, _dev: &UblkDev) {
+        libublk::ublk_dealloc_buf(self.start as *mut u8, self.size as usize, 4096);
+    }
+
+    fn queue_handler(
+        &self,
+        _dev: &UblkDev,
+        _q_id: u16,
+    ) -> Result<Box<dyn UblkQueueHandler>, UblkError> {
+        let start = self.start;
+
+        Ok(Box::new(move |_ctx: &UblkQueueCtx, i: &mut UblkIOCtx| {
+            handle_io(i, start)
+        }))
+    }
+}
+
+fn rd_add_dev(dev_id: i32, size: u64, recover: bool) {
+    let depth = 128;
+    let nr_queues = 1;
+    let mut dev = UblkCtrlBuilder::default()
+        .name("ramdisk")
+        .id(dev_id)
+        .nr_queues(nr_queues)
+        .depth(depth)
+        .io_buf_bytes(512 << 10)
+        .recovery_mode(UblkRecoveryMode::FailInflight)
+        .dev_flags(libublk::io::UBLK_DEV_F_TRACK_INFLIGHT)
+        .recover(recover)
+        .create_device(RamdiskTgt { start: 0, size })
+        .unwrap();
+
+    dev.start().unwrap();
+    dev.ctrl().dump();
+    dev.wait();
+    dev.stop().unwrap();
 }
 
 fn test_add(recover: usize) {
@@ -97,27 +102,14 @@ fn test_add(recover: usize) {
 
     let _pid = unsafe { libc::fork() };
     if _pid == 0 {
-        let mut size = (mb << 20) as u64;
-
-        if recover > 0 {
-            assert!(dev_id >= 0);
-            let mut ctrl = UblkCtrl::new(dev_id, 0, 0, 0, 0, false).unwrap();
-            size = rd_get_device_size(&mut ctrl);
-
-            ctrl.start_user_recover().unwrap();
-        }
-        let buf = libublk::ublk_alloc_buf(size as usize, 4096);
-
-        rd_add_dev(dev_id, buf as u64, size, recover == 0);
-
-        libublk::ublk_dealloc_buf(buf, size as usize, 4096);
+        rd_add_dev(dev_id, mb << 20, recover > 0);
     }
 }
 
 fn test_del() {
     let s = std::env::args().nth(2).unwrap_or_else(|| "0".to_string());
     let dev_id = s.parse::<i32>().unwrap();
-    let mut ctrl = UblkCtrl::new(dev_id as i32, 0, 0, 0, 0, false).unwrap();
+    let mut ctrl = UblkCtrl::new_simple(dev_id).unwrap();
 
     ctrl.del().unwrap();
 }
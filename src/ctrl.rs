@@ -1,14 +1,23 @@
-use super::io::{UblkDev, UblkTgt};
+use super::io::{
+    ublk_inflight_path, ublk_load_inflight, UblkDev, UblkInflightIo, UblkTarget, UblkTgt,
+};
 use super::{sys, UblkError};
+use bitflags::bitflags;
 use bitmaps::Bitmap;
 use io_uring::{cqueue, opcode, squeue, types, IoUring};
 use log::{error, trace};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
-use std::io::{Read, Write};
-use std::os::unix::io::AsRawFd;
+use std::future::Future;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
 
 const CTRL_PATH: &str = "/dev/ublk-control";
+const SYSFS_CDEV_CLASS: &str = "/sys/class/ublk-char";
 
 const MAX_BUF_SZ: u32 = 32_u32 << 20;
 
@@ -35,102 +44,1369 @@ impl UblkQueueAffinity {
     pub fn addr(&self) -> *const u8 {
         self.affinity.as_bytes().as_ptr()
     }
+
+    /// Return buffer address for being filled by ublk driver
+    pub(crate) fn addr_mut(&mut self) -> *mut u8 {
+        AsMut::<[u8]>::as_mut(&mut self.affinity).as_mut_ptr()
+    }
     pub fn to_bits_vec(&self) -> Vec<usize> {
         self.affinity.into_iter().collect()
     }
 }
 
-union CtrlCmd {
-    ctrl_cmd: sys::ublksrv_ctrl_cmd,
-    buf: [u8; 80],
+union CtrlCmd {
+    ctrl_cmd: sys::ublksrv_ctrl_cmd,
+    buf: [u8; 80],
+}
+
+const CTRL_CMD_HAS_DATA: u32 = 1;
+const CTRL_CMD_HAS_BUF: u32 = 2;
+const CTRL_CMD_ASYNC: u32 = 4;
+
+/// command buffer is filled by driver, and copied back after completion
+const CTRL_CMD_BUF_OUT: u32 = 8;
+
+#[derive(Debug, Default, Copy, Clone)]
+struct UblkCtrlCmdData {
+    cmd_op: u32,
+    flags: u32,
+    data: [u64; 2],
+    addr: u64,
+    len: u32,
+    dev_path_len: u16,
+}
+
+/// Legacy opcode of UBLK_U_CMD_GET_FEATURES, which is the ioctl nr, and
+/// ublk driver only supports the ioctl encoded one
+pub(crate) const UBLK_CMD_GET_FEATURES: u32 = (sys::UBLK_U_CMD_GET_FEATURES & 0xff) as u32;
+
+/// Name of control command for error report
+fn ublk_ctrl_cmd_name(cmd_op: u32) -> &'static str {
+    match cmd_op & 0xff {
+        sys::UBLK_CMD_GET_QUEUE_AFFINITY => "GET_QUEUE_AFFINITY",
+        sys::UBLK_CMD_GET_DEV_INFO => "GET_DEV_INFO",
+        sys::UBLK_CMD_ADD_DEV => "ADD_DEV",
+        sys::UBLK_CMD_DEL_DEV => "DEL_DEV",
+        sys::UBLK_CMD_START_DEV => "START_DEV",
+        sys::UBLK_CMD_STOP_DEV => "STOP_DEV",
+        sys::UBLK_CMD_SET_PARAMS => "SET_PARAMS",
+        sys::UBLK_CMD_GET_PARAMS => "GET_PARAMS",
+        sys::UBLK_CMD_START_USER_RECOVERY => "START_USER_RECOVERY",
+        sys::UBLK_CMD_END_USER_RECOVERY => "END_USER_RECOVERY",
+        sys::UBLK_CMD_GET_DEV_INFO2 => "GET_DEV_INFO2",
+        UBLK_CMD_GET_FEATURES => "GET_FEATURES",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Convert legacy control command opcode into ioctl encoded opcode
+///
+/// Opcode which is already ioctl encoded, such as UBLK_U_CMD_GET_FEATURES,
+/// is returned as it is.
+fn ublk_ctrl_cmd_op_ioctl(cmd_op: u32) -> u32 {
+    let op = match cmd_op {
+        sys::UBLK_CMD_GET_QUEUE_AFFINITY => sys::UBLK_U_CMD_GET_QUEUE_AFFINITY,
+        sys::UBLK_CMD_GET_DEV_INFO => sys::UBLK_U_CMD_GET_DEV_INFO,
+        sys::UBLK_CMD_ADD_DEV => sys::UBLK_U_CMD_ADD_DEV,
+        sys::UBLK_CMD_DEL_DEV => sys::UBLK_U_CMD_DEL_DEV,
+        sys::UBLK_CMD_START_DEV => sys::UBLK_U_CMD_START_DEV,
+        sys::UBLK_CMD_STOP_DEV => sys::UBLK_U_CMD_STOP_DEV,
+        sys::UBLK_CMD_SET_PARAMS => sys::UBLK_U_CMD_SET_PARAMS,
+        sys::UBLK_CMD_GET_PARAMS => sys::UBLK_U_CMD_GET_PARAMS,
+        sys::UBLK_CMD_START_USER_RECOVERY => sys::UBLK_U_CMD_START_USER_RECOVERY,
+        sys::UBLK_CMD_END_USER_RECOVERY => sys::UBLK_U_CMD_END_USER_RECOVERY,
+        sys::UBLK_CMD_GET_DEV_INFO2 => sys::UBLK_U_CMD_GET_DEV_INFO2,
+        _ => return cmd_op,
+    };
+
+    op as u32
+}
+
+fn ublk_ctrl_prep_cmd(dev_id: u32, data: &UblkCtrlCmdData) -> sys::ublksrv_ctrl_cmd {
+    sys::ublksrv_ctrl_cmd {
+        addr: if (data.flags & CTRL_CMD_HAS_BUF) != 0 {
+            data.addr
+        } else {
+            0
+        },
+        len: if (data.flags & CTRL_CMD_HAS_BUF) != 0 {
+            data.len as u16
+        } else {
+            0
+        },
+        data: if (data.flags & CTRL_CMD_HAS_DATA) != 0 {
+            [data.data[0] as u64]
+        } else {
+            [0]
+        },
+        dev_id,
+        queue_id: u16::MAX,
+        dev_path_len: data.dev_path_len,
+        ..Default::default()
+    }
+}
+
+/// Transport for delivering control command to ublk driver
+///
+/// Control command is sent to /dev/ublk-control via io_uring, or handled
+/// by the in-process mock driver if feature `mock-driver` is enabled.
+pub(crate) trait UblkCtrlTransport: Send {
+    /// Queue one control command, and submit it without waiting
+    fn submit(
+        &mut self,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_ctrl_cmd,
+        user_data: u64,
+    ) -> Result<(), UblkError>;
+
+    /// Retrieve one completed command as (user_data, result)
+    fn reap(&mut self) -> Option<(u64, i32)>;
+
+    /// Eventfd which is signalled when any command is completed
+    fn event_fd(&self) -> RawFd;
+}
+
+/// Create transport for sending control command
+fn ublk_ctrl_transport() -> Result<Box<dyn UblkCtrlTransport>, UblkError> {
+    #[cfg(feature = "mock-driver")]
+    if super::mock::enabled() {
+        return Ok(Box::new(super::mock::MockCtrl::new()?));
+    }
+    Ok(Box::new(UblkCtrlUring::new()?))
+}
+
+const CTRL_RING_DEPTH: u32 = 64;
+
+/// Send control command to /dev/ublk-control via io_uring
+struct UblkCtrlUring {
+    file: fs::File,
+    ring: IoUring<squeue::Entry128>,
+
+    /// registered to `ring`, and signalled for each CQE
+    efd: fs::File,
+}
+
+impl UblkCtrlUring {
+    fn new() -> Result<UblkCtrlUring, UblkError> {
+        let ring = IoUring::<squeue::Entry128, cqueue::Entry>::builder()
+            .build(CTRL_RING_DEPTH)
+            .map_err(UblkError::OtherIOError)?;
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(CTRL_PATH)
+            .map_err(UblkError::OtherIOError)?;
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+        }
+        let efd = unsafe { fs::File::from_raw_fd(fd) };
+
+        ring.submitter()
+            .register_eventfd(efd.as_raw_fd())
+            .map_err(UblkError::OtherIOError)?;
+
+        Ok(UblkCtrlUring { file, ring, efd })
+    }
+}
+
+impl UblkCtrlTransport for UblkCtrlUring {
+    fn submit(
+        &mut self,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_ctrl_cmd,
+        user_data: u64,
+    ) -> Result<(), UblkError> {
+        let c_cmd = CtrlCmd { ctrl_cmd: *cmd };
+        let sqe = opcode::UringCmd80::new(types::Fd(self.file.as_raw_fd()), cmd_op)
+            .cmd(unsafe { c_cmd.buf })
+            .build()
+            .user_data(user_data);
+
+        // SQ is full if any SQE isn't consumed, so flush it and retry
+        if unsafe { self.ring.submission().push(&sqe) }.is_err() {
+            self.ring
+                .submit()
+                .map_err(UblkError::UringSubmissionError)?;
+            unsafe { self.ring.submission().push(&sqe) }.map_err(UblkError::UringPushError)?;
+        }
+        self.ring
+            .submit()
+            .map_err(UblkError::UringSubmissionError)?;
+        Ok(())
+    }
+
+    fn reap(&mut self) -> Option<(u64, i32)> {
+        self.ring
+            .completion()
+            .next()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+    }
+
+    fn event_fd(&self) -> RawFd {
+        self.efd.as_raw_fd()
+    }
+}
+
+/// One control command submitted to `UblkCtrlRing`
+#[derive(Debug, Default)]
+struct UblkCtrlSlot {
+    /// the command passed by caller, for completing it
+    data: UblkCtrlCmdData,
+    dev_id: u32,
+    res: Option<i32>,
+    waker: Option<Waker>,
+
+    /// command buffer, which has to be live until the command is completed
+    buf: Vec<u8>,
+
+    /// nobody waits for the result, so the slot is freed after the
+    /// command is completed
+    detached: bool,
+}
+
+#[derive(Debug, Default)]
+struct UblkCtrlSlots {
+    last_token: u64,
+    slots: HashMap<u64, UblkCtrlSlot>,
+}
+
+struct UblkCtrlRingInner {
+    transport: Mutex<Box<dyn UblkCtrlTransport>>,
+    slots: Mutex<UblkCtrlSlots>,
+}
+
+/// io_uring for sending control commands to ublk driver
+///
+/// Each command is submitted with one unique token as user_data, and
+/// completions are demultiplexed by the token, so many commands can be
+/// in flight. One ring can be shared by control devices of many ublk
+/// devices via `UblkCtrlBuilder::ctrl_ring()`, otherwise each `UblkCtrl`
+/// creates its own ring.
+///
+/// Async control commands(`UblkCtrl::*_async()`) work with any executor:
+/// the returned future is completed after `reap_events()` retrieves its
+/// completion, so the executor has to call `reap_events()` once
+/// `event_fd()` becomes readable, such as, by tokio's `AsyncFd`.
+///
+/// # Examples:
+///
+/// ```no_run
+/// use libublk::ctrl::{UblkCtrlBuilder, UblkCtrlRing};
+///
+/// async fn add_and_stop(ring: &UblkCtrlRing, id: i32) {
+///     let mut ctrl = UblkCtrlBuilder::default()
+///         .name("null")
+///         .id(id)
+///         .ctrl_ring(ring)
+///         .build_async()
+///         .await
+///         .unwrap();
+///     ctrl.get_info_async().await.unwrap();
+///     ctrl.stop_async().await.unwrap();
+/// }
+/// ```
+#[derive(Clone)]
+pub struct UblkCtrlRing {
+    inner: Arc<UblkCtrlRingInner>,
+}
+
+impl std::fmt::Debug for UblkCtrlRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UblkCtrlRing")
+            .field("inflight", &self.nr_inflight())
+            .finish()
+    }
+}
+
+impl UblkCtrlRing {
+    /// Create one ring, which is connected to /dev/ublk-control
+    pub fn new() -> Result<UblkCtrlRing, UblkError> {
+        Ok(UblkCtrlRing {
+            inner: Arc::new(UblkCtrlRingInner {
+                transport: Mutex::new(ublk_ctrl_transport()?),
+                slots: Mutex::new(UblkCtrlSlots::default()),
+            }),
+        })
+    }
+
+    fn lock_transport(&self) -> MutexGuard<'_, Box<dyn UblkCtrlTransport>> {
+        self.inner
+            .transport
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_slots(&self) -> MutexGuard<'_, UblkCtrlSlots> {
+        self.inner.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Return eventfd which becomes readable when any command is
+    /// completed
+    ///
+    /// The eventfd is non-blocking, and its counter is cleared by
+    /// `reap_events()`.
+    pub fn event_fd(&self) -> RawFd {
+        self.lock_transport().event_fd()
+    }
+
+    /// Return how many commands are submitted and not completed yet
+    pub fn nr_inflight(&self) -> usize {
+        self.lock_slots()
+            .slots
+            .values()
+            .filter(|s| s.res.is_none())
+            .count()
+    }
+
+    /// Retrieve all completed commands, and wake up their waiters
+    ///
+    /// # Return: how many commands are completed
+    pub fn reap_events(&self) -> usize {
+        self.__reap_events(&mut self.lock_transport())
+    }
+
+    fn __reap_events(&self, t: &mut Box<dyn UblkCtrlTransport>) -> usize {
+        let mut cnt = [0_u8; 8];
+        let mut done = Vec::new();
+
+        // clear eventfd before retrieving CQEs, so any new completion
+        // signals it again
+        unsafe {
+            libc::read(
+                t.event_fd(),
+                cnt.as_mut_ptr() as *mut libc::c_void,
+                cnt.len(),
+            )
+        };
+        while let Some(c) = t.reap() {
+            done.push(c);
+        }
+
+        let mut s = self.lock_slots();
+        for (token, res) in &done {
+            match s.slots.get_mut(token) {
+                Some(slot) if slot.detached => {
+                    s.slots.remove(token);
+                }
+                Some(slot) => {
+                    slot.res = Some(*res);
+                    if let Some(w) = slot.waker.take() {
+                        w.wake();
+                    }
+                }
+                None => trace!("ctrl: unknown command {} completed {}", token, res),
+            }
+        }
+        done.len()
+    }
+
+    /// Submit one command, and command buffer is owned by the ring until
+    /// the command is completed
+    ///
+    /// # Return: token of the command, which is always positive i32
+    fn submit(
+        &self,
+        cmd_op: u32,
+        mut cmd: sys::ublksrv_ctrl_cmd,
+        data: &UblkCtrlCmdData,
+        buf: Vec<u8>,
+    ) -> Result<u64, UblkError> {
+        if !buf.is_empty() {
+            cmd.addr = buf.as_ptr() as u64;
+        }
+
+        let token = {
+            let mut s = self.lock_slots();
+            let mut token = s.last_token;
+
+            loop {
+                token = (token + 1) & (i32::MAX as u64);
+                if token != 0 && !s.slots.contains_key(&token) {
+                    break;
+                }
+            }
+            s.last_token = token;
+            s.slots.insert(
+                token,
+                UblkCtrlSlot {
+                    data: *data,
+                    dev_id: cmd.dev_id,
+                    buf,
+                    ..Default::default()
+                },
+            );
+            token
+        };
+
+        if let Err(e) = self.lock_transport().submit(cmd_op, &cmd, token) {
+            self.lock_slots().slots.remove(&token);
+            return Err(e);
+        }
+        Ok(token)
+    }
+
+    /// Remove the command if it is completed
+    fn take(&self, token: u64) -> Option<UblkCtrlSlot> {
+        let mut s = self.lock_slots();
+
+        match s.slots.get(&token) {
+            Some(slot) if slot.res.is_some() => s.slots.remove(&token),
+            _ => None,
+        }
+    }
+
+    /// Remove the command if it is completed, otherwise wake up `waker`
+    /// after it is completed
+    fn poll_slot(&self, token: u64, waker: &Waker) -> Option<UblkCtrlSlot> {
+        let mut s = self.lock_slots();
+
+        match s.slots.get_mut(&token) {
+            Some(slot) if slot.res.is_none() => {
+                slot.waker = Some(waker.clone());
+                None
+            }
+            _ => s.slots.remove(&token),
+        }
+    }
+
+    /// Nobody waits for the command any more, and its buffer is freed
+    /// after it is completed
+    fn detach(&self, token: u64) {
+        let mut s = self.lock_slots();
+
+        if let Some(slot) = s.slots.get_mut(&token) {
+            if slot.res.is_some() {
+                s.slots.remove(&token);
+            } else {
+                slot.detached = true;
+                slot.waker = None;
+            }
+        }
+    }
+
+    /// Wait until the command is completed
+    ///
+    /// Only reaping is done with transport locked, and the eventfd is
+    /// polled without any lock held, so other commands can be submitted
+    /// or completed meantime. If the command's CQE is retrieved by others,
+    /// the eventfd is signalled by this waiter's waker.
+    fn wait(&self, token: u64) -> Result<UblkCtrlSlot, UblkError> {
+        let efd = self.event_fd();
+        let waker = std::task::Waker::from(Arc::new(UblkCtrlSyncWaker(efd)));
+
+        loop {
+            self.reap_events();
+            if let Some(slot) = self.poll_slot(token, &waker) {
+                return Ok(slot);
+            }
+
+            let mut pfd = libc::pollfd {
+                fd: efd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut pfd, 1, -1) } < 0 {
+                let e = std::io::Error::last_os_error();
+
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    self.detach(token);
+                    return Err(UblkError::OtherIOError(e));
+                }
+            }
+        }
+    }
+}
+
+/// Wake up sync waiter of `UblkCtrlRing` by signalling the ring's eventfd
+struct UblkCtrlSyncWaker(RawFd);
+
+impl std::task::Wake for UblkCtrlSyncWaker {
+    fn wake(self: Arc<Self>) {
+        let one = 1_u64;
+
+        unsafe {
+            libc::write(
+                self.0,
+                &one as *const u64 as *const libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+    }
+}
+
+/// Future of one control command submitted to `UblkCtrlRing`
+struct UblkCtrlCmdFuture {
+    ring: UblkCtrlRing,
+    token: u64,
+    done: bool,
+}
+
+impl Future for UblkCtrlCmdFuture {
+    type Output = UblkCtrlSlot;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // retrieve completions if nobody else is doing that
+        if let Ok(mut t) = self.ring.inner.transport.try_lock() {
+            self.ring.__reap_events(&mut t);
+        }
+
+        match self.ring.poll_slot(self.token, cx.waker()) {
+            Some(slot) => {
+                self.done = true;
+                Poll::Ready(slot)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for UblkCtrlCmdFuture {
+    fn drop(&mut self) {
+        // command buffer is still used by driver, so leave it to the ring
+        if !self.done {
+            self.ring.detach(self.token);
+        }
+    }
+}
+
+/// Build command buffer, which is one copy of the payload, and it starts
+/// with char device path if it is required
+///
+/// For device created with UBLK_F_UNPRIVILEGED_DEV or UBLK_CMD_GET_DEV_INFO2,
+/// ublk driver requires the command buffer to start with this device's
+/// char device path(including the null char), then the real command buffer
+/// follows, and the path is used for permission check.
+///
+/// # Return: (command buffer, length of the path)
+fn ublk_ctrl_prep_buf(ctrl: &UblkCtrl, data: &UblkCtrlCmdData) -> (Vec<u8>, u16) {
+    let mut buf = Vec::<u8>::new();
+
+    if ctrl.need_dev_path(data.cmd_op) {
+        let path = format!("{}{}", super::CDEV_PATH, ctrl.dev_info.dev_id);
+
+        buf.reserve(path.len() + 1 + data.len as usize);
+        buf.extend_from_slice(path.as_bytes());
+        buf.push(0);
+    }
+    let dev_path_len = buf.len() as u16;
+
+    if (data.flags & CTRL_CMD_HAS_BUF) != 0 {
+        let payload =
+            unsafe { std::slice::from_raw_parts(data.addr as *const u8, data.len as usize) };
+        buf.extend_from_slice(payload);
+    }
+
+    (buf, dev_path_len)
+}
+
+/// Submit one control command to this device's control ring
+fn ublk_ctrl_submit(ctrl: &UblkCtrl, data: &UblkCtrlCmdData) -> Result<u64, UblkError> {
+    let (buf, dev_path_len) = ublk_ctrl_prep_buf(ctrl, data);
+    let cmd_data = UblkCtrlCmdData {
+        flags: if buf.is_empty() {
+            data.flags
+        } else {
+            data.flags | CTRL_CMD_HAS_BUF
+        },
+        len: buf.len() as u32,
+        dev_path_len,
+        ..*data
+    };
+    let cmd = ublk_ctrl_prep_cmd(ctrl.dev_info.dev_id, &cmd_data);
+    let cmd_op = if ctrl.ioctl_encode() {
+        ublk_ctrl_cmd_op_ioctl(data.cmd_op)
+    } else {
+        data.cmd_op
+    };
+
+    ctrl.ring.submit(cmd_op, cmd, data, buf)
+}
+
+/// Copy command buffer back if it is filled by driver(CTRL_CMD_BUF_OUT),
+/// and convert the command result
+///
+/// Shared by sync, async and polled commands, so all return same result
+/// for one command, such as Ok(-EBUSY). `addr` of the command has to be
+/// writable for `len` bytes if CTRL_CMD_BUF_OUT is set.
+fn ublk_ctrl_complete(slot: &UblkCtrlSlot) -> Result<i32, UblkError> {
+    let data = &slot.data;
+    let res = slot.res.unwrap_or(-libc::EIO);
+    let out = CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_OUT;
+
+    if (data.flags & out) == out {
+        let off = slot.buf.len() - data.len as usize;
+        let payload =
+            unsafe { std::slice::from_raw_parts_mut(data.addr as *mut u8, data.len as usize) };
+        payload.copy_from_slice(&slot.buf[off..]);
+    }
+
+    if res == 0 || res == -libc::EBUSY {
+        Ok(res)
+    } else {
+        Err(UblkError::CtrlCmdError {
+            op: ublk_ctrl_cmd_name(data.cmd_op),
+            dev_id: slot.dev_id,
+            errno: super::UblkErrno(res),
+        })
+    }
+}
+
+/// Send control command and wait for its completion
+///
+/// If CTRL_CMD_ASYNC is set, the command's token is returned without
+/// waiting, and the command has to be polled by `UblkCtrl::poll_cmd()`,
+/// so its buffer can't be CTRL_CMD_BUF_OUT.
+fn ublk_ctrl_cmd(ctrl: &mut UblkCtrl, data: &UblkCtrlCmdData) -> Result<i32, UblkError> {
+    let token = ublk_ctrl_submit(ctrl, data)?;
+
+    if data.flags & CTRL_CMD_ASYNC != 0 {
+        debug_assert!(data.flags & CTRL_CMD_BUF_OUT == 0);
+        return Ok(token as i32);
+    }
+
+    let slot = ctrl.ring.wait(token)?;
+    ublk_ctrl_complete(&slot)
+}
+
+/// Send control command, and the returned future is completed after
+/// `UblkCtrlRing::reap_events()` retrieves the command's completion
+async fn ublk_ctrl_cmd_async(ctrl: &UblkCtrl, data: &UblkCtrlCmdData) -> Result<i32, UblkError> {
+    let token = ublk_ctrl_submit(ctrl, data)?;
+    let slot = UblkCtrlCmdFuture {
+        ring: ctrl.ring.clone(),
+        token,
+        done: false,
+    }
+    .await;
+
+    ublk_ctrl_complete(&slot)
+}
+
+/// Version of the exported json format, bumped when the format is changed
+/// incompatibly
+pub const UBLK_EXPORT_VERSION: u32 = 1;
+
+/// Exported info of one ublk queue
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UblkQueueExport {
+    /// queue id
+    pub qid: u16,
+
+    /// tid of the queue's pthread
+    pub tid: i32,
+
+    /// cpus which the queue's pthread is affine to
+    pub affinity: Vec<u32>,
+}
+
+/// Exported json of ublk device
+///
+/// It is written to `UblkCtrl::run_path()` after all queues are setup,
+/// and used for recovering device, or by external tools for retrieving
+/// device info.
+///
+/// `target_data` is `UblkTarget::Data` returned from `UblkTarget::init()`,
+/// and target code can load it as its own type by
+/// `UblkCtrl::load_export::<T::Data>()`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UblkDevExport<T = serde_json::Value> {
+    /// format version, 0 means the file is written before version is added
+    #[serde(default)]
+    pub version: u32,
+    pub dev_info: sys::ublksrv_ctrl_dev_info,
+    pub target: UblkTgt,
+    pub target_data: T,
+    #[serde(deserialize_with = "ublk_deserialize_queues")]
+    pub queues: Vec<UblkQueueExport>,
+}
+
+/// Queues are exported as map keyed by queue id before version 1
+fn ublk_deserialize_queues<'de, D>(d: D) -> Result<Vec<UblkQueueExport>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Queues {
+        List(Vec<UblkQueueExport>),
+        Map(std::collections::BTreeMap<String, UblkQueueExport>),
+    }
+
+    Ok(match Queues::deserialize(d)? {
+        Queues::List(l) => l,
+        Queues::Map(m) => {
+            let mut l: Vec<UblkQueueExport> = m.into_values().collect();
+            l.sort_by_key(|q| q.qid);
+            l
+        }
+    })
+}
+
+impl<T: serde::de::DeserializeOwned> UblkDevExport<T> {
+    fn check_version(self) -> Result<Self, UblkError> {
+        if self.version > UBLK_EXPORT_VERSION {
+            return Err(UblkError::InvalidExport(format!(
+                "version {} is newer than {}",
+                self.version, UBLK_EXPORT_VERSION
+            )));
+        }
+        Ok(self)
+    }
+
+    /// Parse device export from json value
+    pub fn from_value(val: serde_json::Value) -> Result<Self, UblkError> {
+        let e: Self = serde_json::from_value(val).map_err(UblkError::JsonError)?;
+
+        e.check_version()
+    }
+
+    /// Parse device export from json string
+    pub fn parse(s: &str) -> Result<Self, UblkError> {
+        let e: Self = serde_json::from_str(s).map_err(UblkError::JsonError)?;
+
+        e.check_version()
+    }
+
+    /// Load device export from the specified file
+    pub fn load(path: &str) -> Result<Self, UblkError> {
+        let s = fs::read_to_string(path).map_err(UblkError::OtherIOError)?;
+
+        Self::parse(&s)
+    }
+
+    /// Return exported info of the specified queue
+    pub fn queue(&self, qid: u16) -> Option<&UblkQueueExport> {
+        self.queues.iter().find(|q| q.qid == qid)
+    }
+}
+
+bitflags! {
+    /// Control flags(`UBLK_F_*`) of ublk device, which are sent to ublk
+    /// driver when adding device
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    pub struct UblkFlags: u64 {
+        const SUPPORT_ZERO_COPY = sys::UBLK_F_SUPPORT_ZERO_COPY as u64;
+        const URING_CMD_COMP_IN_TASK = sys::UBLK_F_URING_CMD_COMP_IN_TASK as u64;
+        const NEED_GET_DATA = sys::UBLK_F_NEED_GET_DATA as u64;
+        const USER_RECOVERY = sys::UBLK_F_USER_RECOVERY as u64;
+        const USER_RECOVERY_REISSUE = sys::UBLK_F_USER_RECOVERY_REISSUE as u64;
+        const UNPRIVILEGED_DEV = sys::UBLK_F_UNPRIVILEGED_DEV as u64;
+        const CMD_IOCTL_ENCODE = sys::UBLK_F_CMD_IOCTL_ENCODE as u64;
+        const USER_COPY = sys::UBLK_F_USER_COPY as u64;
+    }
+}
+
+/// How ublk device is recovered after its daemon is gone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UblkRecoveryMode {
+    /// device is removed after its daemon is gone
+    Disabled,
+
+    /// IOs in flight are failed to upper layer, and the device waits
+    /// for user recovery(`UBLK_F_USER_RECOVERY`)
+    FailInflight,
+
+    /// IOs in flight are requeued, and issued again to the recovered
+    /// daemon(`UBLK_F_USER_RECOVERY` | `UBLK_F_USER_RECOVERY_REISSUE`)
+    Reissue,
+}
+
+impl From<UblkFlags> for UblkRecoveryMode {
+    fn from(flags: UblkFlags) -> Self {
+        if !flags.contains(UblkFlags::USER_RECOVERY) {
+            UblkRecoveryMode::Disabled
+        } else if flags.contains(UblkFlags::USER_RECOVERY_REISSUE) {
+            UblkRecoveryMode::Reissue
+        } else {
+            UblkRecoveryMode::FailInflight
+        }
+    }
+}
+
+impl UblkRecoveryMode {
+    /// Control flags for this recovery mode
+    pub fn flags(&self) -> UblkFlags {
+        match self {
+            UblkRecoveryMode::Disabled => UblkFlags::empty(),
+            UblkRecoveryMode::FailInflight => UblkFlags::USER_RECOVERY,
+            UblkRecoveryMode::Reissue => {
+                UblkFlags::USER_RECOVERY | UblkFlags::USER_RECOVERY_REISSUE
+            }
+        }
+    }
+}
+
+/// State of ublk device(`UBLK_S_DEV_*`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub enum UblkDevState {
+    /// device isn't started, or is stopped
+    Dead,
+
+    /// device is started, and /dev/ublkbN is exposed
+    Live,
+
+    /// daemon is gone, and device is waiting for user recovery
+    Quiesced,
+
+    /// state which isn't known by this library
+    Unknown(u16),
+}
+
+impl From<u16> for UblkDevState {
+    fn from(state: u16) -> Self {
+        match state as u32 {
+            sys::UBLK_S_DEV_DEAD => UblkDevState::Dead,
+            sys::UBLK_S_DEV_LIVE => UblkDevState::Live,
+            sys::UBLK_S_DEV_QUIESCED => UblkDevState::Quiesced,
+            _ => UblkDevState::Unknown(state),
+        }
+    }
+}
+
+impl std::fmt::Display for UblkDevState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UblkDevState::Dead => write!(f, "DEAD"),
+            UblkDevState::Live => write!(f, "LIVE"),
+            UblkDevState::Quiesced => write!(f, "QUIESCED"),
+            UblkDevState::Unknown(s) => write!(f, "UNKNOWN({})", s),
+        }
+    }
+}
+
+/// Features supported by ublk driver before UBLK_U_CMD_GET_FEATURES is
+/// introduced
+const UBLK_LEGACY_FEATURES: UblkFlags = UblkFlags::URING_CMD_COMP_IN_TASK
+    .union(UblkFlags::NEED_GET_DATA)
+    .union(UblkFlags::USER_RECOVERY)
+    .union(UblkFlags::USER_RECOVERY_REISSUE);
+
+/// Builder of ublk control device
+///
+/// Collects all parameters for adding one new ublk device or attaching
+/// to one existed device, and validates them before talking to ublk
+/// driver.
+///
+/// # Examples:
+///
+/// ```no_run
+/// use libublk::ctrl::{UblkCtrlBuilder, UblkFlags};
+///
+/// let ctrl = UblkCtrlBuilder::default()
+///     .name("null")
+///     .nr_queues(2)
+///     .depth(64)
+///     .io_buf_bytes(512 << 10)
+///     .ctrl_flags(UblkFlags::USER_RECOVERY)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct UblkCtrlBuilder {
+    name: String,
+    id: i32,
+    nr_queues: u32,
+    depth: u32,
+    io_buf_bytes: u32,
+    ctrl_flags: UblkFlags,
+    dev_flags: u32,
+    for_add: bool,
+    recover: bool,
+    run_dir: String,
+    ring: Option<UblkCtrlRing>,
+}
+
+impl Default for UblkCtrlBuilder {
+    fn default() -> Self {
+        UblkCtrlBuilder {
+            name: "none".to_string(),
+            id: -1,
+            nr_queues: 1,
+            depth: 64,
+            io_buf_bytes: 512_u32 << 10,
+            ctrl_flags: UblkFlags::empty(),
+            dev_flags: 0,
+            for_add: true,
+            recover: false,
+            run_dir: UblkCtrl::default_run_dir(),
+            ring: None,
+        }
+    }
+}
+
+impl UblkCtrlBuilder {
+    /// Target type name, such as "null", "loop", ...
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Device id, or let driver allocate one if -1 is passed
+    pub fn id(mut self, id: i32) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// How many hw queues allocated for this device
+    pub fn nr_queues(mut self, nr_queues: u32) -> Self {
+        self.nr_queues = nr_queues;
+        self
+    }
+
+    /// Each hw queue's depth
+    pub fn depth(mut self, depth: u32) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Max buf size for each IO
+    pub fn io_buf_bytes(mut self, io_buf_bytes: u32) -> Self {
+        self.io_buf_bytes = io_buf_bytes;
+        self
+    }
+
+    /// Control flags(`UBLK_F_*`) sent to ublk driver
+    pub fn ctrl_flags(mut self, flags: UblkFlags) -> Self {
+        self.ctrl_flags = flags;
+        self
+    }
+
+    /// How the device is recovered after its daemon is gone, which
+    /// overrides recovery flags passed to `ctrl_flags()`
+    pub fn recovery_mode(mut self, mode: UblkRecoveryMode) -> Self {
+        self.ctrl_flags = (self.ctrl_flags
+            - (UblkFlags::USER_RECOVERY | UblkFlags::USER_RECOVERY_REISSUE))
+            | mode.flags();
+        self
+    }
+
+    /// Device flags(`UBLK_DEV_F_*`) for `UblkDev`
+    pub fn dev_flags(mut self, flags: u32) -> Self {
+        self.dev_flags = flags;
+        self
+    }
+
+    /// Add one new device if true, otherwise attach to one existed device
+    pub fn for_add(mut self, for_add: bool) -> Self {
+        self.for_add = for_add;
+        self
+    }
+
+    /// Recover one existed device whose daemon is gone
+    ///
+    /// The device has to be created with `UblkFlags::USER_RECOVERY`, and
+    /// its id has to be specified. `create_device()` reloads the device's
+    /// exported json file, restores `UblkDev` from it, and passes the saved
+    /// `target_data` to `UblkTarget::recover()`. Recovery is completed when
+    /// the returned device is started, and the returned device is owned by
+    /// the handle same with the added one, so it is deleted when the
+    /// handle is dropped.
+    pub fn recover(mut self, recover: bool) -> Self {
+        self.recover = recover;
+        self
+    }
+
+    /// Directory for storing device's exported json file, such as
+    /// /run/ublk, default is `UblkCtrl::default_run_dir()`
+    pub fn run_dir(mut self, dir: &str) -> Self {
+        self.run_dir = dir.to_string();
+        self
+    }
+
+    /// Send control commands via the shared `ring`, instead of one ring
+    /// created for this device only
+    pub fn ctrl_ring(mut self, ring: &UblkCtrlRing) -> Self {
+        self.ring = Some(ring.clone());
+        self
+    }
+
+    fn validate(&self) -> Result<(), UblkError> {
+        if self.run_dir.is_empty() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let for_add = self.for_add && !self.recover;
+
+        if self.id < -1 || (!for_add && self.id < 0) {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if (self.dev_flags & !super::io::UBLK_DEV_F_ALL) != 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        // queue parameters are retrieved from driver for existed device
+        if !for_add {
+            return Ok(());
+        }
+
+        if self.name.is_empty() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if self.nr_queues == 0 || self.nr_queues > sys::UBLK_MAX_NR_QUEUES {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if self.depth == 0 || self.depth > sys::UBLK_MAX_QUEUE_DEPTH {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if self.io_buf_bytes == 0
+            || self.io_buf_bytes > MAX_BUF_SZ
+            || (self.io_buf_bytes & 511) != 0
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        Ok(())
+    }
+
+    /// Validate all parameters, then create the control device
+    ///
+    /// New ublk char device(/dev/ublkcN) is added if `for_add` is true.
+    pub fn build(self) -> Result<UblkCtrl, UblkError> {
+        self.validate()?;
+
+        let mut dev = self.open()?;
+
+        //prefer ioctl encoded command, and fallback to legacy command
+        //on old kernel
+        dev.features = dev.supported_features();
+        if dev.for_add {
+            dev.prep_add()?;
+        } else {
+            dev.get_info()?;
+        }
+        if dev.for_add {
+            dev.add()?;
+        }
+        dev.setup_queues();
+
+        Ok(dev)
+    }
+
+    /// Validate all parameters, then create the control device with
+    /// async control commands
+    ///
+    /// Same with `build()`, but doesn't block the executor.
+    pub async fn build_async(self) -> Result<UblkCtrl, UblkError> {
+        self.validate()?;
+
+        let mut dev = self.open()?;
+        dev.features = match dev.__get_features_async().await {
+            Ok(features) => features,
+            Err(_) => UBLK_LEGACY_FEATURES,
+        };
+        if dev.for_add {
+            dev.prep_add()?;
+        } else {
+            dev.get_info_async().await?;
+        }
+        if dev.for_add {
+            dev.add_async().await?;
+        }
+        dev.setup_queues();
+
+        Ok(dev)
+    }
+
+    /// Open control device with the validated parameters
+    ///
+    /// For existed device, queue parameters are retrieved from driver,
+    /// so that device created with UBLK_F_UNPRIVILEGED_DEV can be
+    /// controlled.
+    fn open(self) -> Result<UblkCtrl, UblkError> {
+        let info = sys::ublksrv_ctrl_dev_info {
+            nr_hw_queues: self.nr_queues as u16,
+            queue_depth: self.depth as u16,
+            max_io_buf_bytes: self.io_buf_bytes,
+            dev_id: self.id as u32,
+            ublksrv_pid: unsafe { libc::getpid() } as i32,
+            flags: self.ctrl_flags.bits(),
+            ..Default::default()
+        };
+
+        UblkCtrl::open(info, self.for_add && !self.recover, self.run_dir, self.ring)
+    }
+
+    /// Create ublk device handle (high level)
+    ///
+    /// # Arguments:
+    ///
+    /// * `tgt`: target object
+    ///
+    /// Build control device and `UblkDev` initialized by `tgt`, then setup
+    /// each queue in one dedicated thread. The device isn't started until
+    /// `UblkDevHandle::start()` is called.
+    pub fn create_device<T>(self, mut tgt: T) -> Result<super::UblkDevHandle, UblkError>
+    where
+        T: UblkTarget + 'static,
+    {
+        let name = self.name.clone();
+        let dev_flags = self.dev_flags;
+        let recover = self.recover;
+        let mut ctrl = self.build()?;
+        let dev = if recover {
+            Arc::new(ctrl.recover_dev(&mut tgt, dev_flags)?)
+        } else {
+            Arc::new(UblkDev::new(
+                name,
+                |dev: &mut UblkDev| {
+                    serde_json::to_value(tgt.init(dev)?).map_err(UblkError::JsonError)
+                },
+                &mut ctrl,
+                dev_flags,
+            )?)
+        };
+        let tgt = Arc::new(tgt);
+        let q_threads = super::create_queue_handler(&mut ctrl, &dev, &tgt)?;
+
+        //the recovered device is owned by this handle now, same with
+        //the added one
+        if recover {
+            ctrl.for_add = true;
+        }
+
+        Ok(super::UblkDevHandle::new(ctrl, dev, tgt, q_threads))
+    }
+}
+
+/// Builder of ublk device parameters(`sys::ublk_params`)
+///
+/// All sizes are in bytes except for the ones named with `_sectors`, and
+/// they are validated in `build()`, which sets `types` automatically.
+///
+/// # Examples:
+///
+/// ```no_run
+/// use libublk::ctrl::UblkParamsBuilder;
+///
+/// let params = UblkParamsBuilder::new(64 << 30)
+///     .logical_block_size(4096)
+///     .rotational(true)
+///     .discard(0, 4096, 1 << 20, 0)
+///     .build(512 << 10)
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct UblkParamsBuilder {
+    dev_size: u64,
+    logical_bs: u32,
+    physical_bs: u32,
+    io_opt: u32,
+    io_min: u32,
+    max_sectors: u32,
+    chunk_sectors: u32,
+    virt_boundary_mask: u64,
+    attrs: u32,
+    discard: Option<sys::ublk_param_discard>,
 }
 
-const CTRL_CMD_HAS_DATA: u32 = 1;
-const CTRL_CMD_HAS_BUF: u32 = 2;
-const CTRL_CMD_ASYNC: u32 = 4;
+impl UblkParamsBuilder {
+    /// Create parameters builder for device with `dev_size` bytes
+    ///
+    /// Default logical block size is 512, and default physical block
+    /// size, minimum & optimal IO size are 4096, which are same with
+    /// `UblkDev::set_default_params()`.
+    pub fn new(dev_size: u64) -> Self {
+        UblkParamsBuilder {
+            dev_size,
+            logical_bs: 512,
+            physical_bs: 4096,
+            io_opt: 4096,
+            io_min: 4096,
+            max_sectors: 0,
+            chunk_sectors: 0,
+            virt_boundary_mask: 0,
+            attrs: 0,
+            discard: None,
+        }
+    }
 
-#[derive(Debug, Default, Copy, Clone)]
-struct UblkCtrlCmdData {
-    cmd_op: u32,
-    flags: u32,
-    data: [u64; 2],
-    addr: u64,
-    len: u32,
-}
+    /// Return device size in bytes
+    pub fn dev_size(&self) -> u64 {
+        self.dev_size
+    }
 
-fn ublk_ctrl_prep_cmd(
-    ctrl: &mut UblkCtrl,
-    fd: i32,
-    dev_id: u32,
-    data: &UblkCtrlCmdData,
-) -> squeue::Entry128 {
-    let cmd = sys::ublksrv_ctrl_cmd {
-        addr: if (data.flags & CTRL_CMD_HAS_BUF) != 0 {
-            data.addr
-        } else {
-            0
-        },
-        len: if (data.flags & CTRL_CMD_HAS_BUF) != 0 {
-            data.len as u16
-        } else {
-            0
-        },
-        data: if (data.flags & CTRL_CMD_HAS_DATA) != 0 {
-            [data.data[0] as u64]
+    /// Logical block size, power of 2 between 512 and page size
+    pub fn logical_block_size(mut self, bs: u32) -> Self {
+        self.logical_bs = bs;
+        self
+    }
+
+    /// Physical block size, power of 2 and not less than logical block size
+    pub fn physical_block_size(mut self, bs: u32) -> Self {
+        self.physical_bs = bs;
+        self
+    }
+
+    /// Optimal IO size, power of 2 and not less than logical block size
+    pub fn io_opt(mut self, bytes: u32) -> Self {
+        self.io_opt = bytes;
+        self
+    }
+
+    /// Minimum IO size, power of 2 and not less than logical block size
+    pub fn io_min(mut self, bytes: u32) -> Self {
+        self.io_min = bytes;
+        self
+    }
+
+    /// Max sectors of each IO, can't be larger than device's
+    /// max_io_buf_bytes, default is max_io_buf_bytes
+    pub fn max_sectors(mut self, sectors: u32) -> Self {
+        self.max_sectors = sectors;
+        self
+    }
+
+    /// IO won't cross `chunk_sectors` boundary, 0 or power of 2
+    pub fn chunk_sectors(mut self, sectors: u32) -> Self {
+        self.chunk_sectors = sectors;
+        self
+    }
+
+    /// Each IO segment won't cross this boundary, 0 or (power of 2) - 1
+    pub fn virt_boundary_mask(mut self, mask: u64) -> Self {
+        self.virt_boundary_mask = mask;
+        self
+    }
+
+    fn attr(mut self, attr: u32, set: bool) -> Self {
+        if set {
+            self.attrs |= attr;
         } else {
-            [0]
-        },
-        dev_id,
-        queue_id: u16::MAX,
-        ..Default::default()
-    };
-    let c_cmd = CtrlCmd { ctrl_cmd: cmd };
-
-    opcode::UringCmd80::new(types::Fd(fd), data.cmd_op)
-        .cmd(unsafe { c_cmd.buf })
-        .build()
-        .user_data({
-            ctrl.cmd_token += 1;
-            ctrl.cmd_token as u64
-        })
-}
+            self.attrs &= !attr;
+        }
+        self
+    }
 
-fn ublk_ctrl_cmd(ctrl: &mut UblkCtrl, data: &UblkCtrlCmdData) -> Result<i32, UblkError> {
-    let sqe = ublk_ctrl_prep_cmd(ctrl, ctrl.file.as_raw_fd(), ctrl.dev_info.dev_id, data);
-    let to_wait = if data.flags & CTRL_CMD_ASYNC != 0 {
-        0
-    } else {
-        1
-    };
+    /// Expose read-only block device
+    pub fn read_only(self, ro: bool) -> Self {
+        self.attr(sys::UBLK_ATTR_READ_ONLY, ro)
+    }
 
-    unsafe {
-        ctrl.ring
-            .submission()
-            .push(&sqe)
-            .map_err(UblkError::UringPushError)?;
+    /// Device is rotational
+    pub fn rotational(self, rotational: bool) -> Self {
+        self.attr(sys::UBLK_ATTR_ROTATIONAL, rotational)
     }
-    ctrl.ring
-        .submit_and_wait(to_wait)
-        .map_err(UblkError::UringSubmissionError)?;
 
-    if to_wait == 0 {
-        return Ok(ctrl.cmd_token);
+    /// Device has volatile write cache, so FLUSH is sent to target
+    pub fn volatile_cache(self, vwc: bool) -> Self {
+        self.attr(sys::UBLK_ATTR_VOLATILE_CACHE, vwc)
     }
 
-    let cqe = ctrl.ring.completion().next().expect("cqueue is empty");
-    let res: i32 = cqe.result();
-    if res == 0 || res == -libc::EBUSY {
-        Ok(res)
-    } else {
-        Err(UblkError::UringIOError(res))
+    /// Device supports FUA, requires volatile cache
+    pub fn fua(self, fua: bool) -> Self {
+        self.attr(sys::UBLK_ATTR_FUA, fua)
     }
-}
 
-#[derive(Debug, Deserialize)]
-struct QueueAffinityJson {
-    affinity: Vec<u32>,
-    qid: u32,
-    tid: u32,
+    /// Enable discard & write zeroes
+    ///
+    /// # Arguments:
+    ///
+    /// * `alignment`: discard alignment in bytes, less than `granularity`
+    /// * `granularity`: discard granularity in bytes, power of 2 and not
+    ///     less than logical block size
+    /// * `max_discard_sectors`: max sectors of each discard
+    /// * `max_write_zeroes_sectors`: max sectors of each write zeroes
+    ///
+    /// Only single segment discard is supported by ublk driver.
+    pub fn discard(
+        mut self,
+        alignment: u32,
+        granularity: u32,
+        max_discard_sectors: u32,
+        max_write_zeroes_sectors: u32,
+    ) -> Self {
+        self.discard = Some(sys::ublk_param_discard {
+            discard_alignment: alignment,
+            discard_granularity: granularity,
+            max_discard_sectors,
+            max_write_zeroes_sectors,
+            max_discard_segments: 1,
+            ..Default::default()
+        });
+        self
+    }
+
+    fn validate(&self, max_io_buf_bytes: u32) -> Result<(), UblkError> {
+        let page_sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        let lbs = self.logical_bs;
+        let is_bs = |bs: u32| bs.is_power_of_two() && bs >= lbs;
+
+        if !lbs.is_power_of_two() || lbs < 512 || lbs > page_sz {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if !is_bs(self.physical_bs) || !is_bs(self.io_opt) || !is_bs(self.io_min) {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if self.max_sectors > (max_io_buf_bytes >> 9) || self.max_sectors < (lbs >> 9) {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if self.chunk_sectors != 0 && !self.chunk_sectors.is_power_of_two() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if self.virt_boundary_mask != 0 && !(self.virt_boundary_mask + 1).is_power_of_two() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if (self.attrs & sys::UBLK_ATTR_FUA) != 0
+            && (self.attrs & sys::UBLK_ATTR_VOLATILE_CACHE) == 0
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        if let Some(d) = &self.discard {
+            if !is_bs(d.discard_granularity) || d.discard_alignment >= d.discard_granularity {
+                return Err(UblkError::OtherError(-libc::EINVAL));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validate all parameters, and build `sys::ublk_params`
+    ///
+    /// # Arguments:
+    ///
+    /// * `max_io_buf_bytes`: device's max IO buffer size, which is
+    ///     `dev_info.max_io_buf_bytes`
+    pub fn build(mut self, max_io_buf_bytes: u32) -> Result<sys::ublk_params, UblkError> {
+        if self.max_sectors == 0 {
+            self.max_sectors = max_io_buf_bytes >> 9;
+        }
+        self.validate(max_io_buf_bytes)?;
+
+        let shift = |v: u32| v.trailing_zeros() as u8;
+        let mut params = sys::ublk_params {
+            types: sys::UBLK_PARAM_TYPE_BASIC,
+            basic: sys::ublk_param_basic {
+                attrs: self.attrs,
+                logical_bs_shift: shift(self.logical_bs),
+                physical_bs_shift: shift(self.physical_bs),
+                io_opt_shift: shift(self.io_opt),
+                io_min_shift: shift(self.io_min),
+                max_sectors: self.max_sectors,
+                chunk_sectors: self.chunk_sectors,
+                dev_sectors: self.dev_size >> 9,
+                virt_boundary_mask: self.virt_boundary_mask,
+            },
+            ..Default::default()
+        };
+
+        if let Some(d) = self.discard {
+            params.types |= sys::UBLK_PARAM_TYPE_DISCARD;
+            params.discard = d;
+        }
+
+        Ok(params)
+    }
 }
 
 /// ublk control device
@@ -143,14 +1419,15 @@ struct QueueAffinityJson {
 ///
 /// 3) exporting device as json file
 pub struct UblkCtrl {
-    file: fs::File,
+    ring: UblkCtrlRing,
     pub dev_info: sys::ublksrv_ctrl_dev_info,
     pub json: serde_json::Value,
     for_add: bool,
-    cmd_token: i32,
+    features: UblkFlags,
     queue_tids: Vec<i32>,
     nr_queues_configured: u16,
-    ring: IoUring<squeue::Entry128>,
+    run_dir: String,
+    export_lock: Option<fs::File>,
 }
 
 impl Drop for UblkCtrl {
@@ -163,99 +1440,240 @@ impl Drop for UblkCtrl {
                 trace!("Delete char device {} failed {}", self.dev_info.dev_id, r);
             }
         }
+        self.unlock_export();
     }
 }
 
 impl UblkCtrl {
-    /// New one ublk control device
+    /// Open control device for sending command to ublk driver
     ///
     /// # Arguments:
     ///
-    /// * `id`: device id, or let driver allocate one if -1 is passed
-    /// * `nr_queues`: how many hw queues allocated for this device
-    /// * `depth`: each hw queue's depth
-    /// * `io_buf_bytes`: max buf size for each IO
-    /// * `flags`: flags for setting ublk device
+    /// * `info`: device info, and queue parameters have been validated
+    ///     by `UblkCtrlBuilder`, which is the only public way for creating
+    ///     `UblkCtrl`
     /// * `for_add`: is for adding new device
+    /// * `run_dir`: directory for storing exported json file
+    /// * `ring`: shared control ring, or create one for this device if
+    ///     it is None
     ///
-    /// ublk control device is for sending command to driver, and maintain
-    /// device exported json file, dump, or any misc management task.
-    ///
-    #[allow(clippy::uninit_vec)]
-    pub fn new(
-        id: i32,
-        nr_queues: u32,
-        depth: u32,
-        io_buf_bytes: u32,
-        flags: u64,
+    /// No command is sent to driver, and the builder retrieves features
+    /// supported by driver first, which decides if ioctl encoded command
+    /// is used.
+    fn open(
+        info: sys::ublksrv_ctrl_dev_info,
         for_add: bool,
+        run_dir: String,
+        ring: Option<UblkCtrlRing>,
     ) -> Result<UblkCtrl, UblkError> {
-        if id < 0 && id != -1 {
-            return Err(UblkError::OtherError(-libc::EINVAL));
-        }
+        Ok(UblkCtrl {
+            ring: match ring {
+                Some(r) => r,
+                None => UblkCtrlRing::new()?,
+            },
+            dev_info: info,
+            json: serde_json::json!({}),
+            for_add,
+            features: UblkFlags::empty(),
+            queue_tids: Vec::new(),
+            nr_queues_configured: 0,
+            run_dir,
+            export_lock: None,
+        })
+    }
 
-        if nr_queues > sys::UBLK_MAX_NR_QUEUES {
-            return Err(UblkError::OtherError(-libc::EINVAL));
+    /// Check features before adding device, and cleanup exported json
+    /// files of devices which are gone
+    ///
+    /// UBLK_F_CMD_IOCTL_ENCODE is set if the driver supports it, since
+    /// ioctl encoded commands are used.
+    fn prep_add(&mut self) -> Result<(), UblkError> {
+        self.check_features(self.features)?;
+        if self.ioctl_encode() {
+            self.dev_info.flags |= UblkFlags::CMD_IOCTL_ENCODE.bits();
         }
-
-        if depth > sys::UBLK_MAX_QUEUE_DEPTH {
-            return Err(UblkError::OtherError(-libc::EINVAL));
+        if let Err(r) = cleanup_stale_exports(&self.run_dir) {
+            trace!("ctrl: cleanup {} failed {}", self.run_dir, r);
         }
+        Ok(())
+    }
 
-        if io_buf_bytes > MAX_BUF_SZ {
-            return Err(UblkError::OtherError(-libc::EINVAL));
-        }
+    fn setup_queues(&mut self) {
+        self.queue_tids = vec![0; self.dev_info.nr_hw_queues as usize];
+        trace!("ctrl: device {} created", self.dev_info.dev_id);
+    }
 
-        let ring = IoUring::<squeue::Entry128, cqueue::Entry>::builder()
-            .build(16)
-            .map_err(UblkError::OtherIOError)?;
-        let info = sys::ublksrv_ctrl_dev_info {
-            nr_hw_queues: nr_queues as u16,
-            queue_depth: depth as u16,
-            max_io_buf_bytes: io_buf_bytes,
-            dev_id: id as u32,
-            ublksrv_pid: unsafe { libc::getpid() } as i32,
-            flags,
+    /// Return the ring for sending control commands of this device
+    pub fn ctrl_ring(&self) -> &UblkCtrlRing {
+        &self.ring
+    }
+
+    /// New one ublk control device for existed device
+    ///
+    /// # Arguments:
+    ///
+    /// * `id`: device id of the existed device
+    ///
+    /// Shortcut of `UblkCtrlBuilder::default().id(id).for_add(false).build()`,
+    /// and it is often used for retrieving info of, or deleting the
+    /// existed device
+    pub fn new_simple(id: i32) -> Result<UblkCtrl, UblkError> {
+        UblkCtrlBuilder::default().id(id).for_add(false).build()
+    }
+
+    fn __get_features(&mut self) -> Result<UblkFlags, UblkError> {
+        let mut features = 0_u64;
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_GET_FEATURES as u32,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_OUT,
+            addr: std::ptr::addr_of_mut!(features) as u64,
+            len: core::mem::size_of::<u64>() as u32,
             ..Default::default()
         };
-        let fd = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(CTRL_PATH)
-            .map_err(UblkError::OtherIOError)?;
 
-        let mut dev = UblkCtrl {
-            file: fd,
-            dev_info: info,
-            json: serde_json::json!({}),
-            ring,
-            for_add,
-            cmd_token: 0,
-            queue_tids: {
-                let mut tids = Vec::<i32>::with_capacity(nr_queues as usize);
-                unsafe {
-                    tids.set_len(nr_queues as usize);
-                }
-                tids
-            },
-            nr_queues_configured: 0,
+        ublk_ctrl_cmd(self, &data)?;
+        Ok(UblkFlags::from_bits_retain(features))
+    }
+
+    async fn __get_features_async(&self) -> Result<UblkFlags, UblkError> {
+        let mut features = 0_u64;
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_U_CMD_GET_FEATURES as u32,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_OUT,
+            addr: std::ptr::addr_of_mut!(features) as u64,
+            len: core::mem::size_of::<u64>() as u32,
+            ..Default::default()
         };
 
-        //add cdev if the device is for adding device
-        if dev.for_add {
-            dev.add()?;
+        ublk_ctrl_cmd_async(self, &data).await?;
+        Ok(UblkFlags::from_bits_retain(features))
+    }
+
+    /// Retrieve features supported by ublk driver
+    ///
+    /// # Return: flag set of all `UBLK_F_*` supported by ublk driver
+    ///
+    /// UBLK_U_CMD_GET_FEATURES is supported since linux v6.5, and error
+    /// is returned on old kernel.
+    pub fn get_features() -> Result<UblkFlags, UblkError> {
+        let info = sys::ublksrv_ctrl_dev_info {
+            dev_id: u32::MAX,
+            ..Default::default()
+        };
+        let mut ctrl = UblkCtrl::open(info, false, UblkCtrl::default_run_dir(), None)?;
+
+        ctrl.__get_features()
+    }
+
+    /// Features supported by ublk driver, and `UBLK_LEGACY_FEATURES` is
+    /// returned if the driver is too old to support UBLK_U_CMD_GET_FEATURES
+    fn supported_features(&mut self) -> UblkFlags {
+        match self.__get_features() {
+            Ok(features) => features,
+            Err(_) => UBLK_LEGACY_FEATURES,
         }
-        trace!("ctrl: device {} created", dev.dev_info.dev_id);
+    }
 
-        Ok(dev)
+    /// Check if all flags in `dev_info.flags` are supported by ublk driver
+    fn check_features(&self, features: UblkFlags) -> Result<(), UblkError> {
+        let flags = UblkFlags::from_bits_retain(self.dev_info.flags);
+        let unsupported = flags.difference(features);
+
+        if !unsupported.is_empty() {
+            let names: Vec<&str> = unsupported.iter_names().map(|(name, _)| name).collect();
+
+            error!(
+                "ctrl: feature {:?} isn't supported, flags 0x{:x}",
+                names, self.dev_info.flags
+            );
+            return Err(UblkError::UnsupportedFeature(if names.is_empty() {
+                format!("0x{:x}", unsupported.bits())
+            } else {
+                names.join("|")
+            }));
+        }
+        Ok(())
+    }
+
+    /// If ioctl encoded command is used, which is decided by features
+    /// supported by driver, and io command encoding follows control command
+    #[inline(always)]
+    pub(crate) fn ioctl_encode(&self) -> bool {
+        self.features.contains(UblkFlags::CMD_IOCTL_ENCODE)
+    }
+
+    /// Check if this control command needs char device path
+    ///
+    /// UBLK_CMD_GET_DEV_INFO2 always carries the path, and any command
+    /// for device created with UBLK_F_UNPRIVILEGED_DEV needs the path too,
+    /// except for UBLK_CMD_ADD_DEV, because the device doesn't exist yet.
+    fn need_dev_path(&self, cmd_op: u32) -> bool {
+        match cmd_op {
+            sys::UBLK_CMD_GET_DEV_INFO2 => true,
+            sys::UBLK_CMD_ADD_DEV => false,
+            _ if cmd_op == sys::UBLK_U_CMD_GET_FEATURES as u32 => false,
+            _ => (self.dev_info.flags & UblkFlags::UNPRIVILEGED_DEV.bits()) != 0,
+        }
+    }
+
+    /// Return uid of this device's owner, which is stored by driver
+    pub fn owner_uid(&self) -> u32 {
+        self.dev_info.owner_uid
+    }
+
+    /// Return gid of this device's owner, which is stored by driver
+    pub fn owner_gid(&self) -> u32 {
+        self.dev_info.owner_gid
+    }
+
+    /// Return how this device is recovered after its daemon is gone
+    pub fn recovery_mode(&self) -> UblkRecoveryMode {
+        UblkRecoveryMode::from(UblkFlags::from_bits_retain(self.dev_info.flags))
     }
 
-    fn dev_state_desc(&self) -> String {
-        match self.dev_info.state as u32 {
-            sys::UBLK_S_DEV_DEAD => "DEAD".to_string(),
-            sys::UBLK_S_DEV_LIVE => "LIVE".to_string(),
-            sys::UBLK_S_DEV_QUIESCED => "QUIESCED".to_string(),
-            _ => "UNKNOWN".to_string(),
+    /// Return this device's state, which is updated by `get_info()`
+    pub fn state(&self) -> UblkDevState {
+        UblkDevState::from(self.dev_info.state)
+    }
+
+    /// Wait until this device becomes the specified state
+    ///
+    /// # Arguments:
+    ///
+    /// * `state`: the state to wait for
+    /// * `timeout`: how long to wait at most
+    ///
+    /// Device info is retrieved from ublk driver periodically. When waiting
+    /// for `UblkDevState::Live`, it also waits until /dev/ublkbN can be
+    /// opened, since the block device is exposed by udev asynchronously.
+    ///
+    /// `UblkError::Timeout` carrying device id and the waited state is
+    /// returned if the device doesn't become the state in `timeout`.
+    pub fn wait_for_state(
+        &mut self,
+        state: UblkDevState,
+        timeout: std::time::Duration,
+    ) -> Result<(), UblkError> {
+        let start = std::time::Instant::now();
+        let bdev = format!("{}{}", super::BDEV_PATH, self.dev_info.dev_id);
+
+        loop {
+            self.get_info()?;
+            if self.state() == state
+                && (state != UblkDevState::Live
+                    || super::ublk_mock_enabled()
+                    || fs::File::open(&bdev).is_ok())
+            {
+                return Ok(());
+            }
+
+            if start.elapsed() >= timeout {
+                return Err(UblkError::Timeout {
+                    dev_id: self.dev_info.dev_id,
+                    state,
+                });
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
         }
     }
 
@@ -266,19 +1684,18 @@ impl UblkCtrl {
     /// * `qid`: queue id
     ///
     pub fn get_queue_tid(&self, qid: u32) -> Result<i32, UblkError> {
-        let queues = &self.json["queues"];
-        let queue = &queues[qid.to_string()];
-        let this_queue: Result<QueueAffinityJson, _> = serde_json::from_value(queue.clone());
+        let e = self.export::<serde_json::Value>()?;
 
-        if let Ok(p) = this_queue {
-            Ok(p.tid.try_into().unwrap())
-        } else {
-            Err(UblkError::OtherError(-libc::EEXIST))
+        match e.queue(qid as u16) {
+            Some(q) => Ok(q.tid),
+            None => Err(UblkError::OtherError(-libc::EEXIST)),
         }
     }
 
     fn store_queue_tid(&mut self, qid: u16, tid: i32) {
-        self.queue_tids[qid as usize] = tid;
+        if let Some(t) = self.queue_tids.get_mut(qid as usize) {
+            *t = tid;
+        }
     }
 
     /// Configure queue affinity and record queue tid
@@ -308,42 +1725,47 @@ impl UblkCtrl {
         if !std::path::Path::new(&self.run_path()).exists() {
             return;
         }
-        let mut file = fs::File::open(self.run_path()).expect("Failed to open file");
-        let mut json_str = String::new();
-
-        file.read_to_string(&mut json_str)
-            .expect("Failed to read file");
-
-        let json_value: serde_json::Value =
-            serde_json::from_str(&json_str).expect("Failed to parse JSON");
-        let queues = &json_value["queues"];
-
-        for i in 0..self.dev_info.nr_hw_queues {
-            let queue = &queues[i.to_string()];
-            let this_queue: Result<QueueAffinityJson, _> = serde_json::from_value(queue.clone());
-
-            if let Ok(p) = this_queue {
-                println!(
-                    "\tqueue {} tid: {} affinity({})",
-                    p.qid,
-                    p.tid,
-                    p.affinity
-                        .iter()
-                        .map(ToString::to_string)
-                        .collect::<Vec<String>>()
-                        .join(" ")
-                );
+
+        let e = match self.load_export::<serde_json::Value>() {
+            Ok(e) => e,
+            Err(r) => {
+                println!("\tfailed to load {}: {}", self.run_path(), r);
+                return;
             }
-        }
-        let tgt_val = &json_value["target"];
-        let tgt: Result<UblkTgt, _> = serde_json::from_value(tgt_val.clone());
-        if let Ok(p) = tgt {
+        };
+
+        for q in &e.queues {
             println!(
-                "\ttarget {{\"dev_size\":{},\"name\":\"{}\",\"type\":0}}",
-                p.dev_size, p.tgt_type
+                "\tqueue {} tid: {} affinity({})",
+                q.qid,
+                q.tid,
+                q.affinity
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<String>>()
+                    .join(" ")
             );
         }
-        println!("\ttarget_data {}", &json_value["target_data"]);
+        println!(
+            "\ttarget {{\"dev_size\":{},\"name\":\"{}\"}}",
+            e.target.dev_size, e.target.tgt_type
+        );
+        println!("\ttarget_data {}", e.target_data);
+    }
+
+    /// Parse this device's json info(`UblkCtrl.json`) as `UblkDevExport`
+    ///
+    /// The json info is built after all queues are setup, or loaded by
+    /// `reload_json()`.
+    pub fn export<T: serde::de::DeserializeOwned>(&self) -> Result<UblkDevExport<T>, UblkError> {
+        UblkDevExport::<T>::from_value(self.json.clone())
+    }
+
+    /// Load this device's exported json file as `UblkDevExport`
+    pub fn load_export<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> Result<UblkDevExport<T>, UblkError> {
+        UblkDevExport::<T>::load(&self.run_path())
     }
 
     /// Dump this device info
@@ -382,50 +1804,107 @@ impl UblkCtrl {
             info.max_io_buf_bytes,
             info.ublksrv_pid,
             info.flags,
-            self.dev_state_desc()
+            self.state()
         );
 
         self.dump_from_json();
     }
 
-    pub fn run_dir() -> String {
+    /// Default directory for storing device's exported json file
+    pub fn default_run_dir() -> String {
         format!("{}/ublk", std::env::temp_dir().display())
     }
 
+    /// Directory for storing this device's exported json file
+    pub fn run_dir(&self) -> &str {
+        &self.run_dir
+    }
+
     /// Returned path of this device's exported json file
     ///
     pub fn run_path(&self) -> String {
-        format!("{}/{:04}.json", UblkCtrl::run_dir(), self.dev_info.dev_id)
+        ublk_export_path(&self.run_dir, self.dev_info.dev_id)
+    }
+
+    /// Take lock of this device's exported json file
+    ///
+    /// The lock is held until this control device is dropped or the
+    /// device is stopped, so that two daemons can't claim the same
+    /// device's exported json file.
+    fn lock_export(&mut self) -> Result<(), UblkError> {
+        if self.export_lock.is_none() {
+            let lock = ublk_lock_export(&self.run_dir, self.dev_info.dev_id, 10)?;
+
+            #[cfg(feature = "mock-driver")]
+            if super::mock::enabled() {
+                super::mock::set_export_lock(self.dev_info.dev_id, Some(lock.as_raw_fd()));
+            }
+            self.export_lock = Some(lock);
+        }
+        Ok(())
+    }
+
+    /// Release lock of this device's exported json file
+    ///
+    /// The lock file is removed too.
+    fn unlock_export(&mut self) {
+        if let Some(lock) = self.export_lock.take() {
+            #[cfg(feature = "mock-driver")]
+            if super::mock::enabled() {
+                super::mock::set_export_lock(self.dev_info.dev_id, None);
+            }
+            ublk_unlock_export(&self.run_dir, self.dev_info.dev_id, lock);
+        }
     }
 
     fn add(&mut self) -> Result<i32, UblkError> {
+        let mut info = self.dev_info;
         let data: UblkCtrlCmdData = UblkCtrlCmdData {
             cmd_op: sys::UBLK_CMD_ADD_DEV,
-            flags: CTRL_CMD_HAS_BUF,
-            addr: std::ptr::addr_of!(self.dev_info) as u64,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_OUT,
+            addr: std::ptr::addr_of_mut!(info) as u64,
             len: core::mem::size_of::<sys::ublksrv_ctrl_dev_info>() as u32,
-            data: [0, 0],
+            ..Default::default()
         };
 
-        ublk_ctrl_cmd(self, &data)
+        let res = ublk_ctrl_cmd(self, &data)?;
+        self.dev_info = info;
+        Ok(res)
+    }
+
+    async fn add_async(&mut self) -> Result<i32, UblkError> {
+        let mut info = self.dev_info;
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_CMD_ADD_DEV,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_OUT,
+            addr: std::ptr::addr_of_mut!(info) as u64,
+            len: core::mem::size_of::<sys::ublksrv_ctrl_dev_info>() as u32,
+            ..Default::default()
+        };
+
+        let res = ublk_ctrl_cmd_async(self, &data).await?;
+        self.dev_info = info;
+        Ok(res)
     }
 
     /// Poll one control command until it is completed
     ///
-    /// Note: so far, we only support to poll at most one-inflight
-    /// command, and the use case is for supporting to run start_dev
-    /// in queue io handling context
+    /// # Arguments:
+    ///
+    /// * `token`: returned from command sent asynchronously, such as
+    ///     `start()` with `async_cmd`
+    ///
+    /// -EAGAIN is returned if the command isn't completed yet. Completions
+    /// of other commands are kept in the control ring, so many commands
+    /// can be polled at the same time.
+    ///
+    /// The completed command's result is same with the one sent without
+    /// `async_cmd`, such as Ok(-EBUSY) of `start_user_recover()`.
     pub fn poll_cmd(&mut self, token: i32) -> Result<i32, UblkError> {
-        if self.ring.completion().is_empty() {
-            return Err(UblkError::UringIOError(-libc::EAGAIN));
-        }
-
-        let cqe = self.ring.completion().next().expect("cqueue is empty");
-        let res: i32 = cqe.result();
-        if res == 0 && cqe.user_data() == token as u64 {
-            Ok(res)
-        } else {
-            Err(UblkError::UringIOError(res))
+        self.ring.reap_events();
+        match self.ring.take(token as u64) {
+            None => Err(UblkError::UringIOError(-libc::EAGAIN)),
+            Some(slot) => ublk_ctrl_complete(&slot),
         }
     }
 
@@ -440,6 +1919,16 @@ impl UblkCtrl {
         ublk_ctrl_cmd(self, &data)
     }
 
+    /// Remove this device asynchronously
+    pub async fn del_async(&mut self) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_CMD_DEL_DEV,
+            ..Default::default()
+        };
+
+        ublk_ctrl_cmd_async(self, &data).await
+    }
+
     /// Remove this device and its exported json file
     ///
     /// Called when the user wants to remove one device really
@@ -452,18 +1941,58 @@ impl UblkCtrl {
         Ok(0)
     }
 
+    fn __get_info(&mut self, cmd_op: u32) -> Result<i32, UblkError> {
+        let mut info = self.dev_info;
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_OUT,
+            addr: std::ptr::addr_of_mut!(info) as u64,
+            len: core::mem::size_of::<sys::ublksrv_ctrl_dev_info>() as u32,
+            ..Default::default()
+        };
+
+        let res = ublk_ctrl_cmd(self, &data)?;
+        self.dev_info = info;
+        Ok(res)
+    }
+
     /// Retrieving device info from ublk driver
     ///
+    /// UBLK_CMD_GET_DEV_INFO2 is preferred since it works for device
+    /// created with UBLK_F_UNPRIVILEGED_DEV, and fallback to
+    /// UBLK_CMD_GET_DEV_INFO on old kernel.
     pub fn get_info(&mut self) -> Result<i32, UblkError> {
+        match self.__get_info(sys::UBLK_CMD_GET_DEV_INFO2) {
+            Err(e) if matches!(e.errno(), Some(r) if r == -libc::EOPNOTSUPP || r == -libc::EINVAL) => {
+                self.__get_info(sys::UBLK_CMD_GET_DEV_INFO)
+            }
+            res => res,
+        }
+    }
+
+    async fn __get_info_async(&mut self, cmd_op: u32) -> Result<i32, UblkError> {
+        let mut info = self.dev_info;
         let data: UblkCtrlCmdData = UblkCtrlCmdData {
-            cmd_op: sys::UBLK_CMD_GET_DEV_INFO,
-            flags: CTRL_CMD_HAS_BUF,
-            addr: std::ptr::addr_of!(self.dev_info) as u64,
+            cmd_op,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_OUT,
+            addr: std::ptr::addr_of_mut!(info) as u64,
             len: core::mem::size_of::<sys::ublksrv_ctrl_dev_info>() as u32,
             ..Default::default()
         };
 
-        ublk_ctrl_cmd(self, &data)
+        let res = ublk_ctrl_cmd_async(self, &data).await?;
+        self.dev_info = info;
+        Ok(res)
+    }
+
+    /// Retrieving device info from ublk driver asynchronously
+    pub async fn get_info_async(&mut self) -> Result<i32, UblkError> {
+        match self.__get_info_async(sys::UBLK_CMD_GET_DEV_INFO2).await {
+            Err(e) if matches!(e.errno(), Some(r) if r == -libc::EOPNOTSUPP || r == -libc::EINVAL) => {
+                self.__get_info_async(sys::UBLK_CMD_GET_DEV_INFO).await
+            }
+            res => res,
+        }
     }
 
     /// Start this device by sending command to ublk driver
@@ -479,6 +2008,21 @@ impl UblkCtrl {
         ublk_ctrl_cmd(self, &data)
     }
 
+    /// Start this device asynchronously
+    ///
+    /// The returned future is completed after all queues are ready for
+    /// handling IO, so queues have to be setup in other contexts.
+    pub async fn start_async(&mut self, pid: i32) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_CMD_START_DEV,
+            flags: CTRL_CMD_HAS_DATA,
+            data: [pid as u64, 0],
+            ..Default::default()
+        };
+
+        ublk_ctrl_cmd_async(self, &data).await
+    }
+
     /// Stop this device by sending command to ublk driver
     ///
     pub fn stop(&mut self) -> Result<i32, UblkError> {
@@ -490,6 +2034,16 @@ impl UblkCtrl {
         ublk_ctrl_cmd(self, &data)
     }
 
+    /// Stop this device asynchronously
+    pub async fn stop_async(&mut self) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_CMD_STOP_DEV,
+            ..Default::default()
+        };
+
+        ublk_ctrl_cmd_async(self, &data).await
+    }
+
     /// Retrieve this device's parameter from ublk driver by
     /// sending command
     ///
@@ -501,8 +2055,8 @@ impl UblkCtrl {
         params.len = core::mem::size_of::<sys::ublk_params>() as u32;
         let data: UblkCtrlCmdData = UblkCtrlCmdData {
             cmd_op: sys::UBLK_CMD_GET_PARAMS,
-            flags: CTRL_CMD_HAS_BUF,
-            addr: std::ptr::addr_of!(params) as u64,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_OUT,
+            addr: std::ptr::addr_of_mut!(params) as u64,
             len: params.len,
             ..Default::default()
         };
@@ -511,6 +2065,40 @@ impl UblkCtrl {
         Ok(params)
     }
 
+    /// Retrieve this device's parameter from ublk driver asynchronously
+    pub async fn get_params_async(
+        &mut self,
+        mut params: sys::ublk_params,
+    ) -> Result<sys::ublk_params, UblkError> {
+        params.len = core::mem::size_of::<sys::ublk_params>() as u32;
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_CMD_GET_PARAMS,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_BUF_OUT,
+            addr: std::ptr::addr_of_mut!(params) as u64,
+            len: params.len,
+            ..Default::default()
+        };
+
+        ublk_ctrl_cmd_async(self, &data).await?;
+        Ok(params)
+    }
+
+    /// Retrieve this device's char & block device numbers from ublk driver
+    ///
+    /// `ublk_param_devt` is read-only, and disk's device number is only
+    /// available after the device is started, otherwise -ENODEV is
+    /// returned.
+    pub fn get_devt(&mut self) -> Result<sys::ublk_param_devt, UblkError> {
+        let p = self.get_params(sys::ublk_params {
+            ..Default::default()
+        })?;
+
+        if (p.types & sys::UBLK_PARAM_TYPE_DEVT) == 0 || p.devt.disk_major == 0 {
+            return Err(UblkError::OtherError(-libc::ENODEV));
+        }
+        Ok(p.devt)
+    }
+
     /// Send this device's parameter to ublk driver
     ///
     /// Note: device parameter has to send to driver before starting
@@ -530,6 +2118,22 @@ impl UblkCtrl {
         ublk_ctrl_cmd(self, &data)
     }
 
+    /// Send this device's parameter to ublk driver asynchronously
+    pub async fn set_params_async(&mut self, params: &sys::ublk_params) -> Result<i32, UblkError> {
+        let mut p = *params;
+
+        p.len = core::mem::size_of::<sys::ublk_params>() as u32;
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_CMD_SET_PARAMS,
+            flags: CTRL_CMD_HAS_BUF,
+            addr: std::ptr::addr_of!(p) as u64,
+            len: p.len,
+            ..Default::default()
+        };
+
+        ublk_ctrl_cmd_async(self, &data).await
+    }
+
     /// Retrieving the specified queue's affinity from ublk driver
     ///
     pub fn get_queue_affinity(
@@ -539,14 +2143,33 @@ impl UblkCtrl {
     ) -> Result<i32, UblkError> {
         let data: UblkCtrlCmdData = UblkCtrlCmdData {
             cmd_op: sys::UBLK_CMD_GET_QUEUE_AFFINITY,
-            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_HAS_DATA,
-            addr: bm.addr() as u64,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_HAS_DATA | CTRL_CMD_BUF_OUT,
+            addr: bm.addr_mut() as u64,
             data: [q as u64, 0],
             len: bm.buf_len() as u32,
+            ..Default::default()
         };
         ublk_ctrl_cmd(self, &data)
     }
 
+    /// Retrieving the specified queue's affinity from ublk driver
+    /// asynchronously
+    pub async fn get_queue_affinity_async(
+        &mut self,
+        q: u32,
+        bm: &mut UblkQueueAffinity,
+    ) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_CMD_GET_QUEUE_AFFINITY,
+            flags: CTRL_CMD_HAS_BUF | CTRL_CMD_HAS_DATA | CTRL_CMD_BUF_OUT,
+            addr: bm.addr_mut() as u64,
+            data: [q as u64, 0],
+            len: bm.buf_len() as u32,
+            ..Default::default()
+        };
+        ublk_ctrl_cmd_async(self, &data).await
+    }
+
     pub fn __start_user_recover(&mut self) -> Result<i32, UblkError> {
         let data: UblkCtrlCmdData = UblkCtrlCmdData {
             cmd_op: sys::UBLK_CMD_START_USER_RECOVERY,
@@ -556,6 +2179,19 @@ impl UblkCtrl {
         ublk_ctrl_cmd(self, &data)
     }
 
+    /// Start user recover for this device asynchronously
+    ///
+    /// Ok(-EBUSY) is returned if the device isn't quiesced yet, and
+    /// caller has to retry after a while, see `start_user_recover()`.
+    pub async fn start_user_recover_async(&mut self) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_CMD_START_USER_RECOVERY,
+            ..Default::default()
+        };
+
+        ublk_ctrl_cmd_async(self, &data).await
+    }
+
     /// Start user recover for this device
     ///
     pub fn start_user_recover(&mut self) -> Result<i32, UblkError> {
@@ -577,6 +2213,72 @@ impl UblkCtrl {
         }
     }
 
+    /// Prepare `UblkDev` for recovering this device
+    ///
+    /// The device's exported json file is reloaded and validated, then
+    /// `UblkDev` is restored from the exported `target` and initialized
+    /// by `UblkTarget::recover()` with the exported `target_data`. User
+    /// recovery is started only after the target is recovered, and
+    /// just before /dev/ublkcN is opened.
+    ///
+    /// If anything fails after user recovery is started, /dev/ublkcN is
+    /// closed, which ends this recovery, and the device is kept quiesced
+    /// for being recovered again.
+    fn recover_dev<T: UblkTarget>(
+        &mut self,
+        tgt: &mut T,
+        dev_flags: u32,
+    ) -> Result<UblkDev, UblkError> {
+        if (self.dev_info.flags & UblkFlags::USER_RECOVERY.bits()) == 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        let export = self.load_export::<T::Data>()?;
+        let info = &export.dev_info;
+        if info.dev_id != self.dev_info.dev_id
+            || info.nr_hw_queues != self.dev_info.nr_hw_queues
+            || info.queue_depth != self.dev_info.queue_depth
+        {
+            return Err(UblkError::InvalidExport(format!(
+                "dev {} queues {} depth {} doesn't match the device",
+                info.dev_id, info.nr_hw_queues, info.queue_depth
+            )));
+        }
+
+        let run_dir = self.run_dir.clone();
+        let saved = export.target;
+        let res = UblkDev::__new(
+            saved.tgt_type.clone(),
+            |dev: &mut UblkDev| {
+                dev.tgt.dev_size = saved.dev_size;
+                dev.tgt.params = saved.params;
+                dev.tgt.ring_flags = saved.ring_flags;
+                dev.tgt.ring_cfg = saved.ring_cfg.clone();
+                dev.tgt.sq_depth = saved.sq_depth;
+                dev.tgt.cq_depth = saved.cq_depth;
+                dev.tgt.extra_ios = saved.extra_ios;
+                let data = tgt.recover(dev, export.target_data)?;
+
+                let inflight: Vec<UblkInflightIo> = (0..dev.dev_info.nr_hw_queues)
+                    .flat_map(|q| {
+                        let path = ublk_inflight_path(&run_dir, dev.dev_info.dev_id, q);
+                        ublk_load_inflight(&path, q)
+                    })
+                    .collect();
+                tgt.reconcile(dev, &inflight)?;
+                serde_json::to_value(data).map_err(UblkError::JsonError)
+            },
+            self,
+            dev_flags,
+            |ctrl: &mut UblkCtrl| ctrl.start_user_recover(),
+        );
+
+        if let Err(e) = &res {
+            error!("dev {} recover failed {}", self.dev_info.dev_id, e);
+        }
+        res
+    }
+
     /// End user recover for this device
     ///
     pub fn end_user_recover(&mut self, pid: i32, async_cmd: bool) -> Result<i32, UblkError> {
@@ -590,15 +2292,30 @@ impl UblkCtrl {
         ublk_ctrl_cmd(self, &data)
     }
 
+    /// End user recover for this device asynchronously
+    ///
+    /// The returned future is completed after all queues are ready for
+    /// handling IO, same with `start_async()`.
+    pub async fn end_user_recover_async(&mut self, pid: i32) -> Result<i32, UblkError> {
+        let data: UblkCtrlCmdData = UblkCtrlCmdData {
+            cmd_op: sys::UBLK_CMD_END_USER_RECOVERY,
+            flags: CTRL_CMD_HAS_DATA,
+            data: [pid as u64, 0],
+            ..Default::default()
+        };
+
+        ublk_ctrl_cmd_async(self, &data).await
+    }
+
     fn __start_dev(&mut self, dev: &UblkDev, async_cmd: bool) -> Result<i32, UblkError> {
         self.get_info()?;
-        if self.dev_info.state == sys::UBLK_S_DEV_LIVE as u16 {
+        if self.state() == UblkDevState::Live {
             return Ok(0);
         }
 
-        let token = if self.dev_info.state != sys::UBLK_S_DEV_QUIESCED as u16 {
+        self.flush_json()?;
+        let token = if self.state() != UblkDevState::Quiesced {
             self.set_params(&dev.tgt.params)?;
-            self.flush_json()?;
             self.start(unsafe { libc::getpid() as i32 }, async_cmd)?
         } else {
             self.end_user_recover(unsafe { libc::getpid() as i32 }, async_cmd)?
@@ -645,28 +2362,28 @@ impl UblkCtrl {
     where
         F: FnMut(&mut super::io::UblkIOCtx) -> Result<i32, UblkError>,
     {
-        let mut started = false;
         let token = self.__start_dev(dev, true)?;
 
+        //the device is live already
+        if token == 0 {
+            return Ok(0);
+        }
+
         q.set_poll(true);
-        while !started {
+        let res = loop {
             std::thread::sleep(std::time::Duration::from_millis(10));
-            if let Ok(res) = self.poll_cmd(token) {
-                started = true;
-                if res == 0 {
-                    continue;
-                } else {
-                    return Err(UblkError::UringIOError(res));
-                }
+            match self.poll_cmd(token) {
+                Ok(_) => break Ok(0),
+                Err(e) if e.errno() != Some(-libc::EAGAIN) => break Err(e),
+                _ => {}
             }
-            match q.process_io(&mut ops) {
-                Err(r) => return Err(r),
-                _ => continue,
+            if let Err(e) = q.process_io(&mut ops) {
+                break Err(e);
             }
-        }
+        };
         q.set_poll(false);
 
-        Ok(0)
+        res
     }
 
     /// Stop ublk device
@@ -681,25 +2398,42 @@ impl UblkCtrl {
         if self.for_add && std::path::Path::new(&self.run_path()).exists() {
             fs::remove_file(self.run_path()).map_err(UblkError::OtherIOError)?;
         }
+        self.unlock_export();
         self.stop()
     }
 
     /// Flush this device's json info as file
+    ///
+    /// The json is written to one temporary file, which is renamed to
+    /// `run_path()` after it is synced, so the exported json file is
+    /// never seen half written. Lock of the exported json file is taken
+    /// before writing, and -EBUSY is returned if it is held by another
+    /// daemon.
     pub fn flush_json(&mut self) -> Result<i32, UblkError> {
         if self.json == serde_json::json!({}) {
             return Ok(0);
         }
 
+        fs::create_dir_all(&self.run_dir).map_err(UblkError::OtherIOError)?;
+        self.lock_export()?;
+
         let run_path = self.run_path();
+        let tmp_path = format!("{}.tmp.{}", run_path, unsafe { libc::getpid() });
+        let res = (|| -> std::io::Result<()> {
+            let mut tmp_file = fs::File::create(&tmp_path)?;
 
-        if let Some(parent_dir) = std::path::Path::new(&run_path).parent() {
-            fs::create_dir_all(parent_dir).map_err(UblkError::OtherIOError)?;
-        }
-        let mut run_file = fs::File::create(&run_path).map_err(UblkError::OtherIOError)?;
+            tmp_file.write_all(self.json.to_string().as_bytes())?;
+            tmp_file.sync_all()?;
+            fs::rename(&tmp_path, &run_path)?;
 
-        run_file
-            .write_all(self.json.to_string().as_bytes())
-            .map_err(UblkError::OtherIOError)?;
+            //make the rename durable
+            fs::File::open(&self.run_dir)?.sync_all()
+        })();
+
+        if let Err(e) = res {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(UblkError::OtherIOError(e));
+        }
         Ok(0)
     }
 
@@ -713,44 +2447,320 @@ impl UblkCtrl {
     /// pthread tid
     ///
     fn build_json(&mut self, dev: &UblkDev) {
-        let tgt_data = self.json.clone();
-        let mut map: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
+        let mut queues = Vec::new();
 
         for qid in 0..dev.dev_info.nr_hw_queues {
             let mut affinity = self::UblkQueueAffinity::new();
-            self.get_queue_affinity(qid as u32, &mut affinity).unwrap();
-
-            map.insert(
-                format!("{}", qid),
-                serde_json::json!({
-                    "qid": qid,
-                    "tid": self.queue_tids[qid as usize],
-                    "affinity": affinity.to_bits_vec(),
-                }),
-            );
-        }
+            if let Err(r) = self.get_queue_affinity(qid as u32, &mut affinity) {
+                error!(
+                    "dev {} queue {} get affinity failed {}",
+                    self.dev_info.dev_id, qid, r
+                );
+            }
 
-        let mut json = serde_json::json!({
-                    "dev_info": dev.dev_info,
-                    "target": dev.tgt,
-        });
+            queues.push(UblkQueueExport {
+                qid,
+                tid: self.queue_tids[qid as usize],
+                affinity: affinity.to_bits_vec().iter().map(|c| *c as u32).collect(),
+            });
+        }
 
-        json["target_data"] = tgt_data;
-        json["queues"] = serde_json::Value::Object(map);
+        let export = UblkDevExport {
+            version: UBLK_EXPORT_VERSION,
+            dev_info: dev.dev_info,
+            target: dev.tgt.clone(),
+            target_data: self.json.clone(),
+            queues,
+        };
 
-        self.json = json;
+        match serde_json::to_value(export) {
+            Ok(json) => self.json = json,
+            Err(r) => error!("dev {} build json failed {}", self.dev_info.dev_id, r),
+        }
     }
 
     /// Reload json info for this device
     ///
+    /// The file is validated as `UblkDevExport` before it is stored to
+    /// `UblkCtrl.json`
     pub fn reload_json(&mut self) -> Result<i32, UblkError> {
-        let mut file = fs::File::open(self.run_path()).map_err(UblkError::OtherIOError)?;
-        let mut json_str = String::new();
+        let e = self.load_export::<serde_json::Value>()?;
 
-        file.read_to_string(&mut json_str)
-            .map_err(UblkError::OtherIOError)?;
-        self.json = serde_json::from_str(&json_str).map_err(UblkError::JsonError)?;
+        self.json = serde_json::to_value(e).map_err(UblkError::JsonError)?;
 
         Ok(0)
     }
 }
+
+/// Summary of one existed ublk device, returned from `list_devices()`
+#[derive(Debug, Clone, Serialize)]
+pub struct UblkDevInfo {
+    /// device id, /dev/ublkcN and /dev/ublkbN
+    pub id: u32,
+
+    /// device state
+    pub state: UblkDevState,
+
+    /// how many hw queues
+    pub nr_queues: u16,
+
+    /// each hw queue's depth
+    pub depth: u16,
+
+    /// flags for setting ublk device
+    pub flags: UblkFlags,
+
+    /// pid of ublk daemon which serves this device
+    pub pid: i32,
+
+    /// device capacity in bytes
+    pub capacity: u64,
+
+    /// target type from exported json file, None if the json file
+    /// isn't available
+    pub tgt_type: Option<String>,
+}
+
+impl UblkDevInfo {
+    fn from_ctrl(ctrl: &mut UblkCtrl) -> Result<UblkDevInfo, UblkError> {
+        let params = ctrl.get_params(sys::ublk_params {
+            ..Default::default()
+        })?;
+        let tgt_type = ctrl
+            .load_export::<serde_json::Value>()
+            .ok()
+            .map(|e| e.target.tgt_type);
+        let info = &ctrl.dev_info;
+
+        Ok(UblkDevInfo {
+            id: info.dev_id,
+            state: UblkDevState::from(info.state),
+            nr_queues: info.nr_hw_queues,
+            depth: info.queue_depth,
+            flags: UblkFlags::from_bits_retain(info.flags),
+            pid: info.ublksrv_pid,
+            capacity: params.basic.dev_sectors << 9,
+            tgt_type,
+        })
+    }
+}
+
+/// Collect ids of all existed ublk devices
+///
+/// /sys/class/ublk-char is scanned first, and /dev/ublkc* is scanned
+/// if sysfs isn't available.
+fn ublk_dev_ids() -> Result<Vec<u32>, UblkError> {
+    #[cfg(feature = "mock-driver")]
+    if super::mock::enabled() {
+        return Ok(super::mock::dev_ids());
+    }
+
+    let dir = if std::path::Path::new(SYSFS_CDEV_CLASS).exists() {
+        SYSFS_CDEV_CLASS
+    } else {
+        "/dev"
+    };
+    let mut ids = Vec::new();
+
+    for entry in fs::read_dir(dir).map_err(UblkError::OtherIOError)? {
+        let entry = entry.map_err(UblkError::OtherIOError)?;
+        if let Some(id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.strip_prefix("ublkc"))
+            .and_then(|n| n.parse::<u32>().ok())
+        {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+
+    Ok(ids)
+}
+
+/// List all existed ublk devices
+///
+/// Each device's info is retrieved from ublk driver by GET_DEV_INFO and
+/// GET_PARAMS command, and target type is read from the device's exported
+/// json file.
+///
+/// Device which is removed during listing, or which can't be accessed by
+/// current user is skipped.
+///
+/// # Return: vector of `UblkDevInfo`, sorted by device id
+pub fn list_devices() -> Result<Vec<UblkDevInfo>, UblkError> {
+    let mut devs = Vec::new();
+
+    for id in ublk_dev_ids()? {
+        let res = UblkCtrl::new_simple(id as i32).and_then(|mut c| UblkDevInfo::from_ctrl(&mut c));
+
+        match res {
+            Ok(d) => devs.push(d),
+            Err(r) => trace!("list: skip dev {} {}", id, r),
+        }
+    }
+
+    Ok(devs)
+}
+
+fn ublk_export_path(run_dir: &str, dev_id: u32) -> String {
+    format!("{}/{:04}.json", run_dir, dev_id)
+}
+
+fn ublk_dev_exists(dev_id: u32) -> bool {
+    #[cfg(feature = "mock-driver")]
+    if super::mock::enabled() {
+        return super::mock::dev_ids().contains(&dev_id);
+    }
+
+    std::path::Path::new(&format!("{}/ublkc{}", SYSFS_CDEV_CLASS, dev_id)).exists()
+        || std::path::Path::new(&format!("{}{}", super::CDEV_PATH, dev_id)).exists()
+}
+
+fn ublk_pid_alive(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn ublk_lock_path(run_dir: &str, dev_id: u32) -> String {
+    format!("{}/{:04}.lock", run_dir, dev_id)
+}
+
+/// Take lock of device's exported json file
+///
+/// The lock is one advisory lock(flock) on `NNNN.lock` in `run_dir`, and
+/// it is retried for `retry` times since the lock may be held for a while
+/// by `cleanup_stale_exports()`.
+///
+/// The lock file is removed by its owner before releasing the lock, so
+/// the lock is taken again if the locked file isn't the one in `run_dir`
+/// any more.
+fn ublk_lock_export(run_dir: &str, dev_id: u32, mut retry: u32) -> Result<fs::File, UblkError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let lock_path = ublk_lock_path(run_dir, dev_id);
+
+    loop {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(UblkError::OtherIOError)?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            let locked = file.metadata().map_err(UblkError::OtherIOError)?;
+
+            match fs::metadata(&lock_path) {
+                Ok(m) if m.dev() == locked.dev() && m.ino() == locked.ino() => return Ok(file),
+                _ => continue,
+            }
+        }
+
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+            return Err(UblkError::OtherIOError(e));
+        }
+        if retry == 0 {
+            return Err(UblkError::OtherError(-libc::EBUSY));
+        }
+        retry -= 1;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+/// Release lock taken by `ublk_lock_export()`, and remove the lock file
+fn ublk_unlock_export(run_dir: &str, dev_id: u32, lock: fs::File) {
+    let _ = fs::remove_file(ublk_lock_path(run_dir, dev_id));
+    drop(lock);
+}
+
+/// Remove stale exported json files in `run_dir`
+///
+/// Exported json file is stale if its device doesn't exist any more, and
+/// the daemon which wrote it is gone, which is usually caused by daemon
+/// crash. Exported json file of device which can be recovered is kept,
+/// since it is required for recovering the device.
+///
+/// # Return: ids of devices whose stale exported json file is removed
+pub fn cleanup_stale_exports(run_dir: &str) -> Result<Vec<u32>, UblkError> {
+    let mut ids = Vec::new();
+
+    if !std::path::Path::new(run_dir).exists() {
+        return Ok(ids);
+    }
+
+    let mut exports = Vec::new();
+    let mut inflight: HashMap<u32, Vec<std::path::PathBuf>> = HashMap::new();
+    for entry in fs::read_dir(run_dir).map_err(UblkError::OtherIOError)? {
+        let entry = entry.map_err(UblkError::OtherIOError)?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        //temporary file left by crashed flush_json()
+        if let Some((_, pid)) = name.split_once(".json.tmp.") {
+            if let Ok(pid) = pid.parse::<i32>() {
+                if !ublk_pid_alive(pid) {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+            continue;
+        }
+
+        //per-queue inflight file, `NNNN.qM.inflight`
+        if let Some(id) = name
+            .strip_suffix(".inflight")
+            .and_then(|n| n.split_once(".q"))
+            .and_then(|(id, _)| id.parse::<u32>().ok())
+        {
+            inflight.entry(id).or_default().push(entry.path());
+            continue;
+        }
+
+        if let Some(id) = name
+            .strip_suffix(".json")
+            .and_then(|n| n.parse::<u32>().ok())
+        {
+            exports.push(id);
+        }
+    }
+
+    for id in exports {
+        if ublk_dev_exists(id) {
+            continue;
+        }
+
+        //the exported json file is owned by live daemon
+        let lock = match ublk_lock_export(run_dir, id, 0) {
+            Ok(l) => l,
+            Err(_) => continue,
+        };
+
+        let path = ublk_export_path(run_dir, id);
+        if let Ok(e) = UblkDevExport::<serde_json::Value>::load(&path) {
+            if ublk_pid_alive(e.dev_info.ublksrv_pid) {
+                ublk_unlock_export(run_dir, id, lock);
+                continue;
+            }
+        }
+
+        if let Err(e) = fs::remove_file(&path) {
+            ublk_unlock_export(run_dir, id, lock);
+            return Err(UblkError::OtherIOError(e));
+        }
+        trace!("ctrl: remove stale export {}", path);
+        ids.push(id);
+
+        //inflight files left by crashed daemon
+        for path in inflight.remove(&id).unwrap_or_default() {
+            let _ = fs::remove_file(path);
+        }
+        ublk_unlock_export(run_dir, id, lock);
+    }
+
+    Ok(ids)
+}
@@ -0,0 +1,523 @@
+//! Per-queue local executor for handling ublk IO with async/await
+//!
+//! Opt-in by returning `UblkAsyncHandler` from `UblkTarget::queue_handler()`,
+//! then each incoming io command spawns one future, which is represented by
+//! `UblkIoTask`, and the io command is completed with the future's output.
+//!
+//! Futures are polled in the queue thread only, and driven by the queue's
+//! io_uring: target io is submitted by awaitable `UblkUringOp`, whose
+//! user_data is built by `UblkIOCtx::build_user_data()`, so its completion
+//! is routed to `UblkQueueHandler::handle_tgt_io()`, which wakes up the
+//! owner task.
+//!
+//! Wakers from other threads don't wake up the queue's io_uring, so tasks
+//! can only await `UblkUringOp` or futures woken up by tasks of this queue.
+//!
+//! `UblkUringOp` dropped before completion is cancelled, and the io command
+//! isn't completed until the cancelled operation's CQE is received, so its
+//! io buffer can't be reused by next io command meantime.
+
+use super::io::{UblkIOCtx, UblkIoDesc, UblkQueueCtx, UblkQueueHandler, UBLK_IO_S_COMP_BATCH};
+use super::{sys, UblkError};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+type UblkTaskFuture = Pin<Box<dyn Future<Output = i32>>>;
+
+/// Completion of one target io submitted by `UblkUringOp`
+#[derive(Default)]
+struct UblkOpSlot {
+    res: Option<i32>,
+    waker: Option<Waker>,
+
+    /// the op is dropped and cancelled, and the slot is removed after
+    /// its CQE is received
+    cancelled: bool,
+}
+
+/// State shared by the executor and all its tasks in the queue thread
+#[derive(Default)]
+struct UblkExecShared {
+    /// SQEs to be pushed to queue's io_uring
+    sqes: Vec<squeue::Entry>,
+
+    /// target io in flight, indexed by user_data
+    ops: HashMap<u64, UblkOpSlot>,
+
+    /// stored in `tgt_data` of user_data for making it unique
+    seq: u16,
+}
+
+impl UblkExecShared {
+    fn next_user_data(&mut self, tag: u16, op: u8) -> u64 {
+        self.seq = self.seq.wrapping_add(1);
+        UblkIOCtx::build_user_data(tag, op as u32, self.seq as u32, true)
+    }
+
+    fn complete(&mut self, user_data: u64, res: i32) {
+        match self.ops.get_mut(&user_data) {
+            Some(slot) if slot.cancelled => {
+                self.ops.remove(&user_data);
+            }
+            Some(slot) => {
+                slot.res = Some(res);
+                if let Some(w) = slot.waker.take() {
+                    w.wake();
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// Cancel the op which is dropped before completion
+    ///
+    /// The slot is kept until the op's CQE is received, so that its io
+    /// command isn't completed meantime.
+    fn cancel(&mut self, user_data: u64) {
+        let tag = UblkIOCtx::user_data_to_tag(user_data) as u16;
+        let cancel_data = self.next_user_data(tag, opcode::AsyncCancel::CODE);
+
+        match self.ops.get_mut(&user_data) {
+            Some(slot) if slot.res.is_none() => {
+                slot.cancelled = true;
+                slot.waker = None;
+                self.sqes.push(
+                    opcode::AsyncCancel::new(user_data)
+                        .build()
+                        .user_data(cancel_data),
+                );
+            }
+            _ => {
+                self.ops.remove(&user_data);
+            }
+        }
+    }
+
+    /// If any cancelled op of `tag` isn't completed
+    fn has_cancelled(&self, tag: u16) -> bool {
+        self.ops
+            .iter()
+            .any(|(data, slot)| slot.cancelled && UblkIOCtx::user_data_to_tag(*data) == tag as u32)
+    }
+}
+
+/// Tags of woken tasks, and waker has to be `Send`
+#[derive(Default)]
+struct UblkReadyTags(Mutex<Vec<u16>>);
+
+impl UblkReadyTags {
+    fn push(&self, tag: u16) {
+        let mut tags = self.0.lock().unwrap_or_else(|e| e.into_inner());
+
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    fn take(&self) -> Vec<u16> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+struct UblkTaskWaker {
+    tag: u16,
+    ready: Arc<UblkReadyTags>,
+}
+
+impl Wake for UblkTaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.ready.push(self.tag);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.ready.push(self.tag);
+    }
+}
+
+/// Awaitable io_uring operation, which is submitted from `UblkIoTask`
+///
+/// SQE is queued when the future is polled at the first time, and pushed
+/// to queue's io_uring after the task yields. The output is the CQE
+/// result.
+///
+/// If the future is dropped after it is submitted and before completion,
+/// the operation is cancelled by IORING_OP_ASYNC_CANCEL, and the io
+/// command isn't completed until the operation's CQE is received.
+pub struct UblkUringOp {
+    shared: Rc<RefCell<UblkExecShared>>,
+    sqe: Option<squeue::Entry>,
+    user_data: u64,
+    done: bool,
+}
+
+impl Future for UblkUringOp {
+    type Output = i32;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let user_data = self.user_data;
+        let sqe = self.sqe.take();
+        let shared = self.shared.clone();
+        let mut s = shared.borrow_mut();
+
+        if let Some(sqe) = sqe {
+            s.sqes.push(sqe.user_data(user_data));
+            s.ops.insert(user_data, UblkOpSlot::default());
+        }
+
+        match s.ops.get_mut(&user_data) {
+            Some(slot) if slot.res.is_none() => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            _ => {
+                let res = s.ops.remove(&user_data).and_then(|slot| slot.res);
+
+                self.done = true;
+                Poll::Ready(res.unwrap_or(-libc::ECANCELED))
+            }
+        }
+    }
+}
+
+impl Drop for UblkUringOp {
+    fn drop(&mut self) {
+        if !self.done && self.sqe.is_none() {
+            self.shared.borrow_mut().cancel(self.user_data);
+        }
+    }
+}
+
+/// One ublk IO handled by async task
+///
+/// Passed to the closure of `UblkAsyncHandler`, and moved into the spawned
+/// future for submitting target io.
+pub struct UblkIoTask {
+    tag: u16,
+    q_id: u16,
+    iod: *const sys::ublksrv_io_desc,
+    buf_addr: *mut u8,
+    buf_index: Option<u16>,
+    shared: Rc<RefCell<UblkExecShared>>,
+}
+
+impl UblkIoTask {
+    /// Return io tag
+    pub fn tag(&self) -> u16 {
+        self.tag
+    }
+
+    /// Return queue id
+    pub fn q_id(&self) -> u16 {
+        self.q_id
+    }
+
+    /// Return io descriptor filled by ublk driver, which is readonly and
+    /// stable until this IO is completed
+    pub fn iod(&self) -> &sys::ublksrv_io_desc {
+        unsafe { &*self.iod }
+    }
+
+    /// Return copy of io descriptor, see `UblkIoDesc`
+    pub fn io_desc(&self) -> UblkIoDesc {
+        UblkIoDesc::from(*self.iod())
+    }
+
+    /// Return io buffer of this IO
+    pub fn io_buf_addr(&self) -> *mut u8 {
+        self.buf_addr
+    }
+
+    /// Return fixed buffer index of io buffer, see `UblkIOCtx::io_buf_index()`
+    pub fn io_buf_index(&self) -> Option<u16> {
+        self.buf_index
+    }
+
+    fn __submit(&self, op: u8, sqe: squeue::Entry) -> UblkUringOp {
+        let user_data = self.shared.borrow_mut().next_user_data(self.tag, op);
+
+        UblkUringOp {
+            shared: self.shared.clone(),
+            sqe: Some(sqe),
+            user_data,
+            done: false,
+        }
+    }
+
+    /// Submit one io_uring SQE, whose user_data is overwritten for routing
+    /// the CQE to this task
+    ///
+    /// # Safety
+    ///
+    /// Any memory referred by `sqe` has to be valid until the returned op
+    /// is completed, or until this io command is completed if the op is
+    /// dropped before completion.
+    pub unsafe fn submit(&self, sqe: squeue::Entry) -> UblkUringOp {
+        self.__submit(0, sqe)
+    }
+
+    /// Read `len` bytes at `off` of fixed file `fd` into `buf`
+    ///
+    /// # Safety
+    ///
+    /// `buf` has to be valid for writing `len` bytes, and live until the
+    /// returned op is completed, or until this io command is completed if
+    /// the op is dropped before completion. This io's buffer
+    /// (`io_buf_addr()`) is fine if `len` isn't bigger than the io size.
+    pub unsafe fn read(&self, fd: types::Fixed, buf: *mut u8, len: u32, off: u64) -> UblkUringOp {
+        let sqe = opcode::Read::new(fd, buf, len).offset(off).build();
+
+        self.__submit(opcode::Read::CODE, sqe)
+    }
+
+    /// Write `len` bytes of `buf` to fixed file `fd` at `off`
+    ///
+    /// # Safety
+    ///
+    /// `buf` has to be valid for reading `len` bytes, and its lifetime is
+    /// same with `read()`.
+    pub unsafe fn write(
+        &self,
+        fd: types::Fixed,
+        buf: *const u8,
+        len: u32,
+        off: u64,
+    ) -> UblkUringOp {
+        let sqe = opcode::Write::new(fd, buf, len).offset(off).build();
+
+        self.__submit(opcode::Write::CODE, sqe)
+    }
+
+    /// Read `len` bytes at `off` of fixed file `fd` into `buf`, which is in
+    /// fixed buffer `buf_index`
+    ///
+    /// # Safety
+    ///
+    /// Same with `read()`, and `buf` has to be in fixed buffer `buf_index`.
+    pub unsafe fn read_fixed(
+        &self,
+        fd: types::Fixed,
+        buf: *mut u8,
+        len: u32,
+        off: u64,
+        buf_index: u16,
+    ) -> UblkUringOp {
+        let sqe = opcode::ReadFixed::new(fd, buf, len, buf_index)
+            .offset(off)
+            .build();
+
+        self.__submit(opcode::ReadFixed::CODE, sqe)
+    }
+
+    /// Write `len` bytes of `buf` in fixed buffer `buf_index` to fixed file
+    /// `fd` at `off`
+    ///
+    /// # Safety
+    ///
+    /// Same with `write()`, and `buf` has to be in fixed buffer `buf_index`.
+    pub unsafe fn write_fixed(
+        &self,
+        fd: types::Fixed,
+        buf: *const u8,
+        len: u32,
+        off: u64,
+        buf_index: u16,
+    ) -> UblkUringOp {
+        let sqe = opcode::WriteFixed::new(fd, buf, len, buf_index)
+            .offset(off)
+            .build();
+
+        self.__submit(opcode::WriteFixed::CODE, sqe)
+    }
+
+    /// Flush fixed file `fd`
+    pub fn fsync(&self, fd: types::Fixed) -> UblkUringOp {
+        let sqe = opcode::Fsync::new(fd).build();
+
+        self.__submit(opcode::Fsync::CODE, sqe)
+    }
+
+    /// Allocate or deallocate space of fixed file `fd`, and `mode` is
+    /// same with fallocate(2)
+    pub fn fallocate(&self, fd: types::Fixed, off: u64, len: u64, mode: i32) -> UblkUringOp {
+        let sqe = opcode::Fallocate::new(fd, len)
+            .offset(off)
+            .mode(mode)
+            .build();
+
+        self.__submit(opcode::Fallocate::CODE, sqe)
+    }
+}
+
+/// Queue handler which handles each IO by one async task
+///
+/// `spawn` is called for each incoming io command, and the io command is
+/// completed with output of the returned future, which is often bytes
+/// handled or negative errno.
+///
+/// # Examples:
+///
+/// ```no_run
+/// use libublk::exec::{UblkAsyncHandler, UblkIoTask};
+/// use libublk::io::UblkIoOp;
+///
+/// let handler = UblkAsyncHandler::new(|io: UblkIoTask| async move {
+///     let desc = io.io_desc();
+///     let (off, bytes) = (desc.offset(), desc.len() as u32);
+///     let fd = io_uring::types::Fixed(1);
+///
+///     // io buffer is live until the io command is completed
+///     match desc.op() {
+///         UblkIoOp::Read => unsafe { io.read(fd, io.io_buf_addr(), bytes, off) }.await,
+///         UblkIoOp::Write => unsafe { io.write(fd, io.io_buf_addr(), bytes, off) }.await,
+///         UblkIoOp::Flush => io.fsync(fd).await,
+///         _ => -libc::EINVAL,
+///     }
+/// });
+/// ```
+pub struct UblkAsyncHandler<F> {
+    spawn: F,
+    shared: Rc<RefCell<UblkExecShared>>,
+    tasks: Vec<Option<UblkTaskFuture>>,
+    wakers: Vec<Waker>,
+    ready: Arc<UblkReadyTags>,
+
+    /// output of done task whose cancelled ops aren't completed yet
+    deferred: HashMap<u16, i32>,
+}
+
+impl<F, Fut> UblkAsyncHandler<F>
+where
+    F: FnMut(UblkIoTask) -> Fut,
+    Fut: Future<Output = i32> + 'static,
+{
+    pub fn new(spawn: F) -> Self {
+        UblkAsyncHandler {
+            spawn,
+            shared: Rc::new(RefCell::new(UblkExecShared::default())),
+            tasks: Vec::new(),
+            wakers: Vec::new(),
+            ready: Arc::new(UblkReadyTags::default()),
+            deferred: HashMap::new(),
+        }
+    }
+
+    fn waker(&mut self, tag: u16) -> Waker {
+        while self.wakers.len() <= tag as usize {
+            let waker = Arc::new(UblkTaskWaker {
+                tag: self.wakers.len() as u16,
+                ready: self.ready.clone(),
+            });
+            self.wakers.push(Waker::from(waker));
+        }
+        self.wakers[tag as usize].clone()
+    }
+
+    /// Push queued SQEs to queue's io_uring, which is submitted when the
+    /// queue waits for any CQE
+    fn flush(&mut self, ring: &mut IoUring<squeue::Entry>) -> Result<(), UblkError> {
+        let sqes = std::mem::take(&mut self.shared.borrow_mut().sqes);
+
+        for sqe in sqes {
+            if unsafe { ring.submission().push(&sqe) }.is_err() {
+                ring.submit().map_err(UblkError::UringSubmissionError)?;
+                unsafe { ring.submission().push(&sqe) }.map_err(UblkError::UringPushError)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Poll all woken tasks, and complete IOs whose tasks are done
+    ///
+    /// IO whose task has cancelled ops in flight is completed after all
+    /// these ops are completed.
+    fn run(&mut self, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        let cur = io.get_tag() as u16;
+        let mut batch = false;
+        let mut done = Vec::new();
+
+        loop {
+            let tags = self.ready.take();
+
+            if tags.is_empty() {
+                break;
+            }
+            for tag in tags {
+                let waker = self.waker(tag);
+                let task = match self.tasks.get_mut(tag as usize) {
+                    Some(Some(t)) => t,
+                    _ => continue,
+                };
+
+                if let Poll::Ready(res) = task.as_mut().poll(&mut Context::from_waker(&waker)) {
+                    // ops dropped with the task are cancelled here
+                    self.tasks[tag as usize] = None;
+                    self.deferred.insert(tag, res);
+                }
+            }
+        }
+
+        {
+            let s = self.shared.borrow();
+
+            self.deferred.retain(|tag, res| {
+                if s.has_cancelled(*tag) {
+                    return true;
+                }
+                done.push((*tag, *res));
+                false
+            });
+        }
+        for (tag, res) in done {
+            if tag == cur {
+                io.complete_io(res);
+            } else {
+                io.complete_tag(tag, res);
+                batch = true;
+            }
+        }
+        self.flush(io.get_ring())?;
+
+        Ok(if batch { UBLK_IO_S_COMP_BATCH } else { 0 })
+    }
+}
+
+impl<F, Fut> UblkQueueHandler for UblkAsyncHandler<F>
+where
+    F: FnMut(UblkIoTask) -> Fut,
+    Fut: Future<Output = i32> + 'static,
+{
+    fn handle_io(&mut self, ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        let tag = io.get_tag() as u16;
+        let task = UblkIoTask {
+            tag,
+            q_id: ctx.q_id,
+            iod: ctx.get_iod(tag as u32),
+            buf_addr: io.io_buf_addr(),
+            buf_index: io.io_buf_index(),
+            shared: self.shared.clone(),
+        };
+        let fut = (self.spawn)(task);
+
+        if self.tasks.len() <= tag as usize {
+            self.tasks.resize_with(tag as usize + 1, || None);
+        }
+        self.tasks[tag as usize] = Some(Box::pin(fut));
+        self.ready.push(tag);
+
+        self.run(io)
+    }
+
+    fn handle_tgt_io(&mut self, _ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        self.shared
+            .borrow_mut()
+            .complete(io.user_data(), io.result());
+
+        self.run(io)
+    }
+}
@@ -1,9 +1,15 @@
-use super::{ctrl::UblkCtrl, sys, UblkError};
+use super::{
+    ctrl::{UblkCtrl, UblkFlags, UblkParamsBuilder, UblkRecoveryMode},
+    sys, UblkError,
+};
+use bitflags::bitflags;
 use io_uring::{cqueue, opcode, squeue, types, IoUring};
 use log::{error, info, trace};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::os::unix::io::AsRawFd;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
 
 /// Return value of IO handling closure.
 ///
@@ -15,13 +21,17 @@ use std::os::unix::io::AsRawFd;
 /// then target code sends eventfd to wakeup our queue(io_uring).
 /// After any IO is completed, it is added to `UblkIOCtx.3` by
 /// `UblkIOCtx::add_to_comp_batch()` for later completion.
+///
+/// The built-in completion channel covers this case, see
+/// `UBLK_DEV_F_COMP_CHAN` and `UblkQueueCompleter`.
 pub const UBLK_IO_S_COMP_BATCH: i32 = 1;
 
 pub struct UblkIOCtx<'a, 'b, 'd>(
     &'a mut io_uring::IoUring<io_uring::squeue::Entry>,
     &'b mut UblkIO,
-    &'d UblkCQE<'d>,
+    &'d UblkCQE,
     Option<Vec<(u16, i32)>>,
+    Option<&'a mut UblkTgtRing>,
 );
 
 /// Check if this userdata is from target IO
@@ -36,6 +46,40 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
         self.0
     }
 
+    /// Queue target io `sqe` to the IOPOLL target ring if `UBLK_RING_F_IOPOLL`
+    /// is set, otherwise to the queue ring
+    ///
+    /// # Arguments:
+    ///
+    /// * `sqe`: target io, whose user_data has to be built with `is_target_io`
+    ///
+    /// The queue submits the SQE and polls the target ring, and the CQE is
+    /// handled same with target io from the queue ring. Only read/write on
+    /// files opened with O_DIRECT can be issued to IOPOLL ring, so other
+    /// target io, such as fsync, has to be pushed to `get_ring()`.
+    ///
+    /// # Safety
+    ///
+    /// Any memory referred by `sqe` has to be valid until its CQE is handled.
+    pub unsafe fn push_tgt_sqe(&mut self, sqe: &squeue::Entry) -> Result<(), UblkError> {
+        let ring = match self.4.as_mut() {
+            Some(t) => &mut t.ring,
+            None => &mut *self.0,
+        };
+
+        //flush SQ and retry if it is full
+        if ring.submission().push(sqe).is_err() {
+            ring.submit().map_err(UblkError::UringSubmissionError)?;
+            ring.submission()
+                .push(sqe)
+                .map_err(UblkError::UringPushError)?;
+        }
+        if let Some(t) = self.4.as_mut() {
+            t.inflight += 1;
+        }
+        Ok(())
+    }
+
     #[inline(always)]
     pub fn result(&self) -> i32 {
         self.2.result()
@@ -65,6 +109,91 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
         self.1.get_buf_addr()
     }
 
+    /// Return copy of io descriptor of this io command
+    ///
+    /// Also available for CQE of target io, whose tag is same with the
+    /// io command. None is returned for extra ios(`UblkTgt::extra_ios`),
+    /// which don't have io descriptor.
+    #[inline(always)]
+    pub fn io_desc(&self) -> Option<UblkIoDesc> {
+        if self.1.iod.is_null() {
+            return None;
+        }
+        Some(UblkIoDesc(unsafe { *self.1.iod }))
+    }
+
+    /// Return io buffer of this io command, which covers
+    /// `UblkIoDesc::data_len()` bytes of the request
+    ///
+    /// Request which can't be held in io buffer is failed with -EIO by
+    /// the queue before it is passed to target, so the slice is always
+    /// in bound. Empty slice is returned if there isn't queue's own io
+    /// buffer, such as UBLK_F_USER_COPY is enabled, or the buffer is set
+    /// by `set_io_buf_addr()`, which is owned by target.
+    #[inline(always)]
+    pub fn io_buf(&self) -> &[u8] {
+        let (addr, len) = self.io_buf_range();
+
+        if addr.is_null() {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(addr, len) }
+    }
+
+    /// Return mutable io buffer of this io command, see `io_buf()`
+    #[inline(always)]
+    pub fn io_buf_mut(&mut self) -> &mut [u8] {
+        let (addr, len) = self.io_buf_range();
+
+        if addr.is_null() {
+            return &mut [];
+        }
+        unsafe { std::slice::from_raw_parts_mut(addr, len) }
+    }
+
+    #[inline(always)]
+    fn io_buf_range(&self) -> (*mut u8, usize) {
+        let io = &self.1;
+
+        // only queue's own buffer is known to hold this request
+        match self.io_desc() {
+            Some(desc) if io.data_addr == io.buf_addr => (io.buf_addr, desc.data_len()),
+            _ => (std::ptr::null_mut(), 0),
+        }
+    }
+
+    /// Return io_uring fixed buffer index of `io_buf_addr()`
+    ///
+    /// None if `UBLK_DEV_F_FIXED_BUF` isn't set, or the buffer is set by
+    /// `set_io_buf_addr()`.
+    #[inline(always)]
+    pub fn io_buf_index(&self) -> Option<u16> {
+        self.1.get_buf_index()
+    }
+
+    /// Set io buffer of this io command
+    ///
+    /// # Arguments:
+    ///
+    /// * `addr`: buffer which can hold bytes of this request
+    ///
+    /// Used for write request when `UBLK_IO_F_NEED_GET_DATA` is set in
+    /// `flags()`, or for read request before it is completed, and the
+    /// queue's own buffer is used again after the io command is completed.
+    ///
+    /// Not allowed if UBLK_F_USER_COPY is enabled.
+    ///
+    /// # Safety
+    ///
+    /// `addr` has to be valid for reading and writing `data_len()` bytes
+    /// of this request's `UblkIoDesc`, and live until the io command is
+    /// completed, since ublk driver copies data from or to it. The buffer
+    /// can't be accessed by others meantime.
+    #[inline(always)]
+    pub unsafe fn set_io_buf_addr(&mut self, addr: *mut u8) {
+        self.1.data_addr = addr;
+    }
+
     #[inline(always)]
     pub fn complete_io(&mut self, res: i32) {
         self.1.complete(res);
@@ -87,6 +216,13 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
         }
     }
 
+    /// Complete IO of other tag, no matter if UBLK_DEV_F_COMP_BATCH is set,
+    /// and `UBLK_IO_S_COMP_BATCH` has to be returned from the io handler
+    #[inline(always)]
+    pub(crate) fn complete_tag(&mut self, tag: u16, res: i32) {
+        self.3.get_or_insert_with(Vec::new).push((tag, res));
+    }
+
     /// Build offset for read from or write to per-io-cmd buffer
     ///
     /// # Arguments:
@@ -112,24 +248,98 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
                 | offset as u64)
     }
 
+    /// Build SQE for copying data of io command into `buf`
+    ///
+    /// # Arguments:
+    ///
+    /// * `q_id`: queue id
+    /// * `tag`: io command tag
+    /// * `buf`: destination buffer, which has to be live until the SQE
+    ///     is completed
+    /// * `len`: bytes to copy
+    /// * `offset`: offset to this io-cmd buffer
+    ///
+    /// The SQE reads from /dev/ublkcN(fixed file 0), and is often used for
+    /// retrieving data of write request. Caller sets user_data of the SQE
+    /// and pushes it to queue's io_uring.
+    ///
+    /// Available if UBLK_F_USER_COPY is enabled.
+    #[inline(always)]
+    pub fn user_copy_read_sqe(
+        q_id: u16,
+        tag: u16,
+        buf: *mut u8,
+        len: u32,
+        offset: u32,
+    ) -> squeue::Entry {
+        opcode::Read::new(types::Fixed(0), buf, len)
+            .offset(Self::ublk_user_copy_pos(q_id, tag, offset))
+            .build()
+    }
+
+    /// Build SQE for copying data in `buf` to io command
+    ///
+    /// # Arguments:
+    ///
+    /// * `q_id`: queue id
+    /// * `tag`: io command tag
+    /// * `buf`: source buffer, which has to be live until the SQE is
+    ///     completed
+    /// * `len`: bytes to copy
+    /// * `offset`: offset to this io-cmd buffer
+    ///
+    /// The SQE writes to /dev/ublkcN(fixed file 0), and is often used for
+    /// filling data of read request.
+    ///
+    /// Available if UBLK_F_USER_COPY is enabled.
+    #[inline(always)]
+    pub fn user_copy_write_sqe(
+        q_id: u16,
+        tag: u16,
+        buf: *const u8,
+        len: u32,
+        offset: u32,
+    ) -> squeue::Entry {
+        opcode::Write::new(types::Fixed(0), buf, len)
+            .offset(Self::ublk_user_copy_pos(q_id, tag, offset))
+            .build()
+    }
+
     /// Build userdata for submitting io via io_uring
     ///
     /// # Arguments:
     ///
     /// * `tag`: io tag, length is 16bit
     /// * `op`: io operation code, length is 8bit
-    /// * `tgt_data`: target specific data, at most 39bit (64 - 16 - 8 - 1)
+    /// * `tgt_data`: target specific data, length is 32bit
     /// * `is_target_io`: if this userdata is for handling target io, false if
     ///         if it is only for ublk io command
     ///
     /// The built userdata is passed to io_uring for parsing io result
     ///
+    /// `op` is truncated to 8bit, see `try_build_user_data()` for
+    /// validating it.
     #[inline(always)]
-    #[allow(arithmetic_overflow)]
     pub fn build_user_data(tag: u16, op: u32, tgt_data: u32, is_target_io: bool) -> u64 {
-        assert!((op >> 8) == 0 && (tgt_data >> 16) == 0);
+        tag as u64
+            | (((op & 0xff) as u64) << 16)
+            | ((tgt_data as u64) << 24)
+            | ((is_target_io as u64) << 63)
+    }
 
-        tag as u64 | (op << 16) as u64 | (tgt_data << 24) as u64 | ((is_target_io as u64) << 63)
+    /// Same with `build_user_data()`, but -EINVAL is returned if `op`
+    /// can't be stored in 8bit
+    #[inline(always)]
+    pub fn try_build_user_data(
+        tag: u16,
+        op: u32,
+        tgt_data: u32,
+        is_target_io: bool,
+    ) -> Result<u64, UblkError> {
+        if (op >> 8) != 0 {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        Ok(Self::build_user_data(tag, op, tgt_data, is_target_io))
     }
 
     /// Extract tag from userdata
@@ -143,38 +353,189 @@ impl<'a, 'b, 'd> UblkIOCtx<'a, 'b, 'd> {
     pub fn user_data_to_op(user_data: u64) -> u32 {
         ((user_data >> 16) & 0xff) as u32
     }
+
+    /// Extract target specific data from userdata
+    #[inline(always)]
+    pub fn user_data_to_tgt_data(user_data: u64) -> u32 {
+        ((user_data >> 24) & 0xffff_ffff) as u32
+    }
 }
 
 pub const UBLK_IO_F_FIRST: u32 = 1u32 << 16;
 pub const UBLK_IO_F_LAST: u32 = 1u32 << 17;
 
-struct UblkCQE<'d>(&'d cqueue::Entry, u32);
+/// Set in `UblkIOCtx::flags()` when write request is delivered without data
+/// since UBLK_F_NEED_GET_DATA is enabled, and io handler can choose buffer
+/// for this request by `UblkIOCtx::set_io_buf_addr()`, then the data is
+/// copied to the buffer and the io command is delivered again
+pub const UBLK_IO_F_NEED_GET_DATA: u32 = 1u32 << 18;
+
+/// Completion of io command or target io
+///
+/// Built from io_uring cqe, or from completion of io command delivered
+/// by queue transport directly.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct UblkCQE {
+    user_data: u64,
+    result: i32,
+    flags: u32,
+}
+
+impl UblkCQE {
+    #[inline(always)]
+    pub(crate) fn new(user_data: u64, result: i32) -> Self {
+        UblkCQE {
+            user_data,
+            result,
+            flags: 0,
+        }
+    }
 
-impl<'a> UblkCQE<'a> {
     #[inline(always)]
     fn result(&self) -> i32 {
-        self.0.result()
+        self.result
     }
     #[inline(always)]
     fn user_data(&self) -> u64 {
-        self.0.user_data()
+        self.user_data
     }
 
     #[inline(always)]
     fn get_tag(&self) -> u32 {
-        UblkIOCtx::user_data_to_tag(self.0.user_data())
+        UblkIOCtx::user_data_to_tag(self.user_data)
     }
 
     #[inline(always)]
     fn is_tgt_io(&self) -> bool {
-        is_target_io(self.0.user_data())
+        is_target_io(self.user_data)
     }
     #[inline(always)]
     fn flags(&self) -> u32 {
-        self.1
+        self.flags
+    }
+}
+
+/// Transport for delivering io command to ublk driver
+///
+/// Io command is sent to /dev/ublkcN via io_uring, or handled by the
+/// in-process mock driver if feature `mock-driver` is enabled.
+pub(crate) trait UblkQueueTransport {
+    /// Queue one io command, which is completed with `user_data`
+    fn queue_io_cmd(
+        &mut self,
+        ring: &mut IoUring<squeue::Entry>,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_io_cmd,
+        user_data: u64,
+    ) -> Result<(), UblkError>;
+
+    /// Called before the queue waits for any cqe
+    fn prep_wait(&mut self, _ring: &mut IoUring<squeue::Entry>) -> Result<(), UblkError> {
+        Ok(())
+    }
+
+    /// Return true if the cqe is for transport itself, and it is consumed
+    fn handle_cqe(&mut self, _user_data: u64) -> bool {
+        false
+    }
+
+    /// Collect completions of io command which aren't delivered by io_uring
+    fn reap(&mut self, _cqes: &mut Vec<UblkCQE>) {}
+}
+
+/// Send io command to /dev/ublkcN(fixed file 0) via io_uring
+struct UblkQueueUring;
+
+impl UblkQueueTransport for UblkQueueUring {
+    #[inline(always)]
+    fn queue_io_cmd(
+        &mut self,
+        ring: &mut IoUring<squeue::Entry>,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_io_cmd,
+        user_data: u64,
+    ) -> Result<(), UblkError> {
+        let io_cmd = IOCmd { cmd: *cmd };
+        let sqe = opcode::UringCmd16::new(types::Fixed(0), cmd_op)
+            .cmd(unsafe { io_cmd.buf })
+            .build()
+            .user_data(user_data);
+
+        //flush SQ and retry if it is full
+        if unsafe { ring.submission().push(&sqe) }.is_err() {
+            ring.submit().map_err(UblkError::UringSubmissionError)?;
+            unsafe {
+                ring.submission()
+                    .push(&sqe)
+                    .map_err(UblkError::UringPushError)?;
+            }
+        }
+        Ok(())
     }
 }
 
+/// Create transport for sending io command of queue `q_id`
+fn ublk_queue_transport(
+    dev: &UblkDev,
+    q_id: u16,
+) -> Result<Box<dyn UblkQueueTransport>, UblkError> {
+    #[cfg(feature = "mock-driver")]
+    if super::mock::enabled() {
+        return Ok(Box::new(super::mock::MockQueue::new(
+            dev.dev_info.dev_id,
+            q_id,
+        )?));
+    }
+    let _ = (dev, q_id);
+    Ok(Box::new(UblkQueueUring))
+}
+
+// Queue ring flags(`UBLK_RING_F_*`) for `UblkTgt::ring_flags`, whose
+// values are same with io_uring's `IORING_SETUP_*`
+
+/// Setup one extra IOPOLL ring for each queue, and target io queued by
+/// `UblkIOCtx::push_tgt_sqe()` is submitted to it. The queue ring itself
+/// isn't IOPOLL, since ublk io commands can't be polled.
+pub const UBLK_RING_F_IOPOLL: u64 = 1u64 << 0;
+
+/// Submit SQEs from one kernel thread, which is idle after
+/// `UblkRingCfg::sq_thread_idle` milliseconds
+pub const UBLK_RING_F_SQPOLL: u64 = 1u64 << 1;
+
+/// Pin SQPOLL thread to `UblkRingCfg::sq_thread_cpus[q_id]`
+pub const UBLK_RING_F_SQ_AFF: u64 = 1u64 << 2;
+
+/// Share async backend of `UblkRingCfg::attach_wq_fd` with all queue rings
+pub const UBLK_RING_F_ATTACH_WQ: u64 = 1u64 << 5;
+
+/// Only the queue thread submits to queue ring
+pub const UBLK_RING_F_SINGLE_ISSUER: u64 = 1u64 << 12;
+
+/// Defer task work until the queue thread waits for events, which requires
+/// `UBLK_RING_F_SINGLE_ISSUER`
+pub const UBLK_RING_F_DEFER_TASKRUN: u64 = 1u64 << 13;
+pub const UBLK_RING_F_ALL: u64 = UBLK_RING_F_IOPOLL
+    | UBLK_RING_F_SQPOLL
+    | UBLK_RING_F_SQ_AFF
+    | UBLK_RING_F_ATTACH_WQ
+    | UBLK_RING_F_SINGLE_ISSUER
+    | UBLK_RING_F_DEFER_TASKRUN;
+
+/// Parameters of queue ring flags, see `UBLK_RING_F_*`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UblkRingCfg {
+    /// SQPOLL thread idle time in milliseconds, 0 means kernel default
+    pub sq_thread_idle: u32,
+
+    /// CPU of each queue's SQPOLL thread, indexed by queue id
+    pub sq_thread_cpus: Vec<u32>,
+
+    /// io_uring fd for `UBLK_RING_F_ATTACH_WQ`, which isn't exported since
+    /// it is only valid in current process
+    #[serde(skip)]
+    pub attach_wq_fd: Option<i32>,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UblkTgt {
     /// target type
@@ -183,9 +544,13 @@ pub struct UblkTgt {
     /// target device size, will be the actual size of /dev/ublkbN
     pub dev_size: u64,
 
-    /// target specific io_ring flags, default is 0
+    /// target specific io_ring flags(`UBLK_RING_F_*`), default is 0
     pub ring_flags: u64,
 
+    /// parameters of `ring_flags`
+    #[serde(default)]
+    pub ring_cfg: UblkRingCfg,
+
     /// uring SQ depth, default is queue depth
     pub sq_depth: u16,
 
@@ -204,8 +569,482 @@ pub struct UblkTgt {
     pub params: sys::ublk_params,
 }
 
+impl UblkTgt {
+    /// Build queue ring builder from `ring_flags` and `ring_cfg`
+    ///
+    /// # Arguments:
+    ///
+    /// * `q_id`: queue id
+    ///
+    /// Flags are validated here, and kernel support isn't checked, see
+    /// `UblkTgt::probe_ring()`. `UBLK_RING_F_IOPOLL` is only applied to
+    /// the target ring, see `tgt_ring_builder()`.
+    fn ring_builder(
+        &self,
+        q_id: u16,
+    ) -> Result<io_uring::Builder<squeue::Entry, cqueue::Entry>, UblkError> {
+        let flags = self.ring_flags;
+        let cfg = &self.ring_cfg;
+        let einval = || UblkError::OtherError(-libc::EINVAL);
+        let mut builder = IoUring::<squeue::Entry, cqueue::Entry>::builder();
+
+        if (flags & !UBLK_RING_F_ALL) != 0 {
+            return Err(einval());
+        }
+        if (flags & UBLK_RING_F_SQPOLL) != 0 {
+            // task work is run by SQPOLL thread
+            if (flags & UBLK_RING_F_DEFER_TASKRUN) != 0 {
+                return Err(einval());
+            }
+            builder.setup_sqpoll(cfg.sq_thread_idle);
+        } else {
+            if (flags & UBLK_RING_F_SQ_AFF) != 0 {
+                return Err(einval());
+            }
+            builder.setup_coop_taskrun();
+        }
+        if (flags & UBLK_RING_F_SQ_AFF) != 0 {
+            let cpu = cfg.sq_thread_cpus.get(q_id as usize).ok_or_else(einval)?;
+            builder.setup_sqpoll_cpu(*cpu);
+        }
+        if (flags & UBLK_RING_F_ATTACH_WQ) != 0 {
+            let fd = cfg.attach_wq_fd.ok_or_else(einval)?;
+            builder.setup_attach_wq(fd);
+        }
+        if (flags & UBLK_RING_F_SINGLE_ISSUER) != 0 {
+            builder.setup_single_issuer();
+        }
+        if (flags & UBLK_RING_F_DEFER_TASKRUN) != 0 {
+            if (flags & UBLK_RING_F_SINGLE_ISSUER) == 0 {
+                return Err(einval());
+            }
+            builder.setup_defer_taskrun();
+        }
+
+        Ok(builder)
+    }
+
+    /// Build IOPOLL target ring builder, None if `UBLK_RING_F_IOPOLL`
+    /// isn't set
+    ///
+    /// Only ATTACH_WQ and SINGLE_ISSUER are applied to target ring too,
+    /// and the ring is always polled by the queue thread.
+    fn tgt_ring_builder(&self) -> Option<io_uring::Builder<squeue::Entry, cqueue::Entry>> {
+        let flags = self.ring_flags;
+
+        if (flags & UBLK_RING_F_IOPOLL) == 0 {
+            return None;
+        }
+
+        let mut builder = IoUring::<squeue::Entry, cqueue::Entry>::builder();
+        builder.setup_iopoll();
+        if (flags & UBLK_RING_F_ATTACH_WQ) != 0 {
+            if let Some(fd) = self.ring_cfg.attach_wq_fd {
+                builder.setup_attach_wq(fd);
+            }
+        }
+        if (flags & UBLK_RING_F_SINGLE_ISSUER) != 0 {
+            builder.setup_single_issuer();
+        }
+        Some(builder)
+    }
+
+    /// Check if queue ring can be setup on the running kernel
+    ///
+    /// # Arguments:
+    ///
+    /// * `nr_queues`: how many queues of this device
+    ///
+    /// Build one small ring with `ring_flags` for each queue, and return
+    /// -EOPNOTSUPP if the kernel doesn't support the flags or the ring
+    /// doesn't support IORING_OP_URING_CMD. No ublk io command is issued,
+    /// so driver support of the flags isn't checked. The IOPOLL target
+    /// ring is probed for IORING_OP_READ and IORING_OP_WRITE too if
+    /// `UBLK_RING_F_IOPOLL` is set. Called by `UblkDev::new()` after
+    /// target is initialized.
+    pub fn probe_ring(&self, nr_queues: u16) -> Result<(), UblkError> {
+        if self.ring_flags == 0 {
+            return Ok(());
+        }
+
+        let probe_ops = |builder: io_uring::Builder<squeue::Entry, cqueue::Entry>,
+                         ops: &[u8]|
+         -> Result<(), UblkError> {
+            let ring = builder.build(2).map_err(|e| match e.raw_os_error() {
+                Some(libc::EINVAL) => UblkError::OtherError(-libc::EOPNOTSUPP),
+                _ => UblkError::OtherIOError(e),
+            })?;
+            let mut probe = io_uring::Probe::new();
+
+            ring.submitter()
+                .register_probe(&mut probe)
+                .map_err(UblkError::OtherIOError)?;
+            if ops.iter().any(|op| !probe.is_supported(*op)) {
+                return Err(UblkError::OtherError(-libc::EOPNOTSUPP));
+            }
+            Ok(())
+        };
+
+        for q_id in 0..nr_queues {
+            probe_ops(self.ring_builder(q_id)?, &[opcode::UringCmd16::CODE])?;
+        }
+        if let Some(builder) = self.tgt_ring_builder() {
+            probe_ops(builder, &[opcode::Read::CODE, opcode::Write::CODE])?;
+        }
+        Ok(())
+    }
+}
+
+/// Per-queue IO handler of ublk target
+///
+/// Created by `UblkTarget::queue_handler()` in queue thread context, so
+/// it can hold any per-queue state, such as buffers or backing file
+/// handle, and needn't to be `Send`.
+///
+/// Any `FnMut(&UblkQueueCtx, &mut UblkIOCtx)` closure or function can
+/// be used as queue handler, and it is called for both io command and
+/// target io.
+pub trait UblkQueueHandler {
+    /// Handle io command coming from ublk driver
+    ///
+    /// # Arguments:
+    ///
+    /// * `ctx`: this queue's context info for retrieving iod and so on
+    /// * `io`: IO context, which represents the io command from ublk driver
+    ///
+    /// After the io command is handled, `UblkIOCtx::complete_io()` has to
+    /// be called. If the io command is handled asynchronously by submitting
+    /// target io via io_uring, `handle_tgt_io()` is called when target io is
+    /// completed.
+    fn handle_io(&mut self, ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError>;
+
+    /// Handle target io completion
+    ///
+    /// # Arguments:
+    ///
+    /// * `ctx`: this queue's context info for retrieving iod and so on
+    /// * `io`: IO context, `UblkIOCtx::user_data()` and `UblkIOCtx::result()`
+    ///   represents the completed target io
+    ///
+    /// Called when target io submitted from `handle_io()` is completed
+    /// by io_uring, and only targets which submit io via io_uring need
+    /// to implement it.
+    fn handle_tgt_io(
+        &mut self,
+        _ctx: &UblkQueueCtx,
+        _io: &mut UblkIOCtx,
+    ) -> Result<i32, UblkError> {
+        Err(UblkError::OtherError(-libc::EOPNOTSUPP))
+    }
+
+    /// Prepare buffer of write request when UBLK_F_NEED_GET_DATA is enabled
+    ///
+    /// # Arguments:
+    ///
+    /// * `ctx`: this queue's context info for retrieving iod and so on
+    /// * `io`: IO context, `UBLK_IO_F_NEED_GET_DATA` is set in its flags
+    ///
+    /// Called before data of write request is copied from ublk driver, and
+    /// target may choose buffer by `UblkIOCtx::set_io_buf_addr()`, such as
+    /// one from buffer pool, otherwise the queue's buffer is used. The io
+    /// command can't be completed here, and `handle_io()` is called after
+    /// the data is copied.
+    fn prep_get_data(
+        &mut self,
+        _ctx: &UblkQueueCtx,
+        _io: &mut UblkIOCtx,
+    ) -> Result<i32, UblkError> {
+        Ok(0)
+    }
+}
+
+impl<F> UblkQueueHandler for F
+where
+    F: FnMut(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>,
+{
+    fn handle_io(&mut self, ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        self(ctx, io)
+    }
+
+    fn handle_tgt_io(&mut self, ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        self(ctx, io)
+    }
+}
+
+/// ublk target
+///
+/// Implemented by specific target, such as null, loop, ramdisk, ..., and
+/// driven by the high level APIs, such as `UblkCtrlBuilder::create_device()`
+/// and `ublk_tgt_worker()`.
+///
+/// The target object is shared by all queue threads after it is
+/// initialized, so per-device state(backing file, base address, ...) can
+/// be stored in the target, and per-queue state is stored in the queue
+/// handler created by `queue_handler()`.
+pub trait UblkTarget: Send + Sync {
+    /// Target specific data, which is stored in the device's exported
+    /// json file as `target_data`, and loaded by `UblkCtrl::export()`
+    /// or `UblkCtrl::load_export()` as `UblkDevExport<Self::Data>`
+    ///
+    /// Use `serde_json::Value` for untyped data, or `()` if there is
+    /// nothing to export.
+    type Data: Serialize + serde::de::DeserializeOwned;
+
+    /// Target specific initialization
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device, target sets device parameters, fds, ... here
+    ///
+    /// # Return: target specific data, see `UblkTarget::Data`
+    fn init(&mut self, dev: &mut UblkDev) -> Result<Self::Data, UblkError>;
+
+    /// Create IO handler for the specified queue
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device
+    /// * `q_id`: queue id
+    ///
+    /// Called in the queue thread context before handling any IO.
+    fn queue_handler(
+        &self,
+        dev: &UblkDev,
+        q_id: u16,
+    ) -> Result<Box<dyn UblkQueueHandler>, UblkError>;
+
+    /// Target specific recovery
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device, `dev.tgt` has been restored from the exported
+    ///     json file, except for fds
+    /// * `target_data`: target specific data returned from `init()` of
+    ///     the previous daemon, so that target can reopen backing files
+    ///
+    /// # Return: target specific data, same with `init()`
+    ///
+    /// Called instead of `init()` when the device is created in recover
+    /// mode, default is to call `init()`.
+    fn recover(
+        &mut self,
+        dev: &mut UblkDev,
+        _target_data: Self::Data,
+    ) -> Result<Self::Data, UblkError> {
+        self.init(dev)
+    }
+
+    /// Reconcile target state with IOs in flight when the previous
+    /// daemon died
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device
+    /// * `inflight`: IOs in flight, empty if `UBLK_DEV_F_TRACK_INFLIGHT`
+    ///     isn't set
+    ///
+    /// Called after `recover()` and before any queue starts to fetch IO.
+    /// IOs in flight have been failed to upper layer in
+    /// `UblkRecoveryMode::FailInflight`, and will be issued again in
+    /// `UblkRecoveryMode::Reissue`, see `UblkDev::recovery_mode()`.
+    ///
+    /// Targets with non-idempotent writes, such as journaling targets,
+    /// may rollback or replay the affected ranges here, default is nop.
+    fn reconcile(&mut self, _dev: &UblkDev, _inflight: &[UblkInflightIo]) -> Result<(), UblkError> {
+        Ok(())
+    }
+
+    /// Target specific cleanup
+    ///
+    /// Called after all queues are done, default is nop.
+    fn deinit(&self, _dev: &UblkDev) {}
+}
+
+/// Object safe part of `UblkTarget`, so that targets with different
+/// `UblkTarget::Data` can be held by `UblkDevHandle`
+pub(crate) trait UblkTargetDeinit: Send + Sync {
+    fn deinit(&self, dev: &UblkDev);
+}
+
+impl<T: UblkTarget> UblkTargetDeinit for T {
+    fn deinit(&self, dev: &UblkDev) {
+        UblkTarget::deinit(self, dev)
+    }
+}
+
 pub const UBLK_DEV_F_COMP_BATCH: u32 = 1u32 << 0;
-const UBLK_DEV_F_ALL: u32 = UBLK_DEV_F_COMP_BATCH;
+
+/// Record IOs being handled in per-queue file of run dir, so that the
+/// recovering daemon can retrieve IOs in flight when the previous daemon
+/// died, see `UblkTarget::reconcile()`
+pub const UBLK_DEV_F_TRACK_INFLIGHT: u32 = 1u32 << 1;
+
+/// Create one completion channel for each queue, so that IOs can be
+/// completed from other threads via `UblkQueueCompleter`, see
+/// `UblkDev::completer()`
+pub const UBLK_DEV_F_COMP_CHAN: u32 = 1u32 << 2;
+
+/// Don't allocate io buffer in queue, and target provides buffer for each
+/// request by `UblkIOCtx::set_io_buf_addr()`, which is only allowed with
+/// UBLK_F_NEED_GET_DATA, see `UblkQueueHandler::prep_get_data()`
+pub const UBLK_DEV_F_LAZY_IO_BUF: u32 = 1u32 << 3;
+
+/// Register io buffers of each queue as io_uring fixed buffers, and buffer
+/// index is returned from `UblkIOCtx::io_buf_index()`, so that target can
+/// handle IO by `ReadFixed` and `WriteFixed`
+pub const UBLK_DEV_F_FIXED_BUF: u32 = 1u32 << 4;
+pub(crate) const UBLK_DEV_F_ALL: u32 = UBLK_DEV_F_COMP_BATCH
+    | UBLK_DEV_F_TRACK_INFLIGHT
+    | UBLK_DEV_F_COMP_CHAN
+    | UBLK_DEV_F_LAZY_IO_BUF
+    | UBLK_DEV_F_FIXED_BUF;
+
+/// IO which was in flight when the previous daemon died
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UblkInflightIo {
+    pub q_id: u16,
+    pub tag: u16,
+
+    /// `ublksrv_io_desc.op_flags`, op is `op_flags & 0xff`
+    pub op_flags: u32,
+    pub nr_sectors: u32,
+    pub start_sector: u64,
+}
+
+impl UblkInflightIo {
+    /// Return io operation code, one of UBLK_IO_OP_*
+    pub fn op(&self) -> u32 {
+        self.op_flags & 0xff
+    }
+}
+
+/// Slot in per-queue inflight file, `inflight` is written at last
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct UblkInflightSlot {
+    op_flags: u32,
+    nr_sectors: u32,
+    start_sector: u64,
+    inflight: u32,
+    _pad: u32,
+}
+
+pub(crate) fn ublk_inflight_path(run_dir: &str, dev_id: u32, q_id: u16) -> String {
+    format!("{}/{:04}.q{}.inflight", run_dir, dev_id, q_id)
+}
+
+/// Load IOs in flight from per-queue inflight file
+///
+/// Empty vector is returned if the file doesn't exist.
+pub(crate) fn ublk_load_inflight(path: &str, q_id: u16) -> Vec<UblkInflightIo> {
+    let buf = match fs::read(path) {
+        Ok(b) => b,
+        Err(_) => return Vec::new(),
+    };
+    let slot_sz = core::mem::size_of::<UblkInflightSlot>();
+
+    buf.chunks_exact(slot_sz)
+        .enumerate()
+        .filter_map(|(tag, c)| {
+            let slot = unsafe { std::ptr::read_unaligned(c.as_ptr() as *const UblkInflightSlot) };
+
+            if slot.inflight != 0 {
+                Some(UblkInflightIo {
+                    q_id,
+                    tag: tag as u16,
+                    op_flags: slot.op_flags,
+                    nr_sectors: slot.nr_sectors,
+                    start_sector: slot.start_sector,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Per-queue inflight IO record
+///
+/// Stored in one file backed shared mapping, so it is still available
+/// after the daemon is killed. The file is removed when the queue exits
+/// normally.
+struct UblkInflightMap {
+    path: String,
+    slots: *mut UblkInflightSlot,
+    len: usize,
+}
+
+impl UblkInflightMap {
+    fn new(path: String, depth: u32) -> Result<UblkInflightMap, UblkError> {
+        let len = depth as usize * core::mem::size_of::<UblkInflightSlot>();
+
+        if let Some(dir) = std::path::Path::new(&path).parent() {
+            fs::create_dir_all(dir).map_err(UblkError::OtherIOError)?;
+        }
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(UblkError::OtherIOError)?;
+
+        file.set_len(len as u64).map_err(UblkError::OtherIOError)?;
+        let slots = unsafe {
+            libc::mmap(
+                std::ptr::null_mut::<libc::c_void>(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if slots == libc::MAP_FAILED {
+            return Err(UblkError::MmapError("inflight mmap failed".to_string()));
+        }
+
+        Ok(UblkInflightMap {
+            path,
+            slots: slots as *mut UblkInflightSlot,
+            len,
+        })
+    }
+
+    #[inline(always)]
+    fn set(&self, tag: u32, iod: &sys::ublksrv_io_desc) {
+        let slot = UblkInflightSlot {
+            op_flags: iod.op_flags,
+            nr_sectors: iod.nr_sectors,
+            start_sector: iod.start_sector,
+            inflight: 1,
+            _pad: 0,
+        };
+
+        unsafe { std::ptr::write_volatile(self.slots.add(tag as usize), slot) };
+    }
+
+    #[inline(always)]
+    fn clear(&self, tag: u32) {
+        unsafe {
+            std::ptr::write_volatile(
+                std::ptr::addr_of_mut!((*self.slots.add(tag as usize)).inflight),
+                0,
+            )
+        };
+    }
+}
+
+impl Drop for UblkInflightMap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.slots as *mut libc::c_void, self.len);
+        }
+        if let Err(r) = fs::remove_file(&self.path) {
+            error!("remove {} failed {}", self.path, r);
+        }
+    }
+}
 
 pub struct UblkDev {
     pub dev_info: sys::ublksrv_ctrl_dev_info,
@@ -213,8 +1052,18 @@ pub struct UblkDev {
     /// reserved for supporting new features
     pub flags: u32,
 
-    //fds[0] points to /dev/ublkcN
-    cdev_file: fs::File,
+    //fds[0] points to /dev/ublkcN, which is opened after target is
+    //initialized
+    cdev_file: Option<fs::File>,
+
+    //for storing per-queue inflight file
+    run_dir: String,
+
+    //indexed by queue id, empty if UBLK_DEV_F_COMP_CHAN isn't set
+    comp_chans: Vec<Arc<UblkCompChan>>,
+
+    //io command is ioctl encoded, same with control command
+    ioctl_encode: bool,
 
     pub tgt: UblkTgt,
 }
@@ -234,6 +1083,9 @@ impl UblkDev {
     /// ublk device is abstraction for target, and prepare for setting
     /// up target. Any target private data can be defined in the data
     /// structure which implements UblkTgtImpl.
+    ///
+    /// /dev/ublkcN is opened after `ops` returns, and `tgt.fds[0]` is
+    /// reserved for it.
     pub fn new<F>(
         tgt_name: String,
         ops: F,
@@ -242,6 +1094,22 @@ impl UblkDev {
     ) -> Result<UblkDev, UblkError>
     where
         F: FnOnce(&mut UblkDev) -> Result<serde_json::Value, UblkError>,
+    {
+        Self::__new(tgt_name, ops, ctrl, flags, |_| Ok(0))
+    }
+
+    /// Same with `new()`, and `before_open` is called after target is
+    /// initialized and before /dev/ublkcN is opened
+    pub(crate) fn __new<F, G>(
+        tgt_name: String,
+        ops: F,
+        ctrl: &mut UblkCtrl,
+        flags: u32,
+        before_open: G,
+    ) -> Result<UblkDev, UblkError>
+    where
+        F: FnOnce(&mut UblkDev) -> Result<serde_json::Value, UblkError>,
+        G: FnOnce(&mut UblkCtrl) -> Result<i32, UblkError>,
     {
         let info = ctrl.dev_info;
         let mut tgt = UblkTgt {
@@ -256,102 +1124,507 @@ impl UblkDev {
         if (flags & !UBLK_DEV_F_ALL) != 0 {
             return Err(UblkError::OtherError(-libc::EINVAL));
         }
+        if (flags & UBLK_DEV_F_LAZY_IO_BUF) != 0
+            && (info.flags & sys::UBLK_F_NEED_GET_DATA as u64) == 0
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        // fixed buffer can't be registered if queue doesn't allocate buffer
+        if (flags & UBLK_DEV_F_FIXED_BUF) != 0
+            && ((flags & UBLK_DEV_F_LAZY_IO_BUF) != 0
+                || (info.flags & sys::UBLK_F_USER_COPY as u64) != 0)
+        {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
 
-        let cdev_path = format!("{}{}", super::CDEV_PATH, info.dev_id);
-        let cdev_file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(cdev_path)
-            .map_err(UblkError::OtherIOError)?;
-
-        tgt.fds[0] = cdev_file.as_raw_fd();
+        tgt.fds[0] = -1;
         tgt.nr_fds = 1;
 
+        let comp_chans = if (flags & UBLK_DEV_F_COMP_CHAN) != 0 {
+            (0..info.nr_hw_queues)
+                .map(|_| UblkCompChan::new().map(Arc::new))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            Vec::new()
+        };
+
         let mut dev = UblkDev {
             dev_info: info,
-            cdev_file,
+            cdev_file: None,
+            run_dir: ctrl.run_dir().to_string(),
+            comp_chans,
+            ioctl_encode: ctrl.ioctl_encode(),
             tgt,
             flags,
         };
 
-        ctrl.json = ops(&mut dev)?;
-        info!("dev {} initialized", dev.dev_info.dev_id);
+        ctrl.json = ops(&mut dev)?;
+        dev.tgt.probe_ring(dev.dev_info.nr_hw_queues)?;
+
+        before_open(ctrl)?;
+        let cdev_file = Self::open_cdev(info.dev_id)?;
+        dev.tgt.fds[0] = cdev_file.as_raw_fd();
+        dev.cdev_file = Some(cdev_file);
+        info!("dev {} initialized", dev.dev_info.dev_id);
+
+        Ok(dev)
+    }
+
+    /// Open /dev/ublkcN of this device
+    ///
+    /// The char device may not be ready just after the device is added,
+    /// and for device created with UBLK_F_UNPRIVILEGED_DEV, udev has to
+    /// change owner of /dev/ublkcN to the device owner, so retry for a
+    /// while before giving up.
+    fn open_cdev(dev_id: u32) -> Result<fs::File, UblkError> {
+        #[cfg(feature = "mock-driver")]
+        if super::mock::enabled() {
+            return super::mock::open_cdev(dev_id);
+        }
+
+        let cdev_path = format!("{}{}", super::CDEV_PATH, dev_id);
+        let mut retry = 30;
+
+        loop {
+            match fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&cdev_path)
+            {
+                Ok(f) => return Ok(f),
+                Err(e) => {
+                    let again = matches!(
+                        e.raw_os_error(),
+                        Some(libc::ENOENT) | Some(libc::EACCES) | Some(libc::EPERM)
+                    );
+                    if !again || retry == 0 {
+                        return Err(UblkError::OtherIOError(e));
+                    }
+                }
+            }
+            retry -= 1;
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+    }
+
+    //private method for drop
+    fn deinit_cdev(&mut self) {
+        let id = self.dev_info.dev_id;
+
+        info!("dev {} deinitialized", id);
+    }
+
+    /// Return completer of the specified queue
+    ///
+    /// # Arguments:
+    ///
+    /// * `q_id`: queue id
+    ///
+    /// None if `UBLK_DEV_F_COMP_CHAN` isn't set. Often called from
+    /// `UblkTarget::queue_handler()`, then the completer is passed to
+    /// other threads which handle IO of this queue.
+    pub fn completer(&self, q_id: u16) -> Option<UblkQueueCompleter> {
+        self.comp_chans
+            .get(q_id as usize)
+            .map(|chan| UblkQueueCompleter {
+                q_id,
+                chan: chan.clone(),
+            })
+    }
+
+    /// Return how this device is recovered after its daemon is gone
+    pub fn recovery_mode(&self) -> UblkRecoveryMode {
+        UblkRecoveryMode::from(UblkFlags::from_bits_retain(self.dev_info.flags))
+    }
+
+    /// Set parameters of this device
+    ///
+    /// # Arguments:
+    ///
+    /// * `params`: parameters builder, which is validated against this
+    ///     device's info
+    ///
+    /// The parameters are sent to ublk driver when the device is started.
+    pub fn set_params(&mut self, params: UblkParamsBuilder) -> Result<(), UblkError> {
+        let dev_size = params.dev_size();
+
+        self.tgt.params = params.build(self.dev_info.max_io_buf_bytes)?;
+        self.tgt.dev_size = dev_size;
+        Ok(())
+    }
+
+    pub fn set_default_params(&mut self, dev_size: u64) {
+        let info = self.dev_info;
+
+        self.tgt.dev_size = dev_size;
+        self.tgt.params = super::sys::ublk_params {
+            types: super::sys::UBLK_PARAM_TYPE_BASIC,
+            basic: super::sys::ublk_param_basic {
+                logical_bs_shift: 9,
+                physical_bs_shift: 12,
+                io_opt_shift: 12,
+                io_min_shift: 12,
+                max_sectors: info.max_io_buf_bytes >> 9,
+                dev_sectors: dev_size >> 9,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+    }
+}
+
+impl Drop for UblkDev {
+    fn drop(&mut self) {
+        self.deinit_cdev();
+    }
+}
+
+union IOCmd {
+    cmd: sys::ublksrv_io_cmd,
+    buf: [u8; 16],
+}
+
+const UBLK_IO_NEED_FETCH_RQ: u32 = 1_u32 << 0;
+const UBLK_IO_NEED_COMMIT_RQ_COMP: u32 = 1_u32 << 1;
+const UBLK_IO_FREE: u32 = 1u32 << 2;
+const UBLK_IO_TO_QUEUE: u32 = 1u32 << 3;
+const UBLK_IO_NEED_GET_DATA: u32 = 1u32 << 4;
+
+struct UblkIO {
+    /// buffer allocated by queue
+    buf_addr: *mut u8,
+
+    /// buffer passed to ublk driver, which may be set by target
+    data_addr: *mut u8,
+
+    /// index of `buf_addr` in io_uring fixed buffers
+    buf_index: Option<u16>,
+
+    /// io descriptor in queue's io command buffer, null for extra ios
+    iod: *const sys::ublksrv_io_desc,
+    flags: u32,
+    result: i32,
+}
+
+impl UblkIO {
+    #[inline(always)]
+    fn get_buf_addr(&self) -> *mut u8 {
+        self.data_addr
+    }
+
+    #[inline(always)]
+    fn get_buf_index(&self) -> Option<u16> {
+        if self.data_addr == self.buf_addr {
+            self.buf_index
+        } else {
+            None
+        }
+    }
+
+    /// Complete this io command
+    ///
+    /// # Arguments:
+    ///
+    /// * `res`: result of handling this io command
+    ///
+    /// Called from specific target code for completing this io command,
+    /// so ublk driver gets notified and complete IO request on
+    /// /dev/ublkbN
+    ///
+    #[inline(always)]
+    fn complete(&mut self, res: i32) {
+        self.flags |= UBLK_IO_NEED_COMMIT_RQ_COMP | UBLK_IO_FREE | UBLK_IO_TO_QUEUE;
+        self.result = res;
+    }
+}
+
+/// Sub-IO whose result isn't added to bytes of the io command
+const UBLK_SUB_IO_NO_BYTES: u32 = 1_u32 << 31;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct UblkSubIoState {
+    issued: u32,
+    inflight: u32,
+    bytes: i32,
+    err: i32,
+}
+
+/// Track sub-IOs of io commands fanned out to multiple target operations
+///
+/// Striped, mirrored or split io command is handled by issuing several
+/// target IOs. Fan-out is started by `begin()`, then user_data of each
+/// target IO is built by `sub_io_user_data()` with one sub-IO id in
+/// `tgt_data`. When CQE of sub-IO is handled in
+/// `UblkQueueHandler::handle_tgt_io()`, `handle_tgt_io()` of the tracker
+/// accounts its result, and completes the io command when the last sub-IO
+/// is done.
+///
+/// The io command is completed with the first error of its sub-IOs, or sum
+/// of bytes of sub-IOs which count bytes. One tracker is for one queue, and
+/// is often owned by the queue handler.
+///
+/// # Examples:
+///
+/// ```no_run
+/// use libublk::io::{UblkIOCtx, UblkSubIoTracker};
+/// use libublk::{sys, UblkError};
+///
+/// fn handle_io(tracker: &mut UblkSubIoTracker, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+///     let tag = io.get_tag() as u16;
+///
+///     tracker.begin(tag)?;
+///     for copy in 0..2 {
+///         let data = tracker.sub_io_user_data(tag, sys::UBLK_IO_OP_WRITE, copy == 0)?;
+///
+///         // push one write SQE with `data`, and roll back this sub-IO and
+///         // the following ones by `tracker.cancel(tag, 2 - copy)` if the
+///         // SQE can't be pushed
+///     }
+///     Ok(0)
+/// }
+///
+/// fn handle_tgt_io(tracker: &mut UblkSubIoTracker, io: &mut UblkIOCtx) {
+///     // the io command is completed after all sub-IOs are done
+///     tracker.handle_tgt_io(io).unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct UblkSubIoTracker {
+    ios: Vec<UblkSubIoState>,
+}
+
+impl UblkSubIoTracker {
+    /// New tracker for queue of `depth`
+    pub fn new(depth: u16) -> Self {
+        UblkSubIoTracker {
+            ios: vec![UblkSubIoState::default(); depth as usize],
+        }
+    }
+
+    fn state(&mut self, tag: u16) -> Result<&mut UblkSubIoState, UblkError> {
+        self.ios
+            .get_mut(tag as usize)
+            .ok_or(UblkError::OtherError(-libc::EINVAL))
+    }
+
+    /// Begin to fan out io command of `tag`
+    ///
+    /// # Arguments:
+    ///
+    /// * `tag`: io tag
+    ///
+    /// -EBUSY is returned if sub-IOs of this tag are still in flight.
+    pub fn begin(&mut self, tag: u16) -> Result<(), UblkError> {
+        let s = self.state(tag)?;
+
+        if s.inflight != 0 {
+            return Err(UblkError::OtherError(-libc::EBUSY));
+        }
+        *s = UblkSubIoState::default();
+        Ok(())
+    }
+
+    /// Return user_data of one new sub-IO of `tag`
+    ///
+    /// # Arguments:
+    ///
+    /// * `tag`: io tag
+    /// * `op`: io operation code of this sub-IO, length is 8bit
+    /// * `count_bytes`: if result of this sub-IO is added to bytes of the
+    ///     io command, false for redundant copies, such as the 2nd mirror
+    ///
+    /// Called after `begin()` of this tag. The sub-IO is counted as in
+    /// flight, so its SQE has to be submitted, otherwise it has to be
+    /// rolled back by `cancel()`. -EINVAL is returned if `op` can't be
+    /// stored in 8bit.
+    pub fn sub_io_user_data(
+        &mut self,
+        tag: u16,
+        op: u32,
+        count_bytes: bool,
+    ) -> Result<u64, UblkError> {
+        let s = self.state(tag)?;
+        let id = s.issued;
+
+        if id >= UBLK_SUB_IO_NO_BYTES {
+            return Err(UblkError::OtherError(-libc::E2BIG));
+        }
+
+        let tgt_data = if count_bytes {
+            id
+        } else {
+            id | UBLK_SUB_IO_NO_BYTES
+        };
+        let data = UblkIOCtx::try_build_user_data(tag, op, tgt_data, true)?;
+
+        s.issued += 1;
+        s.inflight += 1;
+        Ok(data)
+    }
+
+    /// Roll back sub-IOs of `tag` which are counted, but never submitted
+    ///
+    /// # Arguments:
+    ///
+    /// * `tag`: io tag
+    /// * `nr`: how many of the latest sub-IOs aren't submitted
+    ///
+    /// Called when SQE of sub-IO can't be pushed, such as the submission
+    /// queue is full. If other sub-IOs are still in flight, the io command
+    /// is completed with -ECANCELED by the last one in `handle_tgt_io()`.
+    ///
+    /// Return true if no sub-IO is in flight, then the tag can be started
+    /// by `begin()` again, and the io command has to be completed by
+    /// caller. -EINVAL is returned if `nr` is bigger than in-flight sub-IOs.
+    pub fn cancel(&mut self, tag: u16, nr: u32) -> Result<bool, UblkError> {
+        let s = self.state(tag)?;
+
+        if nr > s.inflight {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        s.issued -= nr;
+        s.inflight -= nr;
+        if s.inflight != 0 {
+            if s.err == 0 {
+                s.err = -libc::ECANCELED;
+            }
+            return Ok(false);
+        }
+        *s = UblkSubIoState::default();
+        Ok(true)
+    }
+
+    /// Return how many sub-IOs of `tag` are in flight
+    pub fn inflight(&self, tag: u16) -> u32 {
+        self.ios.get(tag as usize).map_or(0, |s| s.inflight)
+    }
+
+    /// Account CQE of one sub-IO, and complete the io command if it is
+    /// the last one
+    ///
+    /// # Arguments:
+    ///
+    /// * `io`: context of target io CQE, whose user_data is built by
+    ///     `sub_io_user_data()`
+    ///
+    /// Return true if the io command is completed. -EAGAIN of sub-IO is
+    /// handled as error, so target has to retry it before calling this
+    /// function if it is expected. The io command is failed with
+    /// -EOVERFLOW if sum of bytes can't be held in i32.
+    pub fn handle_tgt_io(&mut self, io: &mut UblkIOCtx) -> Result<bool, UblkError> {
+        let user_data = io.user_data();
+        let tag = UblkIOCtx::user_data_to_tag(user_data) as u16;
+        let tgt_data = UblkIOCtx::user_data_to_tgt_data(user_data);
+        let res = io.result();
+        let s = self.state(tag)?;
+
+        if !io.is_tgt_io() || s.inflight == 0 || (tgt_data & !UBLK_SUB_IO_NO_BYTES) >= s.issued {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+
+        s.inflight -= 1;
+        let res = if res >= 0 && (tgt_data & UBLK_SUB_IO_NO_BYTES) == 0 {
+            match s.bytes.checked_add(res) {
+                Some(bytes) => {
+                    s.bytes = bytes;
+                    0
+                }
+                None => -libc::EOVERFLOW,
+            }
+        } else {
+            res
+        };
+        if res < 0 && s.err == 0 {
+            s.err = res;
+        }
+
+        if s.inflight != 0 {
+            return Ok(false);
+        }
 
-        Ok(dev)
+        let res = if s.err < 0 { s.err } else { s.bytes };
+        *s = UblkSubIoState::default();
+        io.complete_io(res);
+        Ok(true)
     }
+}
 
-    //private method for drop
-    fn deinit_cdev(&mut self) {
-        let id = self.dev_info.dev_id;
+/// user_data of the eventfd read of completion channel
+const UBLK_COMP_CHAN_USER_DATA: u64 = u64::MAX - 1;
 
-        info!("dev {} deinitialized", id);
-    }
+/// IOs completed from other threads, and the queue is woken up by eventfd
+struct UblkCompChan {
+    comps: Mutex<Vec<(u16, i32)>>,
+    efd: fs::File,
+}
 
-    pub fn set_default_params(&mut self, dev_size: u64) {
-        let info = self.dev_info;
+impl UblkCompChan {
+    fn new() -> Result<UblkCompChan, UblkError> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+        }
 
-        self.tgt.dev_size = dev_size;
-        self.tgt.params = super::sys::ublk_params {
-            types: super::sys::UBLK_PARAM_TYPE_BASIC,
-            basic: super::sys::ublk_param_basic {
-                logical_bs_shift: 9,
-                physical_bs_shift: 12,
-                io_opt_shift: 12,
-                io_min_shift: 12,
-                max_sectors: info.max_io_buf_bytes >> 9,
-                dev_sectors: dev_size >> 9,
-                ..Default::default()
-            },
-            ..Default::default()
-        };
+        Ok(UblkCompChan {
+            comps: Mutex::new(Vec::new()),
+            efd: unsafe { fs::File::from_raw_fd(fd) },
+        })
     }
-}
 
-impl Drop for UblkDev {
-    fn drop(&mut self) {
-        self.deinit_cdev();
+    fn take(&self) -> Vec<(u16, i32)> {
+        std::mem::take(&mut *self.comps.lock().unwrap_or_else(|e| e.into_inner()))
     }
 }
 
-union IOCmd {
-    cmd: sys::ublksrv_io_cmd,
-    buf: [u8; 16],
-}
-
-const UBLK_IO_NEED_FETCH_RQ: u32 = 1_u32 << 0;
-const UBLK_IO_NEED_COMMIT_RQ_COMP: u32 = 1_u32 << 1;
-const UBLK_IO_FREE: u32 = 1u32 << 2;
-const UBLK_IO_TO_QUEUE: u32 = 1u32 << 3;
-
-struct UblkIO {
-    buf_addr: *mut u8,
-    flags: u32,
-    result: i32,
+/// Handle for completing IOs of one queue from other threads
+///
+/// Retrieved by `UblkDev::completer()` or `UblkQueue::completer()`, and
+/// can be cloned and sent to any thread. Posted IOs are committed to ublk
+/// driver in batch from `UblkQueue::process_io()`.
+#[derive(Clone)]
+pub struct UblkQueueCompleter {
+    q_id: u16,
+    chan: Arc<UblkCompChan>,
 }
 
-impl UblkIO {
-    #[inline(always)]
-    fn get_buf_addr(&self) -> *mut u8 {
-        self.buf_addr
+impl UblkQueueCompleter {
+    /// Return queue id
+    pub fn q_id(&self) -> u16 {
+        self.q_id
     }
 
-    /// Complete this io command
+    /// Complete one io command
     ///
     /// # Arguments:
     ///
+    /// * `tag`: io tag, and the io command must have been passed to io
+    ///     handler and not completed yet
     /// * `res`: result of handling this io command
-    ///
-    /// Called from specific target code for completing this io command,
-    /// so ublk driver gets notified and complete IO request on
-    /// /dev/ublkbN
-    ///
-    #[inline(always)]
-    fn complete(&mut self, res: i32) {
-        self.flags |= UBLK_IO_NEED_COMMIT_RQ_COMP | UBLK_IO_FREE | UBLK_IO_TO_QUEUE;
-        self.result = res;
+    pub fn complete(&self, tag: u16, res: i32) -> Result<(), UblkError> {
+        self.chan
+            .comps
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((tag, res));
+
+        // the queue reads eventfd before taking completions
+        (&self.chan.efd)
+            .write_all(&1_u64.to_ne_bytes())
+            .map_err(UblkError::OtherIOError)
     }
 }
 
+/// Queue side of completion channel
+struct UblkQueueComp {
+    chan: Arc<UblkCompChan>,
+
+    /// buffer of the eventfd read
+    buf: Box<u64>,
+    armed: bool,
+    ready: bool,
+}
+
 /// UblkQueue Context info
 ///
 ///
@@ -366,7 +1639,175 @@ pub struct UblkQueueCtx {
     buf_addr: u64,
 }
 
+/// Operation of io command, decoded from `ublksrv_io_desc.op_flags`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UblkIoOp {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteSame,
+    WriteZeroes,
+
+    /// operation not known by this library
+    Unknown(u8),
+}
+
+impl From<u8> for UblkIoOp {
+    fn from(op: u8) -> Self {
+        match op as u32 {
+            sys::UBLK_IO_OP_READ => UblkIoOp::Read,
+            sys::UBLK_IO_OP_WRITE => UblkIoOp::Write,
+            sys::UBLK_IO_OP_FLUSH => UblkIoOp::Flush,
+            sys::UBLK_IO_OP_DISCARD => UblkIoOp::Discard,
+            sys::UBLK_IO_OP_WRITE_SAME => UblkIoOp::WriteSame,
+            sys::UBLK_IO_OP_WRITE_ZEROES => UblkIoOp::WriteZeroes,
+            _ => UblkIoOp::Unknown(op),
+        }
+    }
+}
+
+impl From<UblkIoOp> for u32 {
+    fn from(op: UblkIoOp) -> Self {
+        match op {
+            UblkIoOp::Read => sys::UBLK_IO_OP_READ,
+            UblkIoOp::Write => sys::UBLK_IO_OP_WRITE,
+            UblkIoOp::Flush => sys::UBLK_IO_OP_FLUSH,
+            UblkIoOp::Discard => sys::UBLK_IO_OP_DISCARD,
+            UblkIoOp::WriteSame => sys::UBLK_IO_OP_WRITE_SAME,
+            UblkIoOp::WriteZeroes => sys::UBLK_IO_OP_WRITE_ZEROES,
+            UblkIoOp::Unknown(op) => op as u32,
+        }
+    }
+}
+
+bitflags! {
+    /// Flags(`UBLK_IO_F_*`) of io command, decoded from
+    /// `ublksrv_io_desc.op_flags`
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct UblkIoFlags: u32 {
+        const FAILFAST_DEV = sys::UBLK_IO_F_FAILFAST_DEV;
+        const FAILFAST_TRANSPORT = sys::UBLK_IO_F_FAILFAST_TRANSPORT;
+        const FAILFAST_DRIVER = sys::UBLK_IO_F_FAILFAST_DRIVER;
+        const META = sys::UBLK_IO_F_META;
+        const FUA = sys::UBLK_IO_F_FUA;
+        const NOUNMAP = sys::UBLK_IO_F_NOUNMAP;
+        const SWAP = sys::UBLK_IO_F_SWAP;
+    }
+}
+
+/// Copy of io command descriptor(`ublksrv_io_desc`) filled by ublk driver
+///
+/// # Examples:
+///
+/// ```no_run
+/// use libublk::io::{UblkIOCtx, UblkIoFlags, UblkIoOp};
+///
+/// fn handle(io: &mut UblkIOCtx) {
+///     let Some(desc) = io.io_desc() else {
+///         return;
+///     };
+///
+///     match desc.op() {
+///         UblkIoOp::Write if desc.flags().contains(UblkIoFlags::FUA) => {}
+///         UblkIoOp::Read | UblkIoOp::Write => {}
+///         _ => {}
+///     }
+///     io.complete_io(desc.len() as i32);
+/// }
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UblkIoDesc(sys::ublksrv_io_desc);
+
+impl From<sys::ublksrv_io_desc> for UblkIoDesc {
+    fn from(iod: sys::ublksrv_io_desc) -> Self {
+        UblkIoDesc(iod)
+    }
+}
+
+impl UblkIoDesc {
+    /// Return io operation
+    #[inline(always)]
+    pub fn op(&self) -> UblkIoOp {
+        UblkIoOp::from((self.0.op_flags & 0xff) as u8)
+    }
+
+    /// Return io flags, and unknown flags are dropped
+    #[inline(always)]
+    pub fn flags(&self) -> UblkIoFlags {
+        UblkIoFlags::from_bits_truncate(self.0.op_flags)
+    }
+
+    #[inline(always)]
+    pub fn start_sector(&self) -> u64 {
+        self.0.start_sector
+    }
+
+    #[inline(always)]
+    pub fn nr_sectors(&self) -> u32 {
+        self.0.nr_sectors
+    }
+
+    /// Return byte offset of this IO
+    #[inline(always)]
+    pub fn offset(&self) -> u64 {
+        self.0.start_sector << 9
+    }
+
+    /// Return bytes of this IO
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        (self.0.nr_sectors as usize) << 9
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.0.nr_sectors == 0
+    }
+
+    /// Return bytes transferred via io buffer, which is zero for
+    /// operations without data, such as flush and discard
+    #[inline(always)]
+    pub fn data_len(&self) -> usize {
+        match self.op() {
+            UblkIoOp::Read | UblkIoOp::Write => self.len(),
+            _ => 0,
+        }
+    }
+
+    /// Return the raw descriptor
+    #[inline(always)]
+    pub fn raw(&self) -> &sys::ublksrv_io_desc {
+        &self.0
+    }
+}
+
 impl UblkQueueCtx {
+    /// Return copy of io descriptor of `tag`, see `UblkIoDesc`
+    ///
+    /// # Arguments:
+    ///
+    /// * `tag`: io tag, [0, depth)
+    ///
+    /// Empty descriptor is returned if `tag` is out of range, see
+    /// `try_io_desc()` for validating it.
+    #[inline(always)]
+    pub fn io_desc(&self, tag: u16) -> UblkIoDesc {
+        self.try_io_desc(tag).unwrap_or_default()
+    }
+
+    /// Same with `io_desc()`, but -EINVAL is returned if `tag` is out
+    /// of range
+    #[inline(always)]
+    pub fn try_io_desc(&self, tag: u16) -> Result<UblkIoDesc, UblkError> {
+        let iod = self.get_iod(tag as u32);
+
+        if iod.is_null() {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        Ok(UblkIoDesc(unsafe { *iod }))
+    }
+
     /// Return IO command description info represented by `ublksrv_io_desc`
     ///
     /// # Arguments:
@@ -374,11 +1815,13 @@ impl UblkQueueCtx {
     /// * `tag`: io tag
     ///
     /// Returned `ublksrv_io_desc` data is readonly, and filled by ublk kernel
-    /// driver
+    /// driver. Null is returned if `tag` is out of range.
     ///
     #[inline(always)]
     pub fn get_iod(&self, tag: u32) -> *const sys::ublksrv_io_desc {
-        assert!(tag < self.depth as u32);
+        if tag >= self.depth as u32 {
+            return std::ptr::null();
+        }
         (self.buf_addr + tag as u64 * 24) as *const sys::ublksrv_io_desc
     }
 }
@@ -386,12 +1829,22 @@ impl UblkQueueCtx {
 const UBLK_QUEUE_STOPPING: u32 = 1_u32 << 0;
 const UBLK_QUEUE_IDLE: u32 = 1_u32 << 1;
 const UBLK_QUEUE_POLL: u32 = 1_u32 << 2;
+const UBLK_QUEUE_IOCTL_ENCODE: u32 = 1_u32 << 3;
+
+/// IOPOLL ring for target io of one queue, see `UBLK_RING_F_IOPOLL`
+struct UblkTgtRing {
+    ring: IoUring<squeue::Entry>,
+
+    /// target io queued and not reaped yet
+    inflight: u32,
+}
 
 /// UBLK queue abstraction
 ///
 /// Responsible for handling ublk IO from ublk driver.
 ///
-/// So far, each queue is handled by one single io_uring.
+/// So far, each queue is handled by one single io_uring, and target io
+/// may be issued to one extra IOPOLL ring, see `UBLK_RING_F_IOPOLL`.
 ///
 pub struct UblkQueue<'a> {
     flags: u32,
@@ -404,7 +1857,12 @@ pub struct UblkQueue<'a> {
     q_state: u32,
     cqes_idx: usize,
     cqes_cnt: usize,
+    cqes: Vec<UblkCQE>,
     ios: Vec<UblkIO>,
+    inflight: Option<UblkInflightMap>,
+    transport: Box<dyn UblkQueueTransport>,
+    comp: Option<UblkQueueComp>,
+    tgt_ring: Option<UblkTgtRing>,
     pub q_ring: IoUring<squeue::Entry>,
 }
 
@@ -416,6 +1874,11 @@ impl Drop for UblkQueue<'_> {
         if let Err(r) = self.q_ring.submitter().unregister_files() {
             error!("unregister fixed files failed {}", r);
         }
+        if (self.dev.flags & UBLK_DEV_F_FIXED_BUF) != 0 {
+            if let Err(r) = self.q_ring.submitter().unregister_buffers() {
+                error!("unregister fixed buffers failed {}", r);
+            }
+        }
 
         let depth = dev.dev_info.queue_depth as u32;
         let cmd_buf_sz = UblkQueue::cmd_buf_sz(depth) as usize;
@@ -425,17 +1888,44 @@ impl Drop for UblkQueue<'_> {
             libc::munmap(self.io_cmd_buf as *mut libc::c_void, cmd_buf_sz);
         }
 
-        for i in 0..depth {
-            let io = &self.ios[i as usize];
+        for io in self.ios.iter().take(depth as usize) {
             super::ublk_dealloc_buf(
                 io.buf_addr,
                 dev.dev_info.max_io_buf_bytes as usize,
-                unsafe { libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap() },
+                ublk_page_size(),
             );
         }
     }
 }
 
+/// Convert legacy io command opcode into ioctl encoded opcode
+#[inline(always)]
+fn ublk_io_cmd_op_ioctl(cmd_op: u32) -> u32 {
+    let op = match cmd_op {
+        sys::UBLK_IO_FETCH_REQ => sys::UBLK_U_IO_FETCH_REQ,
+        sys::UBLK_IO_COMMIT_AND_FETCH_REQ => sys::UBLK_U_IO_COMMIT_AND_FETCH_REQ,
+        sys::UBLK_IO_NEED_GET_DATA => sys::UBLK_U_IO_NEED_GET_DATA,
+        _ => return cmd_op,
+    };
+
+    op as u32
+}
+
+#[inline(always)]
+fn ublk_page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Name of io command for error report
+fn ublk_io_cmd_name(cmd_op: u32) -> &'static str {
+    match cmd_op {
+        sys::UBLK_IO_FETCH_REQ => "FETCH_REQ",
+        sys::UBLK_IO_COMMIT_AND_FETCH_REQ => "COMMIT_AND_FETCH_REQ",
+        sys::UBLK_IO_NEED_GET_DATA => "NEED_GET_DATA",
+        _ => "UNKNOWN",
+    }
+}
+
 #[inline(always)]
 fn round_up(val: u32, rnd: u32) -> u32 {
     (val + rnd - 1) & !(rnd - 1)
@@ -445,7 +1935,7 @@ impl UblkQueue<'_> {
     #[inline(always)]
     fn cmd_buf_sz(depth: u32) -> u32 {
         let size = depth * core::mem::size_of::<sys::ublksrv_io_desc>() as u32;
-        let page_sz = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u32;
+        let page_sz = ublk_page_size() as u32;
 
         round_up(size, page_sz)
     }
@@ -454,7 +1944,7 @@ impl UblkQueue<'_> {
     pub fn make_queue_ctx(&self) -> UblkQueueCtx {
         UblkQueueCtx {
             buf_addr: self.io_cmd_buf,
-            depth: self.q_depth.try_into().unwrap(),
+            depth: self.q_depth as u16,
             q_id: self.q_id,
         }
     }
@@ -468,28 +1958,49 @@ impl UblkQueue<'_> {
     ///
     ///ublk queue is handling IO from driver, so far we use dedicated
     ///io_uring for handling both IO command and IO
-    #[allow(clippy::uninit_vec)]
     pub fn new(q_id: u16, dev: &UblkDev) -> Result<UblkQueue, UblkError> {
         let tgt = &dev.tgt;
         let sq_depth = tgt.sq_depth;
         let cq_depth = tgt.cq_depth;
 
-        let ring = IoUring::<squeue::Entry, cqueue::Entry>::builder()
+        let ring = tgt
+            .ring_builder(q_id)?
             .setup_cqsize(cq_depth as u32)
-            .setup_coop_taskrun()
             .build(sq_depth as u32)
             .map_err(UblkError::OtherIOError)?;
 
-        //todo: apply io_uring flags from tgt.ring_flags
-
         let depth = dev.dev_info.queue_depth as u32;
-        let cdev_fd = dev.cdev_file.as_raw_fd();
+        let cdev_fd = tgt.fds[0];
         let cmd_buf_sz = UblkQueue::cmd_buf_sz(depth) as usize;
 
         ring.submitter()
             .register_files(&tgt.fds[0..tgt.nr_fds as usize])
             .map_err(UblkError::OtherIOError)?;
 
+        // same fixed files with queue ring, so target io can be issued to
+        // either ring
+        let tgt_ring = match tgt.tgt_ring_builder() {
+            Some(mut builder) => {
+                let ring = builder
+                    .setup_cqsize(cq_depth as u32)
+                    .build(sq_depth as u32)
+                    .map_err(UblkError::OtherIOError)?;
+                ring.submitter()
+                    .register_files(&tgt.fds[0..tgt.nr_fds as usize])
+                    .map_err(UblkError::OtherIOError)?;
+                Some(UblkTgtRing { ring, inflight: 0 })
+            }
+            None => None,
+        };
+
+        let inflight = if (dev.flags & UBLK_DEV_F_TRACK_INFLIGHT) != 0 {
+            let path = ublk_inflight_path(&dev.run_dir, dev.dev_info.dev_id, q_id);
+            Some(UblkInflightMap::new(path, depth)?)
+        } else {
+            None
+        };
+        let transport = ublk_queue_transport(dev, q_id)?;
+
         let off = sys::UBLKSRV_CMD_BUF_OFFSET as i64
             + q_id as i64
                 * ((sys::UBLK_MAX_QUEUE_DEPTH as usize
@@ -505,33 +2016,35 @@ impl UblkQueue<'_> {
             )
         };
         if io_cmd_buf == libc::MAP_FAILED {
-            return Err(UblkError::MmapError(
-                "io cmd buffer mmap failed".to_string(),
-            ));
+            return Err(UblkError::MmapError(format!(
+                "io cmd buffer of dev {} queue {}: {}",
+                dev.dev_info.dev_id,
+                q_id,
+                std::io::Error::last_os_error()
+            )));
         }
 
+        // io buffer is allocated after the queue is built, so that it
+        // is freed by drop() in case of failure
         let nr_ios = depth + tgt.extra_ios as u32;
-        let mut ios = Vec::<UblkIO>::with_capacity(nr_ios as usize);
-        unsafe {
-            ios.set_len(nr_ios as usize);
-        }
-
-        for i in 0..nr_ios {
-            let mut io = &mut ios[i as usize];
-
-            // extra io slot needn't to allocate buffer
-            if i < depth {
-                io.buf_addr =
-                    super::ublk_alloc_buf(dev.dev_info.max_io_buf_bytes as usize, unsafe {
-                        libc::sysconf(libc::_SC_PAGESIZE).try_into().unwrap()
-                    });
-                io.flags = UBLK_IO_NEED_FETCH_RQ | UBLK_IO_FREE;
-            } else {
-                io.buf_addr = std::ptr::null_mut();
-                io.flags = 0;
-            }
-            io.result = -1;
-        }
+        let ios = (0..nr_ios)
+            .map(|i| UblkIO {
+                buf_addr: std::ptr::null_mut(),
+                data_addr: std::ptr::null_mut(),
+                buf_index: None,
+                iod: if i < depth {
+                    (io_cmd_buf as u64 + i as u64 * 24) as *const sys::ublksrv_io_desc
+                } else {
+                    std::ptr::null()
+                },
+                flags: if i < depth {
+                    UBLK_IO_NEED_FETCH_RQ | UBLK_IO_FREE
+                } else {
+                    0
+                },
+                result: -1,
+            })
+            .collect();
 
         let mut q = UblkQueue {
             flags: dev.flags,
@@ -540,19 +2053,87 @@ impl UblkQueue<'_> {
             io_cmd_buf: io_cmd_buf as u64,
             dev,
             cmd_inflight: 0,
-            q_state: 0,
+            q_state: if dev.ioctl_encode {
+                UBLK_QUEUE_IOCTL_ENCODE
+            } else {
+                0
+            },
             q_ring: ring,
+            tgt_ring,
             ios,
+            inflight,
+            transport,
+            comp: dev.comp_chans.get(q_id as usize).map(|chan| UblkQueueComp {
+                chan: chan.clone(),
+                buf: Box::new(0),
+                armed: false,
+                ready: false,
+            }),
             cqes_idx: 0,
             cqes_cnt: 0,
+            cqes: Vec::with_capacity(cq_depth as usize),
+        };
+
+        // extra io slot needn't to allocate buffer, and data is copied
+        // by target in case of user copy
+        let nr_bufs = if (dev.flags & UBLK_DEV_F_LAZY_IO_BUF) != 0
+            || (dev.dev_info.flags & sys::UBLK_F_USER_COPY as u64) != 0
+        {
+            0
+        } else {
+            depth as usize
         };
-        q.submit_fetch_commands();
+        for io in q.ios.iter_mut().take(nr_bufs) {
+            io.buf_addr =
+                super::ublk_alloc_buf(dev.dev_info.max_io_buf_bytes as usize, ublk_page_size());
+            if io.buf_addr.is_null() {
+                return Err(UblkError::OtherError(-libc::ENOMEM));
+            }
+            io.data_addr = io.buf_addr;
+        }
+        if (dev.flags & UBLK_DEV_F_FIXED_BUF) != 0 {
+            q.register_io_bufs()?;
+        }
+        q.submit_fetch_commands()?;
 
         trace!("dev {} queue {} started", dev.dev_info.dev_id, q_id);
 
         Ok(q)
     }
 
+    /// Register io buffer of each tag as io_uring fixed buffer, and
+    /// buffer index is same with tag
+    fn register_io_bufs(&mut self) -> Result<(), UblkError> {
+        let len = self.dev.dev_info.max_io_buf_bytes as usize;
+        let iovs: Vec<libc::iovec> = self
+            .ios
+            .iter()
+            .take(self.q_depth as usize)
+            .map(|io| libc::iovec {
+                iov_base: io.buf_addr as *mut libc::c_void,
+                iov_len: len,
+            })
+            .collect();
+
+        // buffers are live until the queue is dropped
+        unsafe { self.q_ring.submitter().register_buffers(&iovs) }
+            .map_err(UblkError::OtherIOError)?;
+        if let Some(t) = self.tgt_ring.as_ref() {
+            unsafe { t.ring.submitter().register_buffers(&iovs) }
+                .map_err(UblkError::OtherIOError)?;
+        }
+        for (tag, io) in self.ios.iter_mut().take(iovs.len()).enumerate() {
+            io.buf_index = Some(tag as u16);
+        }
+        Ok(())
+    }
+
+    /// Return completer of this queue, None if `UBLK_DEV_F_COMP_CHAN`
+    /// isn't set
+    pub fn completer(&self) -> Option<UblkQueueCompleter> {
+        self.dev.completer(self.q_id)
+    }
+
     fn support_comp_batch(&self) -> bool {
         self.flags & UBLK_DEV_F_COMP_BATCH != 0
     }
@@ -571,42 +2152,51 @@ impl UblkQueue<'_> {
 
     #[inline(always)]
     #[allow(unused_assignments)]
-    fn __queue_io_cmd(&mut self, tag: u16) -> i32 {
+    fn __queue_io_cmd(&mut self, tag: u16) -> Result<i32, UblkError> {
         let mut cmd_op = 0_u32;
         let io = &self.ios[tag as usize];
 
         if (io.flags & UBLK_IO_FREE) == 0 {
-            return 0;
+            return Ok(0);
         }
 
-        if (io.flags & UBLK_IO_NEED_COMMIT_RQ_COMP) != 0 {
+        if (io.flags & UBLK_IO_NEED_GET_DATA) != 0 {
+            cmd_op = sys::UBLK_IO_NEED_GET_DATA;
+        } else if (io.flags & UBLK_IO_NEED_COMMIT_RQ_COMP) != 0 {
             cmd_op = sys::UBLK_IO_COMMIT_AND_FETCH_REQ;
+            if let Some(m) = &self.inflight {
+                m.clear(tag as u32);
+            }
         } else if (io.flags & UBLK_IO_NEED_FETCH_RQ) != 0 {
             cmd_op = sys::UBLK_IO_FETCH_REQ;
         } else {
-            return 0;
+            return Ok(0);
         }
 
-        let io_cmd = IOCmd {
-            cmd: sys::ublksrv_io_cmd {
-                tag,
-                addr: io.buf_addr as u64,
-                q_id: self.q_id,
-                result: io.result,
-            },
+        let io_cmd = sys::ublksrv_io_cmd {
+            tag,
+            addr: io.data_addr as u64,
+            q_id: self.q_id,
+            result: io.result,
         };
         let data = UblkIOCtx::build_user_data(tag, cmd_op, 0, false);
+        let op = if (self.q_state & UBLK_QUEUE_IOCTL_ENCODE) != 0 {
+            ublk_io_cmd_op_ioctl(cmd_op)
+        } else {
+            cmd_op
+        };
 
-        let sqe = opcode::UringCmd16::new(types::Fixed(0), cmd_op)
-            .cmd(unsafe { io_cmd.buf })
-            .build()
-            .user_data(data);
-
-        unsafe {
-            self.q_ring
-                .submission()
-                .push(&sqe)
-                .expect("submission fail");
+        if let Err(e) = self
+            .transport
+            .queue_io_cmd(&mut self.q_ring, op, &io_cmd, data)
+        {
+            return Err(UblkError::IoCmdError {
+                op: ublk_io_cmd_name(cmd_op),
+                dev_id: self.dev.dev_info.dev_id,
+                q_id: self.q_id,
+                tag,
+                errno: super::UblkErrno(e.errno().unwrap_or(-libc::EIO)),
+            });
         }
 
         trace!(
@@ -619,19 +2209,25 @@ impl UblkQueue<'_> {
             (self.q_state & UBLK_QUEUE_STOPPING) != 0
         );
 
-        1
+        Ok(1)
     }
 
     #[inline(always)]
-    fn queue_io_cmd(&mut self, tag: u16) -> i32 {
-        let res = self.__queue_io_cmd(tag);
+    fn queue_io_cmd(&mut self, tag: u16) -> Result<i32, UblkError> {
+        let res = self.__queue_io_cmd(tag)?;
 
         if res > 0 {
+            let io = &mut self.ios[tag as usize];
+
+            // buffer chosen for write request is used until it is committed
+            if (io.flags & UBLK_IO_NEED_GET_DATA) == 0 {
+                io.data_addr = io.buf_addr;
+            }
             self.cmd_inflight += 1;
-            self.ios[tag as usize].flags = 0;
+            io.flags = 0;
         }
 
-        res
+        Ok(res)
     }
 
     /// Submit all commands for fetching IO
@@ -639,10 +2235,11 @@ impl UblkQueue<'_> {
     /// Only called during queue initialization. After queue is setup,
     /// COMMIT_AND_FETCH_REQ command is used for both committing io command
     /// result and fetching new incoming IO
-    fn submit_fetch_commands(&mut self) {
+    fn submit_fetch_commands(&mut self) -> Result<(), UblkError> {
         for i in 0..self.q_depth {
-            self.queue_io_cmd(i as u16);
+            self.queue_io_cmd(i as u16)?;
         }
+        Ok(())
     }
 
     #[inline(always)]
@@ -656,30 +2253,78 @@ impl UblkQueue<'_> {
     }
 
     #[inline(always)]
-    fn call_io_closure<F>(&mut self, mut ops: F, tag: u32, e: &UblkCQE)
+    fn io_error(&self, op: &'static str, tag: u32, errno: i32) -> UblkError {
+        UblkError::IoCmdError {
+            op,
+            dev_id: self.dev.dev_info.dev_id,
+            q_id: self.q_id,
+            tag: tag as u16,
+            errno: super::UblkErrno(errno),
+        }
+    }
+
+    #[inline(always)]
+    fn call_io_closure<F>(&mut self, mut ops: F, tag: u32, e: &UblkCQE) -> Result<(), UblkError>
     where
         F: FnMut(&mut UblkIOCtx) -> Result<i32, UblkError>,
     {
+        let op = if e.is_tgt_io() {
+            "TARGET_IO"
+        } else {
+            "HANDLE_IO"
+        };
         let comp_batch = self.support_comp_batch();
+        let io = match self.ios.get_mut(tag as usize) {
+            Some(io) => io,
+            None => return Err(self.io_error(op, tag, -libc::EINVAL)),
+        };
         let mut ctx = UblkIOCtx(
             &mut self.q_ring,
-            &mut self.ios[tag as usize],
+            io,
             e,
             if comp_batch { Some(Vec::new()) } else { None },
+            self.tgt_ring.as_mut(),
         );
-        let res = ops(&mut ctx).unwrap();
+        let res = match ops(&mut ctx) {
+            Ok(res) => res,
+            Err(r) => {
+                error!(
+                    "dev {} queue {} tag {}: io handler failed {}",
+                    self.dev.dev_info.dev_id, self.q_id, tag, r
+                );
+                return Err(r);
+            }
+        };
         if res == UBLK_IO_S_COMP_BATCH {
-            if let Some(ios) = ctx.3.as_mut() {
-                for item in ios {
-                    self.ios[item.0 as usize].complete(item.1);
+            if let Some(ios) = ctx.3.take() {
+                for (t, r) in ios {
+                    match self.ios.get_mut(t as usize) {
+                        Some(io) => io.complete(r),
+                        None => return Err(self.io_error(op, t as u32, -libc::EINVAL)),
+                    }
+
+                    // io of this cqe's tag is queued after the cqe is handled
+                    if t as u32 != tag {
+                        self.ios[t as usize].flags &= !UBLK_IO_TO_QUEUE;
+                        self.queue_io_cmd(t)?;
+                    }
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Check if data of io command `tag` can be held in io buffer
+    #[inline(always)]
+    fn io_desc_fits(&self, tag: u16) -> bool {
+        let desc = UblkIoDesc(unsafe { *self.ios[tag as usize].iod });
+
+        desc.data_len() <= self.dev.dev_info.max_io_buf_bytes as usize
     }
 
     #[inline(always)]
     #[allow(unused_assignments)]
-    fn handle_cqe<F>(&mut self, ops: F, e: &UblkCQE)
+    fn handle_cqe<F>(&mut self, ops: F, e: &UblkCQE) -> Result<(), UblkError>
     where
         F: FnMut(&mut UblkIOCtx) -> Result<i32, UblkError>,
     {
@@ -713,8 +2358,11 @@ impl UblkQueue<'_> {
                     UblkIOCtx::user_data_to_op(data)
                 );
             }
-            self.call_io_closure(ops, tag, e);
-            return;
+            return self.call_io_closure(ops, tag, e);
+        }
+
+        if tag >= self.q_depth {
+            return Err(self.io_error(ublk_io_cmd_name(cmd_op), tag, -libc::EINVAL));
         }
 
         self.cmd_inflight -= 1;
@@ -724,9 +2372,35 @@ impl UblkQueue<'_> {
             self.ios[tag as usize].flags &= !UBLK_IO_NEED_FETCH_RQ;
         }
 
+        // fail io command whose data can't be held in io buffer, so target
+        // code never sees it
+        if (res == sys::UBLK_IO_RES_OK as i32 || res == sys::UBLK_IO_RES_NEED_GET_DATA as i32)
+            && !self.io_desc_fits(tag as u16)
+        {
+            error!(
+                "dev {} queue {} tag {}: io exceeds buffer size {}",
+                self.dev.dev_info.dev_id, self.q_id, tag, self.dev.dev_info.max_io_buf_bytes
+            );
+            self.ios[tag as usize].complete(-libc::EIO);
+            return Ok(());
+        }
+
         if res == sys::UBLK_IO_RES_OK as i32 {
-            assert!(tag < self.q_depth);
-            self.call_io_closure(ops, tag, e);
+            if let Some(m) = &self.inflight {
+                let iod = (self.io_cmd_buf + tag as u64 * 24) as *const sys::ublksrv_io_desc;
+                m.set(tag, unsafe { &*iod });
+            }
+            self.call_io_closure(ops, tag, e)
+        } else if res == sys::UBLK_IO_RES_NEED_GET_DATA as i32 {
+            let e = UblkCQE {
+                flags: e.flags | UBLK_IO_F_NEED_GET_DATA,
+                ..*e
+            };
+            self.call_io_closure(ops, tag, &e)?;
+
+            // the io command can't be completed before getting data
+            self.ios[tag as usize].flags = UBLK_IO_NEED_GET_DATA | UBLK_IO_FREE;
+            self.queue_io_cmd(tag as u16).map(|_| ())
         } else {
             /*
              * COMMIT_REQ will be completed immediately since no fetching
@@ -737,50 +2411,140 @@ impl UblkQueue<'_> {
              *
              * */
             self.ios[tag as usize].flags = UBLK_IO_FREE;
+            Ok(())
         }
     }
 
     #[inline(always)]
-    fn reap_one_event<F>(&mut self, ops: F) -> usize
+    fn reap_one_event<F>(&mut self, ops: F) -> Result<usize, UblkError>
     where
         F: FnMut(&mut UblkIOCtx) -> Result<i32, UblkError>,
     {
         let idx = self.cqes_idx;
         if idx >= self.cqes_cnt {
-            return 0;
+            return Ok(0);
         }
+        self.cqes_idx += 1;
 
-        let cqe = self.q_ring.completion().next().unwrap();
-        let ublk_cqe = UblkCQE(
-            &cqe,
-            if idx == 0 { UBLK_IO_F_FIRST } else { 0 }
+        let ublk_cqe = UblkCQE {
+            flags: if idx == 0 { UBLK_IO_F_FIRST } else { 0 }
                 | if idx + 1 == self.cqes_cnt {
                     UBLK_IO_F_LAST
                 } else {
                     0
                 },
-        );
-        self.handle_cqe(ops, &ublk_cqe);
+            ..self.cqes[idx]
+        };
+        self.handle_cqe(ops, &ublk_cqe)?;
 
         let tag = ublk_cqe.get_tag() as usize;
-        if self.ios[tag].flags & UBLK_IO_TO_QUEUE != 0 {
-            self.ios[tag].flags &= !UBLK_IO_TO_QUEUE;
-            self.queue_io_cmd(tag.try_into().unwrap());
+        if let Some(io) = self.ios.get_mut(tag) {
+            if io.flags & UBLK_IO_TO_QUEUE != 0 {
+                io.flags &= !UBLK_IO_TO_QUEUE;
+                self.queue_io_cmd(tag as u16)?;
+            }
         }
 
-        self.cqes_idx += 1;
-
-        1
+        Ok(1)
     }
 
     #[inline(always)]
     fn prep_reap_events(&mut self) -> usize {
-        self.cqes_cnt = self.q_ring.completion().len();
+        self.cqes.clear();
+        while let Some(cqe) = self.q_ring.completion().next() {
+            if cqe.user_data() == UBLK_COMP_CHAN_USER_DATA {
+                if let Some(comp) = self.comp.as_mut() {
+                    comp.armed = false;
+                    comp.ready = true;
+                }
+            } else if !self.transport.handle_cqe(cqe.user_data()) {
+                self.cqes.push(UblkCQE::new(cqe.user_data(), cqe.result()));
+            }
+        }
+        if let Some(t) = self.tgt_ring.as_mut() {
+            for cqe in t.ring.completion() {
+                t.inflight = t.inflight.saturating_sub(1);
+                self.cqes.push(UblkCQE::new(cqe.user_data(), cqe.result()));
+            }
+        }
+        self.transport.reap(&mut self.cqes);
+
+        self.cqes_cnt = self.cqes.len();
         self.cqes_idx = 0;
 
         self.cqes_cnt
     }
 
+    /// Read eventfd of completion channel, and the CQE is handled by
+    /// `prep_reap_events()`
+    fn arm_comp_chan(&mut self) -> Result<(), UblkError> {
+        let comp = match self.comp.as_mut() {
+            Some(c) if !c.armed => c,
+            _ => return Ok(()),
+        };
+        let sqe = opcode::Read::new(
+            types::Fd(comp.chan.efd.as_raw_fd()),
+            &mut *comp.buf as *mut u64 as *mut u8,
+            8,
+        )
+        .build()
+        .user_data(UBLK_COMP_CHAN_USER_DATA);
+
+        if unsafe { self.q_ring.submission().push(&sqe) }.is_err() {
+            self.q_ring
+                .submit()
+                .map_err(UblkError::UringSubmissionError)?;
+            unsafe { self.q_ring.submission().push(&sqe) }.map_err(UblkError::UringPushError)?;
+        }
+        comp.armed = true;
+        Ok(())
+    }
+
+    /// Return true if there is any target io queued to IOPOLL ring and
+    /// not reaped
+    #[inline(always)]
+    fn tgt_ring_busy(&self) -> bool {
+        self.tgt_ring.as_ref().is_some_and(|t| t.inflight > 0)
+    }
+
+    /// Submit queued target io to IOPOLL ring, and poll completed ones,
+    /// which are reaped by `prep_reap_events()`
+    fn poll_tgt_ring(&mut self) -> Result<(), UblkError> {
+        match self.tgt_ring.as_mut() {
+            Some(t) if t.inflight > 0 => t
+                .ring
+                .submit_and_wait(0)
+                .map(|_| ())
+                .map_err(UblkError::UringSubmissionError),
+            _ => Ok(()),
+        }
+    }
+
+    /// Commit IOs posted to completion channel
+    fn complete_posted(&mut self) -> Result<(), UblkError> {
+        let comps = match self.comp.as_mut() {
+            Some(c) if c.ready => {
+                c.ready = false;
+                c.chan.take()
+            }
+            _ => return Ok(()),
+        };
+
+        for (tag, res) in comps {
+            if tag as u32 >= self.q_depth {
+                error!(
+                    "dev {} queue {}: posted invalid tag {}",
+                    self.dev.dev_info.dev_id, self.q_id, tag
+                );
+                continue;
+            }
+            self.ios[tag as usize].complete(res);
+            self.ios[tag as usize].flags &= !UBLK_IO_TO_QUEUE;
+            self.queue_io_cmd(tag)?;
+        }
+        Ok(())
+    }
+
     /// Process the incoming IO from io_uring
     ///
     /// # Arguments:
@@ -807,6 +2571,10 @@ impl UblkQueue<'_> {
     /// Or called when target IO is completed by io_uring, when e.is_target_io()
     /// returns true.
     ///
+    /// Or called for choosing buffer of write request when UBLK_F_NEED_GET_DATA
+    /// is enabled, when `UBLK_IO_F_NEED_GET_DATA` is set in io.flags(), see
+    /// `UblkQueueHandler::prep_get_data()`.
+    ///
     /// In short, this method handles both io cmd and target io. IO command comes
     /// when its CQE is done from ublk driver, and target IO is done when its CQE
     /// is done from io_uring normal operations(FS, network, ...). Both share
@@ -820,8 +2588,6 @@ impl UblkQueue<'_> {
     where
         F: FnMut(&mut UblkIOCtx) -> Result<i32, UblkError>,
     {
-        let to_wait = if self.get_poll() { 0 } else { 1 };
-
         info!(
             "dev{}-q{}: to_submit {} inflight cmd {} stopping {}",
             self.dev.dev_info.dev_id,
@@ -831,19 +2597,30 @@ impl UblkQueue<'_> {
             (self.q_state & UBLK_QUEUE_STOPPING)
         );
 
-        if self.reap_one_event(ops) > 0 {
+        if self.reap_one_event(ops)? > 0 {
             return Ok(0);
         }
 
-        if self.queue_is_done() && self.q_ring.submission().is_empty() {
+        if self.queue_is_done() && self.q_ring.submission().is_empty() && !self.tgt_ring_busy() {
             return Err(UblkError::QueueIsDown("queue is done".to_string()));
         }
 
+        self.arm_comp_chan()?;
+        self.poll_tgt_ring()?;
+
+        // IOPOLL target ring is polled, so don't sleep on queue ring
+        let to_wait = if self.get_poll() || self.tgt_ring_busy() {
+            0
+        } else {
+            1
+        };
+        self.transport.prep_wait(&mut self.q_ring)?;
         let ret = self
             .q_ring
             .submit_and_wait(to_wait)
             .map_err(UblkError::UringSubmissionError)?;
         let reapped = self.prep_reap_events();
+        self.complete_posted()?;
 
         info!(
             "submit result {}, reapped {} stop {} idle {}",
@@ -871,7 +2648,14 @@ impl UblkQueue<'_> {
     {
         loop {
             match self.process_io(&mut ops) {
-                Err(_) => break,
+                Err(UblkError::QueueIsDown(_)) => break,
+                Err(r) => {
+                    error!(
+                        "dev {} queue {} exits on error {}",
+                        self.dev.dev_info.dev_id, self.q_id, r
+                    );
+                    break;
+                }
                 _ => continue,
             }
         }
@@ -5,54 +5,156 @@
 //! and introduction doc in
 //! `<https://github.com/ming1/ubdsrv/blob/master/doc/ublk_intro.pdf>`
 
-use log::error;
+use log::{error, trace};
 use std::alloc::{alloc, dealloc, Layout};
 use std::sync::Arc;
 
 pub mod ctrl;
+pub mod exec;
 pub mod io;
+#[cfg(feature = "mock-driver")]
+pub mod mock;
 pub mod sys;
+#[cfg(feature = "tokio")]
+pub mod tokio_io;
 
-type QueueFn = fn(&io::UblkQueueCtx, &mut io::UblkIOCtx) -> Result<i32, UblkError>;
+/// Errno carried by `UblkError`, which is negative as returned from
+/// ublk driver, and is decoded as the OS error message when displayed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct UblkErrno(pub i32);
+
+impl std::fmt::Display for UblkErrno {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", std::io::Error::from_raw_os_error(self.0.abs()))
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum UblkError {
-    #[error("failed to read the key file")]
+    #[error("failed to submit io_uring SQE")]
     UringSubmissionError(#[source] std::io::Error),
 
     #[error("failed to push SQE to uring")]
     UringPushError(#[from] io_uring::squeue::PushError),
 
-    #[error("io_uring IO failure")]
+    #[error("io_uring IO failure: {}", UblkErrno(*.0))]
     UringIOError(i32),
 
+    #[error("control command {op} of device {dev_id} failed: {errno}")]
+    CtrlCmdError {
+        op: &'static str,
+        dev_id: u32,
+        errno: UblkErrno,
+    },
+
+    #[error("io command {op} of device {dev_id} queue {q_id} tag {tag} failed: {errno}")]
+    IoCmdError {
+        op: &'static str,
+        dev_id: u32,
+        q_id: u16,
+        tag: u16,
+        errno: UblkErrno,
+    },
+
     #[error("json failure")]
     JsonError(#[from] serde_json::Error),
 
-    #[error("mmap failure")]
+    #[error("mmap failure: {0}")]
     MmapError(String),
 
-    #[error("queue down failure")]
+    #[error("queue down failure: {0}")]
     QueueIsDown(String),
 
+    #[error("feature {0} isn't supported by ublk driver")]
+    UnsupportedFeature(String),
+
+    #[error("device {dev_id} timed out waiting for state {state:?}")]
+    Timeout {
+        dev_id: u32,
+        state: ctrl::UblkDevState,
+    },
+
+    #[error("invalid device export: {0}")]
+    InvalidExport(String),
+
     #[error("other IO failure")]
     OtherIOError(#[source] std::io::Error),
 
-    #[error("other failure")]
+    #[error("other failure: {}", UblkErrno(*.0))]
     OtherError(i32),
 }
 
+impl UblkError {
+    /// Return the negative errno of this error, None if the error isn't
+    /// from any system call or ublk driver
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            UblkError::UringIOError(e) | UblkError::OtherError(e) => Some(*e),
+            UblkError::CtrlCmdError { errno, .. } | UblkError::IoCmdError { errno, .. } => {
+                Some(errno.0)
+            }
+            UblkError::UringSubmissionError(e) | UblkError::OtherIOError(e) => {
+                e.raw_os_error().map(|e| -e)
+            }
+            UblkError::UringPushError(_) => Some(-libc::EBUSY),
+            UblkError::UnsupportedFeature(_) => Some(-libc::EOPNOTSUPP),
+            UblkError::Timeout { .. } => Some(-libc::ETIMEDOUT),
+            UblkError::JsonError(_)
+            | UblkError::MmapError(_)
+            | UblkError::QueueIsDown(_)
+            | UblkError::InvalidExport(_) => None,
+        }
+    }
+}
+
+impl From<UblkError> for std::io::Error {
+    fn from(e: UblkError) -> Self {
+        match e {
+            UblkError::UringSubmissionError(e) | UblkError::OtherIOError(e) => e,
+            UblkError::JsonError(_) | UblkError::InvalidExport(_) => {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+            }
+            _ => {
+                let kind = match e.errno() {
+                    Some(errno) => std::io::Error::from_raw_os_error(-errno).kind(),
+                    None => std::io::ErrorKind::Other,
+                };
+                std::io::Error::new(kind, e)
+            }
+        }
+    }
+}
+
 pub const CDEV_PATH: &str = "/dev/ublkc";
 pub const BDEV_PATH: &str = "/dev/ublkb";
 
+/// If the in-process mock driver is used instead of ublk driver
+pub(crate) fn ublk_mock_enabled() -> bool {
+    #[cfg(feature = "mock-driver")]
+    return mock::enabled();
+
+    #[cfg(not(feature = "mock-driver"))]
+    false
+}
+
+/// Allocate buffer of `size` bytes aligned with `align`
+///
+/// Null is returned if the allocation fails, or `align` isn't power of 2
 pub fn ublk_alloc_buf(size: usize, align: usize) -> *mut u8 {
-    let layout = Layout::from_size_align(size, align).unwrap();
-    unsafe { alloc(layout) as *mut u8 }
+    match Layout::from_size_align(size, align) {
+        Ok(layout) if size > 0 => unsafe { alloc(layout) as *mut u8 },
+        _ => std::ptr::null_mut(),
+    }
 }
 
+/// Free buffer allocated by `ublk_alloc_buf()`, and null `ptr` is ignored
 pub fn ublk_dealloc_buf(ptr: *mut u8, size: usize, align: usize) {
-    let layout = Layout::from_size_align(size, align).unwrap();
-    unsafe { dealloc(ptr as *mut u8, layout) };
+    if ptr.is_null() {
+        return;
+    }
+    if let Ok(layout) = Layout::from_size_align(size, align) {
+        unsafe { dealloc(ptr as *mut u8, layout) };
+    }
 }
 
 /// Create queue thread handler(high level)
@@ -61,22 +163,20 @@ pub fn ublk_dealloc_buf(ptr: *mut u8, size: usize, align: usize) {
 ///
 /// * `ctrl`: UblkCtrl mut reference
 /// * `dev`: UblkDev reference, which is required for creating queue
-/// * `sq_depth`: uring submission queue depth
-/// * `cq_depth`: uring completion queue depth
-/// * `ring_flags`: uring flags
-/// *  `f`: closure for allocating queue trait object, and Arc() is
-/// required since the closure is called for multiple threads
+/// * `tgt`: target object, which creates IO handler for each queue
 ///
-/// # Return: Vectors for holding each queue thread JoinHandler and tid
+/// # Return: Vectors for holding each queue thread JoinHandler, or error
+/// if any queue can't be set up, and then the device is stopped and all
+/// queue threads are joined
 ///
 /// Note: This method is one high level API, and handles each queue in
 /// one dedicated thread. If your target won't take this approach, please
 /// don't use this API.
-pub fn create_queue_handler(
+pub fn create_queue_handler<T: io::UblkTarget + 'static>(
     ctrl: &mut ctrl::UblkCtrl,
     dev: &Arc<io::UblkDev>,
-    q_fn: QueueFn,
-) -> Vec<std::thread::JoinHandle<()>> {
+    tgt: &Arc<T>,
+) -> Result<Vec<std::thread::JoinHandle<()>>, UblkError> {
     use std::sync::mpsc;
 
     let mut q_threads = Vec::new();
@@ -84,12 +184,28 @@ pub fn create_queue_handler(
 
     let (tx, rx) = mpsc::channel();
 
+    // queue threads which are setup already won't exit until the device
+    // is stopped, so stop the device and join them on failure
+    let fail = |ctrl: &mut ctrl::UblkCtrl, q_threads: Vec<std::thread::JoinHandle<()>>, e| {
+        if let Err(r) = ctrl.stop() {
+            trace!("dev-{} stop failed {}", dev.dev_info.dev_id, r);
+        }
+        for qh in q_threads {
+            qh.join()
+                .unwrap_or_else(|_| error!("dev-{} join queue thread failed", dev.dev_info.dev_id));
+        }
+        Err(e)
+    };
+
     for q in 0..nr_queues {
         let _dev = Arc::clone(dev);
+        let _tgt = Arc::clone(tgt);
         let _tx = tx.clone();
 
         let mut affinity = ctrl::UblkQueueAffinity::new();
-        ctrl.get_queue_affinity(q as u32, &mut affinity).unwrap();
+        if let Err(e) = ctrl.get_queue_affinity(q as u32, &mut affinity) {
+            return fail(ctrl, q_threads, e);
+        }
 
         q_threads.push(std::thread::spawn(move || {
             //setup pthread affinity first, so that any allocation may
@@ -101,35 +217,143 @@ pub fn create_queue_handler(
                     affinity.addr() as *const libc::cpu_set_t,
                 );
             }
-            _tx.send((q, unsafe { libc::gettid() })).unwrap();
-
-            let mut queue = io::UblkQueue::new(q, &_dev).unwrap();
+            let res = io::UblkQueue::new(q, &_dev)
+                .and_then(|queue| Ok((_tgt.queue_handler(&_dev, q)?, queue)));
+            let (mut handler, mut queue) = match res {
+                Ok(r) => {
+                    let _ = _tx.send(Ok((q, unsafe { libc::gettid() })));
+                    r
+                }
+                Err(e) => {
+                    let _ = _tx.send(Err(e));
+                    return;
+                }
+            };
             let ctx = queue.make_queue_ctx();
-            let queue_closure = move |io_ctx: &mut io::UblkIOCtx| q_fn(&ctx, io_ctx);
+            let queue_closure = move |io_ctx: &mut io::UblkIOCtx| {
+                if io_ctx.is_tgt_io() {
+                    handler.handle_tgt_io(&ctx, io_ctx)
+                } else if (io_ctx.flags() & io::UBLK_IO_F_NEED_GET_DATA) != 0 {
+                    handler.prep_get_data(&ctx, io_ctx)
+                } else {
+                    handler.handle_io(&ctx, io_ctx)
+                }
+            };
 
             queue.wait_and_handle_io(queue_closure);
         }));
     }
 
+    drop(tx);
     for _q in 0..nr_queues {
-        let (qid, tid) = rx.recv().unwrap();
-        ctrl.configure_queue(dev, qid, tid);
+        let res = rx
+            .recv()
+            .map_err(|_| UblkError::QueueIsDown("queue thread exited".to_string()))
+            .and_then(|r| r);
+        match res {
+            Ok((qid, tid)) => ctrl.configure_queue(dev, qid, tid),
+            Err(e) => return fail(ctrl, q_threads, e),
+        }
     }
 
-    q_threads
+    Ok(q_threads)
+}
+
+/// Owned handle of one ublk device (high level)
+///
+/// Created by `UblkCtrlBuilder::create_device()`, and holds the control
+/// device, `UblkDev`, the target and all queue threads of this device.
+///
+/// The device is stopped and all queue threads are joined when the
+/// handle is dropped, then the target is deinitialized, and the control
+/// device is removed if it is added by this handle.
+pub struct UblkDevHandle {
+    // `dev` has to be dropped before `ctrl`, since deleting device waits
+    // until /dev/ublkcN is closed
+    dev: Arc<io::UblkDev>,
+    ctrl: ctrl::UblkCtrl,
+    tgt: Arc<dyn io::UblkTargetDeinit>,
+    q_threads: Vec<std::thread::JoinHandle<()>>,
+    started: bool,
+}
+
+impl UblkDevHandle {
+    pub(crate) fn new(
+        ctrl: ctrl::UblkCtrl,
+        dev: Arc<io::UblkDev>,
+        tgt: Arc<dyn io::UblkTargetDeinit>,
+        q_threads: Vec<std::thread::JoinHandle<()>>,
+    ) -> UblkDevHandle {
+        UblkDevHandle {
+            dev,
+            ctrl,
+            tgt,
+            q_threads,
+            started: false,
+        }
+    }
+
+    /// Return device id of this device
+    pub fn dev_id(&self) -> i32 {
+        self.dev.dev_info.dev_id as i32
+    }
+
+    /// Return control device of this device
+    pub fn ctrl(&mut self) -> &mut ctrl::UblkCtrl {
+        &mut self.ctrl
+    }
+
+    /// Return `UblkDev` of this device
+    pub fn dev(&self) -> &Arc<io::UblkDev> {
+        &self.dev
+    }
+
+    /// Start this device, then /dev/ublkbN is exposed
+    pub fn start(&mut self) -> Result<i32, UblkError> {
+        let res = self.ctrl.start_dev(&self.dev)?;
+
+        self.started = true;
+        Ok(res)
+    }
+
+    /// Stop this device, then all queue threads will exit
+    pub fn stop(&mut self) -> Result<i32, UblkError> {
+        self.started = false;
+        self.ctrl.stop_dev(&self.dev)
+    }
+
+    /// Wait until all queue threads exit
+    ///
+    /// Queue threads exit after the device is stopped or deleted.
+    pub fn wait(&mut self) {
+        for qh in self.q_threads.drain(..) {
+            qh.join().unwrap_or_else(|_| {
+                error!("dev-{} join queue thread failed", self.dev.dev_info.dev_id)
+            });
+        }
+    }
+}
+
+impl Drop for UblkDevHandle {
+    fn drop(&mut self) {
+        // queue threads won't exit until the device is stopped, and
+        // it is fine to stop one device which isn't started
+        if self.started || !self.q_threads.is_empty() {
+            if let Err(r) = self.stop() {
+                trace!("dev-{} stop failed {}", self.dev_id(), r);
+            }
+        }
+        self.wait();
+        self.tgt.deinit(&self.dev);
+    }
 }
 
 /// create ublk target device (high level)
 ///
 /// # Arguments:
 ///
-/// * `id`: device id, or let driver allocate one if -1 is passed
-/// * `nr_queues`: how many hw queues allocated for this device
-/// * `depth`: each hw queue's depth
-/// * `io_buf_bytes`: max buf size for each IO
-/// * `flags`: flags for setting ublk device
-/// * `tgt_fn`: closure for allocating Target Trait object
-/// * `q_fn`: closure for allocating Target Queue Trait object
+/// * `builder`: parameters for creating the device
+/// * `tgt`: target object
 /// * `worker_fn`: closure for running workerload
 ///
 /// # Return: JoinHandle of thread for running workload
@@ -137,42 +361,26 @@ pub fn create_queue_handler(
 /// Note: This method is one high level API, and handles each queue in
 /// one dedicated thread. If your target won't take this approach, please
 /// don't use this API.
-#[allow(clippy::too_many_arguments)]
 pub fn ublk_tgt_worker<T, W>(
-    name: String,
-    id: i32,
-    nr_queues: u32,
-    depth: u32,
-    io_buf_bytes: u32,
-    flags: u64,
-    for_add: bool,
-    dev_flags: u32,
-    tgt_fn: T,
-    q_fn: QueueFn,
+    builder: ctrl::UblkCtrlBuilder,
+    tgt: T,
     worker_fn: W,
 ) -> Result<std::thread::JoinHandle<()>, UblkError>
 where
-    T: FnOnce(&mut io::UblkDev) -> Result<serde_json::Value, UblkError>,
+    T: io::UblkTarget + 'static,
     W: Fn(i32) + Send + Sync + 'static,
 {
-    let mut ctrl = ctrl::UblkCtrl::new(id, nr_queues, depth, io_buf_bytes, flags, for_add).unwrap();
-    let ublk_dev = Arc::new(io::UblkDev::new(name, tgt_fn, &mut ctrl, dev_flags).unwrap());
-    let threads = create_queue_handler(&mut ctrl, &ublk_dev, q_fn);
+    let mut dev = builder.create_device(tgt)?;
 
-    ctrl.start_dev(&ublk_dev).unwrap();
+    dev.start()?;
 
-    let dev_id = ublk_dev.dev_info.dev_id as i32;
+    let dev_id = dev.dev_id();
     let worker_qh = std::thread::spawn(move || {
         worker_fn(dev_id);
     });
 
-    for qh in threads {
-        qh.join().unwrap_or_else(|_| {
-            eprintln!("dev-{} join queue thread failed", ublk_dev.dev_info.dev_id)
-        });
-    }
-
-    ctrl.stop_dev(&ublk_dev).unwrap();
+    dev.wait();
+    dev.stop()?;
 
     Ok(worker_qh)
 }
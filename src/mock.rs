@@ -0,0 +1,958 @@
+//! In-process mock of ublk driver
+//!
+//! Built with feature `mock-driver`. After `enable()` is called, control
+//! commands and io commands of this process are handled by the mock driver
+//! instead of ublk_drv, so control & queue state machines and target code
+//! can be tested on any linux box, without kernel module and root privilege.
+//!
+//! The mock driver emulates ADD/DEL/START/STOP/SET_PARAMS/GET_PARAMS, user
+//! recovery, the io descriptor buffer mmapped from /dev/ublkcN, and request
+//! delivery. Block device isn't exposed, and IO request is submitted by
+//! `submit_io()` instead. Daemon crash is emulated by `kill_daemon()`.
+
+use super::ctrl::{UblkCtrlTransport, UblkFlags, UBLK_CMD_GET_FEATURES};
+use super::io::{UblkCQE, UblkQueueTransport};
+use super::{sys, UblkError};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, OnceLock};
+
+/// Features supported by the mock driver
+pub const MOCK_FEATURES: UblkFlags = UblkFlags::URING_CMD_COMP_IN_TASK
+    .union(UblkFlags::NEED_GET_DATA)
+    .union(UblkFlags::USER_RECOVERY)
+    .union(UblkFlags::USER_RECOVERY_REISSUE)
+    .union(UblkFlags::UNPRIVILEGED_DEV)
+    .union(UblkFlags::CMD_IOCTL_ENCODE);
+
+/// Major numbers reported in `ublk_param_devt`
+const MOCK_CHAR_MAJOR: u32 = 511;
+const MOCK_DISK_MAJOR: u32 = 510;
+
+/// user_data of the PollAdd on queue's eventfd
+const MOCK_POLL_USER_DATA: u64 = u64::MAX;
+
+static MOCK_ENABLED: AtomicBool = AtomicBool::new(false);
+static MOCK_DRIVER: OnceLock<Mutex<MockDriver>> = OnceLock::new();
+
+/// Use the mock driver for all ublk devices of this process
+pub fn enable() {
+    MOCK_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// If the mock driver is enabled
+pub fn enabled() -> bool {
+    MOCK_ENABLED.load(Ordering::SeqCst)
+}
+
+thread_local! {
+    static MOCK_LEGACY: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Emulate old ublk driver for control rings created by current thread
+///
+/// Ioctl encoded control commands are failed with -EOPNOTSUPP, such as
+/// UBLK_U_CMD_GET_FEATURES, so libublk falls back to legacy commands.
+pub fn set_legacy(legacy: bool) {
+    MOCK_LEGACY.with(|l| l.set(legacy));
+}
+
+fn driver() -> MutexGuard<'static, MockDriver> {
+    MOCK_DRIVER
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Copy `val` to command buffer
+fn mock_copy_out<T>(buf: &mut [u8], val: &T) {
+    let len = buf.len().min(core::mem::size_of::<T>());
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(val as *const T as *const u8, buf.as_mut_ptr(), len);
+    }
+}
+
+/// Copy command buffer to `val`
+fn mock_copy_in<T>(buf: &[u8], val: &mut T) {
+    let len = buf.len().min(core::mem::size_of::<T>());
+
+    unsafe {
+        std::ptr::copy_nonoverlapping(buf.as_ptr(), val as *mut T as *mut u8, len);
+    }
+}
+
+/// IO request submitted to mock device
+struct MockReq {
+    iod: sys::ublksrv_io_desc,
+    data: Vec<u8>,
+    done: mpsc::Sender<(i32, Vec<u8>)>,
+}
+
+impl MockReq {
+    fn complete(self, res: i32) {
+        let _ = self.done.send((res, self.data));
+    }
+
+    /// Complete request committed by daemon, and data of READ is copied
+    /// from the io buffer
+    fn commit(mut self, buf_addr: u64, res: i32) {
+        if (self.iod.op_flags & 0xff) == sys::UBLK_IO_OP_READ && res > 0 {
+            let len = (res as usize).min(self.data.len());
+            let buf = unsafe { std::slice::from_raw_parts(buf_addr as *const u8, len) };
+
+            self.data[..len].copy_from_slice(buf);
+        }
+        self.complete(res);
+    }
+}
+
+/// Handle of IO request submitted by `submit_io()`
+pub struct MockIo(mpsc::Receiver<(i32, Vec<u8>)>);
+
+impl MockIo {
+    /// Wait until the request is completed
+    ///
+    /// # Return: (result, data), and data is read from device for
+    /// UBLK_IO_OP_READ. -EIO is returned if the device is deleted
+    pub fn wait(self) -> (i32, Vec<u8>) {
+        self.0.recv().unwrap_or((-libc::EIO, Vec::new()))
+    }
+
+    /// Wait until the request is completed or `timeout` expires
+    ///
+    /// # Return: None if the request isn't completed in `timeout`
+    pub fn wait_timeout(&self, timeout: std::time::Duration) -> Option<(i32, Vec<u8>)> {
+        match self.0.recv_timeout(timeout) {
+            Ok(r) => Some(r),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => Some((-libc::EIO, Vec::new())),
+        }
+    }
+}
+
+/// Completions of io command from mock driver to one queue, or of control
+/// command to one control transport, and the waiter is woken up by eventfd
+struct MockLink {
+    cqes: Mutex<Vec<(u64, i32)>>,
+    efd: fs::File,
+}
+
+impl MockLink {
+    fn new() -> Result<MockLink, UblkError> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+        }
+
+        Ok(MockLink {
+            cqes: Mutex::new(Vec::new()),
+            efd: unsafe { fs::File::from_raw_fd(fd) },
+        })
+    }
+
+    fn complete(&self, user_data: u64, res: i32) {
+        self.cqes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push((user_data, res));
+        let _ = (&self.efd).write(&1_u64.to_ne_bytes());
+    }
+}
+
+#[derive(Default)]
+struct MockTag {
+    /// user_data of FETCH_REQ or COMMIT_AND_FETCH_REQ which waits for request
+    fetch: Option<u64>,
+
+    /// io buffer of this tag
+    buf_addr: u64,
+
+    /// request which is being handled by daemon
+    req: Option<MockReq>,
+
+    /// data of the write request isn't retrieved by NEED_GET_DATA yet
+    need_data: bool,
+}
+
+#[derive(Default)]
+struct MockQueueState {
+    /// link of the daemon's queue, None if the daemon is gone
+    link: Option<Arc<MockLink>>,
+    tags: Vec<MockTag>,
+    pending: VecDeque<MockReq>,
+    aborting: bool,
+}
+
+type MockCtrlCqes = Arc<MockLink>;
+
+struct MockDev {
+    info: sys::ublksrv_ctrl_dev_info,
+    params: sys::ublk_params,
+
+    /// emulates /dev/ublkcN, which provides io descriptor buffer
+    cdev: fs::File,
+    queues: Vec<MockQueueState>,
+
+    /// START_DEV or END_USER_RECOVERY which waits for all io commands
+    /// fetched: (user_data, pid, completion queue)
+    start: Option<(u64, i32, MockCtrlCqes)>,
+    recovering: bool,
+
+    /// fd holding lock of the daemon's exported json file, which is
+    /// released when the daemon is killed
+    export_lock: Option<RawFd>,
+}
+
+impl MockDev {
+    fn new(info: sys::ublksrv_ctrl_dev_info) -> Result<MockDev, UblkError> {
+        let name = std::ffi::CString::new(format!("ublkc{}", info.dev_id))
+            .map_err(|_| UblkError::OtherError(-libc::EINVAL))?;
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(UblkError::OtherIOError(std::io::Error::last_os_error()));
+        }
+        let cdev = unsafe { fs::File::from_raw_fd(fd) };
+        let size =
+            sys::UBLKSRV_CMD_BUF_OFFSET as u64 + info.nr_hw_queues as u64 * Self::queue_buf_sz();
+        cdev.set_len(size).map_err(UblkError::OtherIOError)?;
+
+        let queues = (0..info.nr_hw_queues)
+            .map(|_| MockQueueState {
+                tags: (0..info.queue_depth).map(|_| MockTag::default()).collect(),
+                ..Default::default()
+            })
+            .collect();
+
+        Ok(MockDev {
+            info,
+            params: Default::default(),
+            cdev,
+            queues,
+            start: None,
+            recovering: false,
+            export_lock: None,
+        })
+    }
+
+    #[inline]
+    fn queue_buf_sz() -> u64 {
+        sys::UBLK_MAX_QUEUE_DEPTH as u64 * core::mem::size_of::<sys::ublksrv_io_desc>() as u64
+    }
+
+    fn flags(&self) -> UblkFlags {
+        UblkFlags::from_bits_retain(self.info.flags)
+    }
+
+    fn set_state(&mut self, state: u32) {
+        self.info.state = state as u16;
+    }
+
+    fn is_live(&self) -> bool {
+        self.info.state == sys::UBLK_S_DEV_LIVE as u16
+    }
+
+    /// All io commands of the daemon are fetched
+    fn is_ready(&self) -> bool {
+        self.queues
+            .iter()
+            .all(|q| q.link.is_some() && q.tags.iter().all(|t| t.fetch.is_some()))
+    }
+
+    /// Complete the waiting START_DEV or END_USER_RECOVERY if the daemon
+    /// is ready
+    fn try_start(&mut self) {
+        if self.start.is_none() || !self.is_ready() {
+            return;
+        }
+
+        if let Some((user_data, pid, cqes)) = self.start.take() {
+            self.set_state(sys::UBLK_S_DEV_LIVE);
+            self.info.ublksrv_pid = pid;
+            self.recovering = false;
+            cqes.complete(user_data, 0);
+        }
+
+        for q in 0..self.queues.len() {
+            self.deliver(q);
+        }
+    }
+
+    /// Deliver pending requests of queue `q_id` to tags which wait for
+    /// request
+    fn deliver(&mut self, q_id: usize) {
+        if !self.is_live() {
+            return;
+        }
+
+        let q = &mut self.queues[q_id];
+        let link = match &q.link {
+            Some(l) => l.clone(),
+            None => return,
+        };
+
+        while !q.pending.is_empty() {
+            let tag = match q.tags.iter().position(|t| t.fetch.is_some()) {
+                Some(tag) => tag,
+                None => break,
+            };
+            let req = match q.pending.pop_front() {
+                Some(req) => req,
+                None => break,
+            };
+            let t = &mut q.tags[tag];
+            let iod = sys::ublksrv_io_desc {
+                addr: t.buf_addr,
+                ..req.iod
+            };
+            let mut buf = [0_u8; core::mem::size_of::<sys::ublksrv_io_desc>()];
+            let off = sys::UBLKSRV_CMD_BUF_OFFSET as u64
+                + q_id as u64 * Self::queue_buf_sz()
+                + (tag * buf.len()) as u64;
+
+            mock_copy_out(&mut buf, &iod);
+            if self.cdev.write_at(&buf, off).is_err() {
+                req.complete(-libc::EIO);
+                continue;
+            }
+
+            let mut res = sys::UBLK_IO_RES_OK as i32;
+            if (iod.op_flags & 0xff) == sys::UBLK_IO_OP_WRITE
+                && self.info.flags & sys::UBLK_F_NEED_GET_DATA as u64 != 0
+            {
+                t.need_data = true;
+                res = sys::UBLK_IO_RES_NEED_GET_DATA as i32;
+            } else if (iod.op_flags & 0xff) == sys::UBLK_IO_OP_WRITE {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        req.data.as_ptr(),
+                        t.buf_addr as *mut u8,
+                        req.data.len(),
+                    );
+                }
+            }
+            t.req = Some(req);
+            if let Some(user_data) = t.fetch.take() {
+                link.complete(user_data, res);
+            }
+        }
+    }
+
+    /// Abort all io commands and pending requests, and queue will exit
+    /// after all io commands are completed
+    fn stop(&mut self) {
+        self.set_state(sys::UBLK_S_DEV_DEAD);
+        if let Some((user_data, _, cqes)) = self.start.take() {
+            cqes.complete(user_data, -libc::EINTR);
+        }
+
+        for q in &mut self.queues {
+            q.aborting = true;
+            for req in q.pending.drain(..) {
+                req.complete(-libc::EIO);
+            }
+            if let Some(link) = &q.link {
+                for t in &mut q.tags {
+                    if let Some(user_data) = t.fetch.take() {
+                        link.complete(user_data, sys::UBLK_IO_RES_ABORT);
+                    }
+                }
+            }
+        }
+    }
+
+    fn ctrl_cmd(
+        &mut self,
+        op: u32,
+        cmd: &sys::ublksrv_ctrl_cmd,
+        buf: &mut [u8],
+        user_data: u64,
+        cqes: &MockCtrlCqes,
+    ) -> Option<i32> {
+        let res = match op {
+            sys::UBLK_CMD_GET_DEV_INFO | sys::UBLK_CMD_GET_DEV_INFO2 => {
+                mock_copy_out(buf, &self.info);
+                0
+            }
+            sys::UBLK_CMD_SET_PARAMS => {
+                if self.is_live() {
+                    -libc::EACCES
+                } else {
+                    mock_copy_in(buf, &mut self.params);
+                    0
+                }
+            }
+            sys::UBLK_CMD_GET_PARAMS => {
+                let mut p = self.params;
+
+                p.devt = sys::ublk_param_devt {
+                    char_major: MOCK_CHAR_MAJOR,
+                    char_minor: self.info.dev_id,
+                    ..Default::default()
+                };
+                if self.is_live() {
+                    p.devt.disk_major = MOCK_DISK_MAJOR;
+                    p.devt.disk_minor = self.info.dev_id;
+                }
+                p.types |= sys::UBLK_PARAM_TYPE_DEVT;
+                mock_copy_out(buf, &p);
+                0
+            }
+            sys::UBLK_CMD_GET_QUEUE_AFFINITY => {
+                if cmd.data[0] >= self.info.nr_hw_queues as u64 {
+                    -libc::EINVAL
+                } else {
+                    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+
+                    unsafe {
+                        libc::sched_getaffinity(
+                            0,
+                            core::mem::size_of::<libc::cpu_set_t>(),
+                            &mut set,
+                        )
+                    };
+                    mock_copy_out(buf, &set);
+                    0
+                }
+            }
+            sys::UBLK_CMD_START_DEV => {
+                if self.info.state != sys::UBLK_S_DEV_DEAD as u16 || self.start.is_some() {
+                    -libc::EBUSY
+                } else {
+                    self.start = Some((user_data, cmd.data[0] as i32, cqes.clone()));
+                    self.try_start();
+                    return None;
+                }
+            }
+            sys::UBLK_CMD_STOP_DEV => {
+                self.stop();
+                0
+            }
+            sys::UBLK_CMD_START_USER_RECOVERY => {
+                if !self.flags().contains(UblkFlags::USER_RECOVERY) {
+                    -libc::EINVAL
+                } else if self.info.state != sys::UBLK_S_DEV_QUIESCED as u16 {
+                    -libc::EBUSY
+                } else {
+                    self.recovering = true;
+                    0
+                }
+            }
+            sys::UBLK_CMD_END_USER_RECOVERY => {
+                if !self.recovering || self.start.is_some() {
+                    -libc::EINVAL
+                } else {
+                    self.start = Some((user_data, cmd.data[0] as i32, cqes.clone()));
+                    self.try_start();
+                    return None;
+                }
+            }
+            _ => -libc::EOPNOTSUPP,
+        };
+
+        Some(res)
+    }
+}
+
+#[derive(Default)]
+struct MockDriver {
+    devs: BTreeMap<u32, MockDev>,
+}
+
+impl MockDriver {
+    /// Add one device, and UBLK_F_CMD_IOCTL_ENCODE is always reported if
+    /// the command is ioctl encoded, same with ublk driver
+    fn add_dev(&mut self, cmd: &sys::ublksrv_ctrl_cmd, buf: &mut [u8], encoded: bool) -> i32 {
+        let mut info = sys::ublksrv_ctrl_dev_info::default();
+        mock_copy_in(buf, &mut info);
+
+        if info.nr_hw_queues == 0
+            || info.nr_hw_queues as u32 > sys::UBLK_MAX_NR_QUEUES
+            || info.queue_depth == 0
+            || info.queue_depth as u32 > sys::UBLK_MAX_QUEUE_DEPTH
+        {
+            return -libc::EINVAL;
+        }
+
+        let id = if cmd.dev_id == u32::MAX {
+            match (0..).find(|id| !self.devs.contains_key(id)) {
+                Some(id) => id,
+                None => return -libc::ENOSPC,
+            }
+        } else if self.devs.contains_key(&cmd.dev_id) {
+            return -libc::EEXIST;
+        } else {
+            cmd.dev_id
+        };
+
+        info.dev_id = id;
+        info.state = sys::UBLK_S_DEV_DEAD as u16;
+        info.flags &= MOCK_FEATURES.bits();
+        if encoded {
+            info.flags |= UblkFlags::CMD_IOCTL_ENCODE.bits();
+        } else {
+            info.flags &= !UblkFlags::CMD_IOCTL_ENCODE.bits();
+        }
+        info.owner_uid = unsafe { libc::getuid() };
+        info.owner_gid = unsafe { libc::getgid() };
+
+        match MockDev::new(info) {
+            Ok(dev) => {
+                mock_copy_out(buf, &info);
+                self.devs.insert(id, dev);
+                0
+            }
+            Err(_) => -libc::ENOMEM,
+        }
+    }
+
+    fn ctrl_cmd(
+        &mut self,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_ctrl_cmd,
+        user_data: u64,
+        cqes: &MockCtrlCqes,
+    ) -> Option<i32> {
+        //legacy opcode is the same with ioctl nr of the encoded opcode, and
+        //command buffer starts with char device path if dev_path_len isn't 0
+        let op = cmd_op & 0xff;
+        let off = cmd.dev_path_len as usize;
+        let buf: &mut [u8] = if cmd.addr != 0 && cmd.len as usize > off {
+            unsafe {
+                std::slice::from_raw_parts_mut(
+                    (cmd.addr as usize + off) as *mut u8,
+                    cmd.len as usize - off,
+                )
+            }
+        } else {
+            &mut []
+        };
+
+        match op {
+            UBLK_CMD_GET_FEATURES => {
+                mock_copy_out(buf, &MOCK_FEATURES.bits());
+                Some(0)
+            }
+            sys::UBLK_CMD_ADD_DEV => Some(self.add_dev(cmd, buf, op != cmd_op)),
+            sys::UBLK_CMD_DEL_DEV => match self.devs.remove(&cmd.dev_id) {
+                Some(mut dev) => {
+                    dev.stop();
+                    Some(0)
+                }
+                None => Some(-libc::ENODEV),
+            },
+            _ => match self.devs.get_mut(&cmd.dev_id) {
+                Some(dev) => dev.ctrl_cmd(op, cmd, buf, user_data, cqes),
+                None => Some(-libc::ENODEV),
+            },
+        }
+    }
+
+    /// Attach daemon's queue, and the queue's io commands are completed
+    /// via `link`
+    fn attach_queue(&mut self, dev_id: u32, q_id: u16, link: &Arc<MockLink>) -> i32 {
+        let dev = match self.devs.get_mut(&dev_id) {
+            Some(dev) => dev,
+            None => return -libc::ENODEV,
+        };
+        let q = match dev.queues.get_mut(q_id as usize) {
+            Some(q) => q,
+            None => return -libc::EINVAL,
+        };
+
+        for t in &mut q.tags {
+            if let Some(req) = t.req.take() {
+                req.complete(-libc::EIO);
+            }
+            t.fetch = None;
+            t.need_data = false;
+        }
+        q.link = Some(link.clone());
+        q.aborting = false;
+        0
+    }
+
+    fn io_cmd(
+        &mut self,
+        dev_id: u32,
+        q_id: u16,
+        link: &Arc<MockLink>,
+        op: u32,
+        cmd: &sys::ublksrv_io_cmd,
+        user_data: u64,
+    ) {
+        let dev = match self.devs.get_mut(&dev_id) {
+            Some(dev) => dev,
+            None => return link.complete(user_data, sys::UBLK_IO_RES_ABORT),
+        };
+        let q = &mut dev.queues[q_id as usize];
+
+        //io command from daemon which is gone is never completed
+        if !q.link.as_ref().is_some_and(|l| Arc::ptr_eq(l, link)) {
+            return;
+        }
+
+        let t = match q.tags.get_mut(cmd.tag as usize) {
+            Some(t) => t,
+            None => return link.complete(user_data, -libc::EINVAL),
+        };
+        match op {
+            sys::UBLK_IO_FETCH_REQ => {
+                if t.fetch.is_some() || t.req.is_some() {
+                    return link.complete(user_data, -libc::EINVAL);
+                }
+            }
+            sys::UBLK_IO_COMMIT_AND_FETCH_REQ => match t.req.take() {
+                Some(req) => {
+                    t.need_data = false;
+                    req.commit(cmd.addr, cmd.result)
+                }
+                None => return link.complete(user_data, -libc::EINVAL),
+            },
+            sys::UBLK_IO_NEED_GET_DATA => {
+                return match &t.req {
+                    Some(req) if t.need_data && cmd.addr != 0 => {
+                        unsafe {
+                            std::ptr::copy_nonoverlapping(
+                                req.data.as_ptr(),
+                                cmd.addr as *mut u8,
+                                req.data.len(),
+                            );
+                        }
+                        t.need_data = false;
+                        link.complete(user_data, sys::UBLK_IO_RES_OK as i32)
+                    }
+                    _ => link.complete(user_data, -libc::EINVAL),
+                };
+            }
+            _ => return link.complete(user_data, -libc::EOPNOTSUPP),
+        }
+
+        if q.aborting {
+            return link.complete(user_data, sys::UBLK_IO_RES_ABORT);
+        }
+
+        t.buf_addr = cmd.addr;
+        t.fetch = Some(user_data);
+        dev.try_start();
+        dev.deliver(q_id as usize);
+    }
+}
+
+/// Control command transport of the mock driver
+///
+/// Control command is completed via `MockLink` too, whose eventfd is
+/// signalled for each completed command.
+pub(crate) struct MockCtrl {
+    cqes: MockCtrlCqes,
+
+    /// old driver which only supports legacy commands, see `set_legacy()`
+    legacy: bool,
+}
+
+impl MockCtrl {
+    pub(crate) fn new() -> Result<MockCtrl, UblkError> {
+        Ok(MockCtrl {
+            cqes: Arc::new(MockLink::new()?),
+            legacy: MOCK_LEGACY.with(|l| l.get()),
+        })
+    }
+}
+
+impl UblkCtrlTransport for MockCtrl {
+    fn submit(
+        &mut self,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_ctrl_cmd,
+        user_data: u64,
+    ) -> Result<(), UblkError> {
+        if self.legacy && (cmd_op & 0xff) != cmd_op {
+            self.cqes.complete(user_data, -libc::EOPNOTSUPP);
+            return Ok(());
+        }
+
+        //START_DEV is completed after all queues are ready
+        if let Some(res) = driver().ctrl_cmd(cmd_op, cmd, user_data, &self.cqes) {
+            self.cqes.complete(user_data, res);
+        }
+        Ok(())
+    }
+
+    fn reap(&mut self) -> Option<(u64, i32)> {
+        let mut cqes = self.cqes.cqes.lock().unwrap_or_else(|e| e.into_inner());
+
+        if cqes.is_empty() {
+            None
+        } else {
+            Some(cqes.remove(0))
+        }
+    }
+
+    fn event_fd(&self) -> RawFd {
+        self.cqes.efd.as_raw_fd()
+    }
+}
+
+/// Io command transport of the mock driver
+///
+/// Io command is completed via `MockLink`, and the queue's io_uring is
+/// woken up by polling the link's eventfd.
+pub(crate) struct MockQueue {
+    dev_id: u32,
+    q_id: u16,
+    link: Arc<MockLink>,
+    armed: bool,
+}
+
+impl MockQueue {
+    pub(crate) fn new(dev_id: u32, q_id: u16) -> Result<MockQueue, UblkError> {
+        let link = Arc::new(MockLink::new()?);
+        let res = driver().attach_queue(dev_id, q_id, &link);
+
+        if res < 0 {
+            return Err(UblkError::OtherError(res));
+        }
+        Ok(MockQueue {
+            dev_id,
+            q_id,
+            link,
+            armed: false,
+        })
+    }
+}
+
+impl UblkQueueTransport for MockQueue {
+    fn queue_io_cmd(
+        &mut self,
+        _ring: &mut IoUring<squeue::Entry>,
+        cmd_op: u32,
+        cmd: &sys::ublksrv_io_cmd,
+        user_data: u64,
+    ) -> Result<(), UblkError> {
+        driver().io_cmd(
+            self.dev_id,
+            self.q_id,
+            &self.link,
+            cmd_op & 0xff,
+            cmd,
+            user_data,
+        );
+        Ok(())
+    }
+
+    fn prep_wait(&mut self, ring: &mut IoUring<squeue::Entry>) -> Result<(), UblkError> {
+        if self.armed {
+            return Ok(());
+        }
+
+        let sqe = opcode::PollAdd::new(types::Fd(self.link.efd.as_raw_fd()), libc::POLLIN as _)
+            .build()
+            .user_data(MOCK_POLL_USER_DATA);
+
+        // SQ may be filled up by target io
+        if unsafe { ring.submission().push(&sqe) }.is_err() {
+            ring.submit().map_err(UblkError::UringSubmissionError)?;
+            unsafe { ring.submission().push(&sqe) }.map_err(UblkError::UringPushError)?;
+        }
+        self.armed = true;
+        Ok(())
+    }
+
+    fn handle_cqe(&mut self, user_data: u64) -> bool {
+        if user_data != MOCK_POLL_USER_DATA {
+            return false;
+        }
+
+        let mut buf = [0_u8; 8];
+        let _ = (&self.link.efd).read(&mut buf);
+        self.armed = false;
+        true
+    }
+
+    fn reap(&mut self, cqes: &mut Vec<UblkCQE>) {
+        let mut done = self.link.cqes.lock().unwrap_or_else(|e| e.into_inner());
+
+        for (user_data, res) in done.drain(..) {
+            cqes.push(UblkCQE::new(user_data, res));
+        }
+    }
+}
+
+/// Ids of all devices added to the mock driver
+pub(crate) fn dev_ids() -> Vec<u32> {
+    driver().devs.keys().copied().collect()
+}
+
+/// Record fd holding lock of the device's exported json file
+pub(crate) fn set_export_lock(dev_id: u32, fd: Option<RawFd>) {
+    if let Some(dev) = driver().devs.get_mut(&dev_id) {
+        dev.export_lock = fd;
+    }
+}
+
+/// Open the emulated /dev/ublkcN
+pub(crate) fn open_cdev(dev_id: u32) -> Result<fs::File, UblkError> {
+    match driver().devs.get(&dev_id) {
+        Some(dev) => dev.cdev.try_clone().map_err(UblkError::OtherIOError),
+        None => Err(UblkError::OtherIOError(std::io::Error::from_raw_os_error(
+            libc::ENOENT,
+        ))),
+    }
+}
+
+/// Submit one IO request to mock device
+///
+/// # Arguments:
+///
+/// * `dev_id`: device id
+/// * `q_id`: queue which the request is delivered to
+/// * `op`: UBLK_IO_OP_*
+/// * `start_sector`: start sector of this IO
+/// * `nr_sectors`: how many sectors of this IO
+/// * `data`: data written to device for UBLK_IO_OP_WRITE, its length has to
+///    be `nr_sectors << 9`; ignored for other ops
+///
+/// The request fails with -EIO if the device isn't live, and it is queued
+/// until the daemon is recovered if the device is quiesced.
+pub fn submit_io(
+    dev_id: u32,
+    q_id: u16,
+    op: u32,
+    start_sector: u64,
+    nr_sectors: u32,
+    data: &[u8],
+) -> Result<MockIo, UblkError> {
+    __submit_io(dev_id, q_id, op, start_sector, nr_sectors, data, true)
+}
+
+/// Submit one IO request without limiting its size by `max_io_buf_bytes`
+///
+/// Simulates misbehaving driver, and arguments are same with `submit_io()`.
+/// UBLK_IO_OP_WRITE isn't allowed, since its data is copied to io buffer
+/// before the daemon sees the request.
+pub fn submit_io_unchecked(
+    dev_id: u32,
+    q_id: u16,
+    op: u32,
+    start_sector: u64,
+    nr_sectors: u32,
+    data: &[u8],
+) -> Result<MockIo, UblkError> {
+    if (op & 0xff) == sys::UBLK_IO_OP_WRITE {
+        return Err(UblkError::OtherError(-libc::EINVAL));
+    }
+    __submit_io(dev_id, q_id, op, start_sector, nr_sectors, data, false)
+}
+
+fn __submit_io(
+    dev_id: u32,
+    q_id: u16,
+    op: u32,
+    start_sector: u64,
+    nr_sectors: u32,
+    data: &[u8],
+    check: bool,
+) -> Result<MockIo, UblkError> {
+    let mut drv = driver();
+    let dev = drv
+        .devs
+        .get_mut(&dev_id)
+        .ok_or(UblkError::OtherError(-libc::ENODEV))?;
+    let bytes = (nr_sectors as usize) << 9;
+
+    if q_id >= dev.info.nr_hw_queues || (check && bytes > dev.info.max_io_buf_bytes as usize) {
+        return Err(UblkError::OtherError(-libc::EINVAL));
+    }
+
+    let data = match op {
+        sys::UBLK_IO_OP_WRITE if data.len() != bytes => {
+            return Err(UblkError::OtherError(-libc::EINVAL));
+        }
+        sys::UBLK_IO_OP_WRITE => data.to_vec(),
+        sys::UBLK_IO_OP_READ => vec![0_u8; bytes],
+        _ => Vec::new(),
+    };
+    let (tx, rx) = mpsc::channel();
+    let req = MockReq {
+        iod: sys::ublksrv_io_desc {
+            op_flags: op,
+            nr_sectors,
+            start_sector,
+            addr: 0,
+        },
+        data,
+        done: tx,
+    };
+
+    if dev.is_live() || dev.info.state == sys::UBLK_S_DEV_QUIESCED as u16 {
+        dev.queues[q_id as usize].pending.push_back(req);
+        dev.deliver(q_id as usize);
+    } else {
+        req.complete(-libc::EIO);
+    }
+    Ok(MockIo(rx))
+}
+
+/// Emulate crash of the device's daemon
+///
+/// The daemon's queues never see any io command completion again, so
+/// they look hung, and the caller should leak(`std::mem::forget`) the old
+/// `UblkDevHandle` or `UblkCtrl` instead of dropping it. Lock of exported
+/// json file is released as if the daemon exited, so the device can be
+/// recovered in the same process.
+///
+/// If the device is created with UBLK_F_USER_RECOVERY, it becomes
+/// quiesced, and requests in flight are failed, or re-issued to the
+/// recovered daemon with UBLK_F_USER_RECOVERY_REISSUE. Otherwise the
+/// device is dead and all requests are failed.
+pub fn kill_daemon(dev_id: u32) -> Result<(), UblkError> {
+    let mut drv = driver();
+    let dev = drv
+        .devs
+        .get_mut(&dev_id)
+        .ok_or(UblkError::OtherError(-libc::ENODEV))?;
+    let recover = dev.flags().contains(UblkFlags::USER_RECOVERY) && dev.is_live();
+    let reissue = recover && dev.flags().contains(UblkFlags::USER_RECOVERY_REISSUE);
+
+    dev.start = None;
+    for q in &mut dev.queues {
+        let mut inflight = Vec::new();
+
+        q.link = None;
+        for t in &mut q.tags {
+            t.fetch = None;
+            t.need_data = false;
+            if let Some(req) = t.req.take() {
+                inflight.push(req);
+            }
+        }
+        for req in inflight.into_iter().rev() {
+            if reissue {
+                q.pending.push_front(req);
+            } else {
+                req.complete(-libc::EIO);
+            }
+        }
+        if !recover {
+            for req in q.pending.drain(..) {
+                req.complete(-libc::EIO);
+            }
+        }
+    }
+
+    if let Some(fd) = dev.export_lock.take() {
+        unsafe { libc::flock(fd, libc::LOCK_UN) };
+    }
+    dev.info.ublksrv_pid = -1;
+    dev.set_state(if recover {
+        sys::UBLK_S_DEV_QUIESCED
+    } else {
+        sys::UBLK_S_DEV_DEAD
+    });
+    Ok(())
+}
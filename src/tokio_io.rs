@@ -0,0 +1,243 @@
+//! Offload ublk IO handling to tokio runtime
+//!
+//! Enabled by feature `tokio`. Opt-in by returning `UblkTokioHandler` from
+//! `UblkTarget::queue_handler()`, then each incoming io command is moved
+//! into one task spawned on the tokio runtime, which is represented by
+//! `UblkTokioIo`.
+//!
+//! Tasks run in tokio worker threads, and the io command is completed by
+//! `UblkTokioIo::complete()`, which posts the completion to the queue's
+//! completion channel, then all posted IOs are committed in the queue
+//! thread in batch. So the device has to be created with
+//! `UBLK_DEV_F_COMP_CHAN`.
+
+use super::io::{
+    UblkDev, UblkIOCtx, UblkIoDesc, UblkQueueCompleter, UblkQueueCtx, UblkQueueHandler,
+};
+use super::{sys, UblkError};
+use log::{error, trace};
+use std::future::Future;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// Counter of IOs owned by tokio tasks
+#[derive(Default)]
+struct UblkTokioOutstanding {
+    nr: Mutex<usize>,
+    idle: Condvar,
+}
+
+impl UblkTokioOutstanding {
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        self.nr.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn get(&self) {
+        *self.lock() += 1;
+    }
+
+    fn put(&self) {
+        let mut nr = self.lock();
+
+        *nr -= 1;
+        if *nr == 0 {
+            self.idle.notify_all();
+        }
+    }
+
+    fn wait_idle(&self) {
+        let mut nr = self.lock();
+
+        while *nr != 0 {
+            nr = self.idle.wait(nr).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// One ublk IO handled by tokio task
+///
+/// Owns the IO buffer until the IO is completed, so it is safe to access
+/// the buffer from any thread. The IO is completed with `-EIO` if it is
+/// dropped without calling `complete()`.
+pub struct UblkTokioIo {
+    tag: u16,
+    q_id: u16,
+    iod: sys::ublksrv_io_desc,
+    buf: *mut u8,
+    buf_len: usize,
+    res: i32,
+    completer: UblkQueueCompleter,
+    outstanding: Arc<UblkTokioOutstanding>,
+}
+
+// The io buffer is exclusively owned by this IO until it is completed
+unsafe impl Send for UblkTokioIo {}
+
+impl UblkTokioIo {
+    /// Return io tag
+    pub fn tag(&self) -> u16 {
+        self.tag
+    }
+
+    /// Return queue id
+    pub fn q_id(&self) -> u16 {
+        self.q_id
+    }
+
+    /// Return copy of io descriptor filled by ublk driver
+    pub fn iod(&self) -> &sys::ublksrv_io_desc {
+        &self.iod
+    }
+
+    /// Return copy of io descriptor, see `UblkIoDesc`
+    pub fn io_desc(&self) -> UblkIoDesc {
+        UblkIoDesc::from(self.iod)
+    }
+
+    /// Return io buffer of this IO, which covers bytes of the request
+    ///
+    /// Same with `UblkIOCtx::io_buf()`, it is empty for request without
+    /// data, such as discard, or if there isn't queue's own io buffer.
+    pub fn buf(&self) -> &[u8] {
+        if self.buf.is_null() {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.buf, self.buf_len) }
+    }
+
+    /// Return mutable io buffer of this IO, which covers bytes of the request
+    pub fn buf_mut(&mut self) -> &mut [u8] {
+        if self.buf.is_null() {
+            return &mut [];
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.buf, self.buf_len) }
+    }
+
+    /// Complete this IO
+    ///
+    /// # Arguments:
+    ///
+    /// * `res`: bytes handled or negative errno
+    ///
+    /// The IO is committed to ublk driver in the queue thread later.
+    pub fn complete(mut self, res: i32) {
+        self.res = res;
+    }
+}
+
+impl Drop for UblkTokioIo {
+    fn drop(&mut self) {
+        if let Err(e) = self.completer.complete(self.tag, self.res) {
+            error!(
+                "queue {} tag {}: post completion failed {}",
+                self.q_id, self.tag, e
+            );
+        }
+        self.outstanding.put();
+    }
+}
+
+/// Queue handler which handles each IO by one task of tokio runtime
+///
+/// `spawn` is called in the queue thread for each incoming io command,
+/// and the returned future is spawned on the runtime.
+///
+/// When the handler is dropped, the queue thread waits until all IOs
+/// owned by tokio tasks are completed, so tasks can't access io buffers
+/// after the queue is gone.
+///
+/// # Examples:
+///
+/// ```no_run
+/// use libublk::tokio_io::{UblkTokioHandler, UblkTokioIo};
+/// use libublk::io::UblkDev;
+///
+/// fn handler(dev: &UblkDev, q_id: u16, rt: tokio::runtime::Handle) {
+///     let _h = UblkTokioHandler::new(dev, q_id, rt, |mut io: UblkTokioIo| async move {
+///         let bytes = io.buf().len() as i32;
+///
+///         io.buf_mut().fill(0);
+///         io.complete(bytes);
+///     });
+/// }
+/// ```
+pub struct UblkTokioHandler<F> {
+    spawn: F,
+    rt: tokio::runtime::Handle,
+    completer: UblkQueueCompleter,
+    outstanding: Arc<UblkTokioOutstanding>,
+}
+
+impl<F, Fut> UblkTokioHandler<F>
+where
+    F: FnMut(UblkTokioIo) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    /// Create tokio handler for one queue
+    ///
+    /// # Arguments:
+    ///
+    /// * `dev`: ublk device, which has to be created with
+    ///     `UBLK_DEV_F_COMP_CHAN`
+    /// * `q_id`: queue id
+    /// * `rt`: handle of tokio runtime for spawning tasks
+    /// * `spawn`: called for each io command for building task future
+    pub fn new(
+        dev: &UblkDev,
+        q_id: u16,
+        rt: tokio::runtime::Handle,
+        spawn: F,
+    ) -> Result<Self, UblkError> {
+        let completer = dev
+            .completer(q_id)
+            .ok_or(UblkError::OtherError(-libc::EINVAL))?;
+
+        trace!("dev {} queue {}: tokio handler", dev.dev_info.dev_id, q_id);
+        Ok(UblkTokioHandler {
+            spawn,
+            rt,
+            completer,
+            outstanding: Arc::new(UblkTokioOutstanding::default()),
+        })
+    }
+}
+
+impl<F, Fut> UblkQueueHandler for UblkTokioHandler<F>
+where
+    F: FnMut(UblkTokioIo) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn handle_io(&mut self, ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        let tag = io.get_tag() as u16;
+        let desc = io.io_desc().ok_or(UblkError::OtherError(-libc::EINVAL))?;
+
+        // only queue's own buffer is moved to the task, see io_buf_mut()
+        let buf = io.io_buf_mut();
+        let (buf, buf_len) = if buf.is_empty() {
+            (std::ptr::null_mut(), 0)
+        } else {
+            (buf.as_mut_ptr(), buf.len())
+        };
+
+        self.outstanding.get();
+
+        let tio = UblkTokioIo {
+            tag,
+            q_id: ctx.q_id,
+            iod: *desc.raw(),
+            buf,
+            buf_len,
+            res: -libc::EIO,
+            completer: self.completer.clone(),
+            outstanding: self.outstanding.clone(),
+        };
+        self.rt.spawn((self.spawn)(tio));
+
+        Ok(0)
+    }
+}
+
+impl<F> Drop for UblkTokioHandler<F> {
+    fn drop(&mut self) {
+        self.outstanding.wait_idle();
+    }
+}
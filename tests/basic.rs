@@ -1,20 +1,402 @@
 #[cfg(test)]
 mod tests {
-    use libublk::io::{UblkDev, UblkIOCtx, UblkQueue, UblkQueueCtx};
+    use libublk::ctrl::{
+        UblkCtrl, UblkCtrlBuilder, UblkDevExport, UblkDevState, UblkFlags, UblkParamsBuilder,
+        UblkQueueExport, UblkRecoveryMode, UBLK_EXPORT_VERSION,
+    };
+    use libublk::io::{
+        UblkDev, UblkIOCtx, UblkIoDesc, UblkIoFlags, UblkIoOp, UblkQueue, UblkQueueCtx,
+        UblkQueueHandler, UblkSubIoTracker, UblkTarget, UblkTgt,
+    };
     use libublk::sys;
-    use libublk::{ctrl::UblkCtrl, UblkError};
+    use libublk::UblkError;
     use std::env;
+    use std::os::unix::fs::MetadataExt;
     use std::path::Path;
+    use std::time::Duration;
 
     #[test]
     fn test_add_ctrl_dev() {
-        let ctrl = UblkCtrl::new(-1, 1, 64, 512_u32 * 1024, 0, true).unwrap();
+        let ctrl = UblkCtrlBuilder::default()
+            .name("null")
+            .nr_queues(1)
+            .depth(64)
+            .io_buf_bytes(512_u32 * 1024)
+            .build()
+            .unwrap();
         let dev_path = format!("{}{}", libublk::CDEV_PATH, ctrl.dev_info.dev_id);
 
         std::thread::sleep(std::time::Duration::from_millis(500));
         assert!(Path::new(&dev_path).exists() == true);
     }
 
+    /// attach to existed device, and device info is retrieved from driver
+    #[test]
+    fn test_attach_ctrl_dev() {
+        let ctrl = UblkCtrlBuilder::default()
+            .name("null")
+            .nr_queues(2)
+            .depth(64)
+            .build()
+            .unwrap();
+        let id = ctrl.dev_info.dev_id as i32;
+        let attached = UblkCtrl::new_simple(id).unwrap();
+
+        assert!(attached.dev_info.nr_hw_queues == 2);
+        assert!(attached.dev_info.queue_depth == 64);
+        assert!(attached.owner_uid() == unsafe { libc::getuid() });
+        assert!(attached.owner_gid() == unsafe { libc::getgid() });
+    }
+
+    /// invalid parameters are rejected before talking to ublk driver
+    #[test]
+    fn test_ctrl_builder_validate() {
+        let b = UblkCtrlBuilder::default().name("null");
+
+        assert!(b.clone().id(-2).build().is_err());
+        assert!(b.clone().nr_queues(0).build().is_err());
+        assert!(b
+            .clone()
+            .depth(sys::UBLK_MAX_QUEUE_DEPTH + 1)
+            .build()
+            .is_err());
+        assert!(b.clone().io_buf_bytes(4097).build().is_err());
+        assert!(b.clone().io_buf_bytes(64_u32 << 20).build().is_err());
+        assert!(b.clone().dev_flags(1_u32 << 31).build().is_err());
+        assert!(b.clone().recover(true).build().is_err());
+        assert!(b.for_add(false).build().is_err());
+    }
+
+    /// errors carry decoded errno and convert into std::io::Error
+    #[test]
+    fn test_error_errno() {
+        let e = UblkError::CtrlCmdError {
+            op: "ADD_DEV",
+            dev_id: 3,
+            errno: libublk::UblkErrno(-libc::EPERM),
+        };
+        let msg = e.to_string();
+
+        assert!(msg.contains("ADD_DEV") && msg.contains("device 3"));
+        assert!(msg.contains(&std::io::Error::from_raw_os_error(libc::EPERM).to_string()));
+        assert!(e.errno() == Some(-libc::EPERM));
+        assert!(std::io::Error::from(e).kind() == std::io::ErrorKind::PermissionDenied);
+
+        assert!(UblkError::OtherError(-libc::ENOENT).errno() == Some(-libc::ENOENT));
+
+        let e = UblkError::Timeout {
+            dev_id: 3,
+            state: UblkDevState::Live,
+        };
+        assert!(e.errno() == Some(-libc::ETIMEDOUT));
+        assert!(e.to_string().contains("device 3") && e.to_string().contains("Live"));
+        assert!(
+            std::io::Error::from(UblkError::OtherError(-libc::ENOENT)).kind()
+                == std::io::ErrorKind::NotFound
+        );
+    }
+
+    /// io op of user data is validated or truncated to 8bit
+    #[test]
+    fn test_user_data() {
+        let data = UblkIOCtx::build_user_data(3, 0x1ff, 5, true);
+
+        assert!(UblkIOCtx::user_data_to_tag(data) == 3);
+        assert!(UblkIOCtx::user_data_to_op(data) == 0xff);
+        assert!(UblkIOCtx::user_data_to_tgt_data(data) == 5);
+
+        assert!(UblkIOCtx::try_build_user_data(3, 0xff, 5, true).unwrap() == data);
+        let e = UblkIOCtx::try_build_user_data(3, 0x100, 5, true).unwrap_err();
+        assert!(e.errno() == Some(-libc::EINVAL));
+    }
+
+    /// sub-IOs which aren't submitted are rolled back, so the tag can be
+    /// fanned out again
+    #[test]
+    fn test_sub_io_tracker() {
+        let mut t = UblkSubIoTracker::new(4);
+        let op = sys::UBLK_IO_OP_WRITE;
+
+        t.begin(1).unwrap();
+        let e = t.sub_io_user_data(1, 0x100, true).unwrap_err();
+        assert!(e.errno() == Some(-libc::EINVAL));
+        assert!(t.inflight(1) == 0);
+
+        for i in 0..3 {
+            let data = t.sub_io_user_data(1, op, true).unwrap();
+
+            assert!(UblkIOCtx::user_data_to_tag(data) == 1);
+            assert!(UblkIOCtx::user_data_to_op(data) == op);
+            assert!(UblkIOCtx::user_data_to_tgt_data(data) == i);
+        }
+        assert!(t.begin(1).unwrap_err().errno() == Some(-libc::EBUSY));
+        assert!(t.cancel(1, 4).unwrap_err().errno() == Some(-libc::EINVAL));
+
+        // the 1st sub-IO is still in flight
+        assert!(!t.cancel(1, 2).unwrap());
+        assert!(t.inflight(1) == 1);
+        assert!(t.cancel(1, 1).unwrap());
+        assert!(t.inflight(1) == 0);
+
+        t.begin(1).unwrap();
+        let data = t.sub_io_user_data(1, op, false).unwrap();
+        assert!(UblkIOCtx::user_data_to_tgt_data(data) & 0x7fff_ffff == 0);
+        assert!(t.begin(4).is_err() && t.cancel(4, 0).is_err());
+    }
+
+    /// io descriptor is decoded from the raw one
+    #[test]
+    fn test_io_desc() {
+        let iod = sys::ublksrv_io_desc {
+            op_flags: sys::UBLK_IO_OP_WRITE | sys::UBLK_IO_F_FUA | (1 << 31),
+            nr_sectors: 8,
+            start_sector: 16,
+            addr: 0,
+        };
+        let desc = UblkIoDesc::from(iod);
+
+        assert!(desc.op() == UblkIoOp::Write);
+        assert!(desc.flags() == UblkIoFlags::FUA);
+        assert!(desc.offset() == 16 << 9);
+        assert!(desc.len() == 8 << 9);
+        assert!(desc.data_len() == desc.len());
+
+        let iod = sys::ublksrv_io_desc {
+            op_flags: sys::UBLK_IO_OP_DISCARD,
+            ..iod
+        };
+        let desc = UblkIoDesc::from(iod);
+        assert!(desc.op() == UblkIoOp::Discard);
+        assert!(desc.len() == 8 << 9 && desc.data_len() == 0);
+
+        let desc = UblkIoDesc::from(sys::ublksrv_io_desc {
+            op_flags: 0xfe,
+            ..iod
+        });
+        assert!(desc.op() == UblkIoOp::Unknown(0xfe));
+        assert!(UblkIoDesc::default().is_empty());
+    }
+
+    /// recovery mode is mapped to recovery flags
+    #[test]
+    fn test_recovery_mode() {
+        for mode in [
+            UblkRecoveryMode::Disabled,
+            UblkRecoveryMode::FailInflight,
+            UblkRecoveryMode::Reissue,
+        ] {
+            assert!(UblkRecoveryMode::from(mode.flags()) == mode);
+        }
+
+        let flags = UblkFlags::USER_RECOVERY | UblkFlags::USER_RECOVERY_REISSUE;
+        assert!(UblkRecoveryMode::from(flags) == UblkRecoveryMode::Reissue);
+        assert!(
+            UblkRecoveryMode::from(UblkFlags::USER_RECOVERY_REISSUE) == UblkRecoveryMode::Disabled
+        );
+    }
+
+    /// UBLK_U_CMD_GET_FEATURES is supported since v6.5, and zero copy
+    /// isn't supported by ublk driver yet
+    #[test]
+    fn test_ctrl_features() {
+        if let Ok(features) = UblkCtrl::get_features() {
+            assert!(features.contains(UblkFlags::CMD_IOCTL_ENCODE));
+        }
+
+        match UblkCtrlBuilder::default()
+            .name("null")
+            .ctrl_flags(UblkFlags::SUPPORT_ZERO_COPY)
+            .build()
+        {
+            Err(UblkError::UnsupportedFeature(f)) => assert!(f == "SUPPORT_ZERO_COPY"),
+            _ => panic!("zero copy should be rejected"),
+        }
+    }
+
+    /// exported json is typed & versioned, and old format can be loaded
+    #[test]
+    fn test_dev_export() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct TgtData {
+            path: String,
+        }
+
+        let e = UblkDevExport {
+            version: UBLK_EXPORT_VERSION,
+            dev_info: sys::ublksrv_ctrl_dev_info {
+                dev_id: 3,
+                nr_hw_queues: 2,
+                ..Default::default()
+            },
+            target: UblkTgt {
+                tgt_type: "loop".to_string(),
+                ..Default::default()
+            },
+            target_data: TgtData {
+                path: "/tmp/img".to_string(),
+            },
+            queues: (0..2)
+                .map(|q| UblkQueueExport {
+                    qid: q,
+                    tid: 100 + q as i32,
+                    affinity: vec![q as u32],
+                })
+                .collect(),
+        };
+        let mut val = serde_json::to_value(&e).unwrap();
+
+        let t = UblkDevExport::<TgtData>::from_value(val.clone()).unwrap();
+        assert!(t.target_data == e.target_data);
+        assert!(t.queue(1).unwrap().tid == 101);
+
+        //format without version, and queues are stored in map
+        let mut map = serde_json::Map::new();
+        for q in &e.queues {
+            map.insert(q.qid.to_string(), serde_json::to_value(q).unwrap());
+        }
+        val["queues"] = serde_json::Value::Object(map);
+        val.as_object_mut().unwrap().remove("version");
+        let t = UblkDevExport::<serde_json::Value>::parse(&val.to_string()).unwrap();
+        assert!(t.version == 0);
+        assert!(t.queues == e.queues);
+
+        //newer format is rejected
+        val["version"] = serde_json::json!(UBLK_EXPORT_VERSION + 1);
+        assert!(matches!(
+            UblkDevExport::<serde_json::Value>::from_value(val),
+            Err(UblkError::InvalidExport(_))
+        ));
+
+        //broken file is reported as error instead of panic
+        assert!(UblkDevExport::<TgtData>::parse("{\"version\":1").is_err());
+    }
+
+    /// invalid device parameters are rejected, and types is set
+    #[test]
+    fn test_params_builder() {
+        let max_buf = 512_u32 << 10;
+        let p = UblkParamsBuilder::new(1_u64 << 30)
+            .logical_block_size(4096)
+            .volatile_cache(true)
+            .fua(true)
+            .chunk_sectors(256)
+            .discard(0, 4096, 1 << 10, 1 << 10)
+            .build(max_buf)
+            .unwrap();
+
+        assert!(p.types == sys::UBLK_PARAM_TYPE_BASIC | sys::UBLK_PARAM_TYPE_DISCARD);
+        assert!(p.basic.logical_bs_shift == 12);
+        assert!(p.basic.max_sectors == max_buf >> 9);
+        assert!(p.basic.dev_sectors == (1_u64 << 30) >> 9);
+        assert!(p.basic.attrs == sys::UBLK_ATTR_VOLATILE_CACHE | sys::UBLK_ATTR_FUA);
+        assert!(p.discard.max_discard_segments == 1);
+
+        let p = UblkParamsBuilder::new(1_u64 << 30).build(max_buf).unwrap();
+        assert!(p.types == sys::UBLK_PARAM_TYPE_BASIC);
+
+        let invalid = [
+            UblkParamsBuilder::new(1 << 30).max_sectors((max_buf >> 9) + 1),
+            UblkParamsBuilder::new(1 << 30).logical_block_size(1000),
+            UblkParamsBuilder::new(1 << 30).logical_block_size(256),
+            UblkParamsBuilder::new(1 << 30)
+                .logical_block_size(4096)
+                .physical_block_size(512),
+            UblkParamsBuilder::new(1 << 30).chunk_sectors(3),
+            UblkParamsBuilder::new(1 << 30).virt_boundary_mask(4096),
+            UblkParamsBuilder::new(1 << 30).fua(true),
+            UblkParamsBuilder::new(1 << 30).discard(0, 0, 1 << 10, 0),
+            UblkParamsBuilder::new(1 << 30).discard(4096, 4096, 1 << 10, 0),
+        ];
+        for b in invalid {
+            assert!(b.build(max_buf).is_err());
+        }
+    }
+
+    /// stale exported json file and its inflight files are removed, and
+    /// live one is kept
+    #[test]
+    fn test_cleanup_stale_exports() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = dir.path().to_str().unwrap();
+        let export = |pid: i32| {
+            serde_json::json!(UblkDevExport {
+                version: UBLK_EXPORT_VERSION,
+                dev_info: sys::ublksrv_ctrl_dev_info {
+                    ublksrv_pid: pid,
+                    ..Default::default()
+                },
+                target: UblkTgt::default(),
+                target_data: serde_json::json!({}),
+                queues: Vec::new(),
+            })
+            .to_string()
+        };
+
+        //device ids which can't exist, pid 0 means the daemon is gone
+        std::fs::write(dir.path().join("999990.json"), export(0)).unwrap();
+        std::fs::write(
+            dir.path().join("999991.json"),
+            export(unsafe { libc::getpid() }),
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("999992.json"), "{broken").unwrap();
+
+        //inflight files are removed with the stale export only
+        for name in [
+            "999990.q0.inflight",
+            "999990.q9.inflight",
+            "999991.q0.inflight",
+        ] {
+            std::fs::write(dir.path().join(name), []).unwrap();
+        }
+
+        let mut ids = libublk::ctrl::cleanup_stale_exports(run_dir).unwrap();
+        ids.sort();
+        assert!(ids == vec![999990, 999992]);
+        assert!(dir.path().join("999991.json").exists());
+        assert!(dir.path().join("999991.q0.inflight").exists());
+        assert!(!dir.path().join("999990.q0.inflight").exists());
+        assert!(!dir.path().join("999990.q9.inflight").exists());
+
+        //no lock file is left
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            assert!(path.extension() != Some(std::ffi::OsStr::new("lock")));
+        }
+    }
+
+    /// exported json file is stored in the specified run dir, and can't be
+    /// claimed by two daemons
+    #[test]
+    fn test_run_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let run_dir = dir.path().to_str().unwrap();
+        let mut dev = UblkCtrlBuilder::default()
+            .name("null")
+            .run_dir(run_dir)
+            .create_device(NullTgt(null_handle_io))
+            .unwrap();
+
+        dev.start().unwrap();
+
+        let path = dev.ctrl().run_path();
+        assert!(path.starts_with(run_dir));
+        assert!(Path::new(&path).exists());
+
+        let mut ctrl = UblkCtrlBuilder::default()
+            .id(dev.dev_id())
+            .for_add(false)
+            .run_dir(run_dir)
+            .build()
+            .unwrap();
+        ctrl.reload_json().unwrap();
+        assert!(ctrl.flush_json().is_err());
+
+        dev.stop().unwrap();
+        dev.wait();
+        assert!(!Path::new(&path).exists());
+    }
+
     fn null_handle_io(ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
         let iod = ctx.get_iod(io.get_tag());
         let bytes = unsafe { (*iod).nr_sectors << 9 } as i32;
@@ -31,29 +413,51 @@ mod tests {
         Ok(libublk::io::UBLK_IO_S_COMP_BATCH)
     }
 
+    fn null_handle_io_desc(ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+        let desc = io.io_desc().unwrap();
+
+        assert!(desc.raw().start_sector == ctx.io_desc(io.get_tag() as u16).start_sector());
+        io.complete_io(desc.len() as i32);
+        Ok(0)
+    }
+
+    struct NullTgt(fn(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>);
+
+    impl UblkTarget for NullTgt {
+        type Data = ();
+
+        fn init(&mut self, dev: &mut UblkDev) -> Result<(), UblkError> {
+            dev.set_default_params(250_u64 << 30);
+            Ok(())
+        }
+
+        fn queue_handler(
+            &self,
+            _dev: &UblkDev,
+            _q_id: u16,
+        ) -> Result<Box<dyn UblkQueueHandler>, UblkError> {
+            Ok(Box::new(self.0))
+        }
+    }
+
     fn __test_ublk_null(
         dev_flags: u32,
         f: fn(&UblkQueueCtx, &mut UblkIOCtx) -> Result<i32, UblkError>,
     ) {
         libublk::ublk_tgt_worker(
-            "null".to_string(),
-            -1,
-            2,
-            64,
-            512_u32 * 1024,
-            0,
-            true,
-            dev_flags,
-            |dev: &mut UblkDev| {
-                dev.set_default_params(250_u64 << 30);
-                Ok(serde_json::json!({}))
-            },
-            f,
+            UblkCtrlBuilder::default()
+                .name("null")
+                .nr_queues(2)
+                .depth(64)
+                .io_buf_bytes(512_u32 * 1024)
+                .dev_flags(dev_flags),
+            NullTgt(f),
             |dev_id| {
-                let mut ctrl = UblkCtrl::new(dev_id, 0, 0, 0, 0, false).unwrap();
+                let mut ctrl = UblkCtrl::new_simple(dev_id).unwrap();
                 let dev_path = format!("{}{}", libublk::BDEV_PATH, dev_id);
 
-                std::thread::sleep(std::time::Duration::from_millis(500));
+                ctrl.wait_for_state(UblkDevState::Live, Duration::from_secs(2))
+                    .unwrap();
 
                 //ublk block device should be observed now
                 assert!(Path::new(&dev_path).exists() == true);
@@ -81,6 +485,62 @@ mod tests {
         __test_ublk_null(libublk::io::UBLK_DEV_F_COMP_BATCH, null_handle_io_batch);
     }
 
+    /// make one ublk-null which handles io via UblkIoDesc
+    #[test]
+    fn test_ublk_null_io_desc() {
+        __test_ublk_null(0, null_handle_io_desc);
+    }
+
+    /// make one ublk-null and check if it can be found by list_devices()
+    #[test]
+    fn test_list_devices() {
+        let mut dev = UblkCtrlBuilder::default()
+            .name("null")
+            .nr_queues(2)
+            .depth(32)
+            .create_device(NullTgt(null_handle_io))
+            .unwrap();
+
+        dev.start().unwrap();
+
+        let id = dev.dev_id() as u32;
+        let devs = libublk::ctrl::list_devices().unwrap();
+        let info = devs.iter().find(|d| d.id == id).unwrap();
+
+        assert!(info.nr_queues == 2);
+        assert!(info.depth == 32);
+        assert!(info.capacity == 250_u64 << 30);
+        assert!(info.pid == unsafe { libc::getpid() });
+        assert!(info.state == UblkDevState::Live);
+        assert!(info.tgt_type.as_deref() == Some("null"));
+
+        dev.stop().unwrap();
+        dev.wait();
+    }
+
+    /// disk device number read back from device parameters matches
+    /// /dev/ublkbN
+    #[test]
+    fn test_get_devt() {
+        let mut dev = UblkCtrlBuilder::default()
+            .name("null")
+            .create_device(NullTgt(null_handle_io))
+            .unwrap();
+        let id = dev.dev_id();
+
+        //disk device number is available after the device is started
+        dev.start().unwrap();
+
+        let devt = dev.ctrl().get_devt().unwrap();
+        let bdev = format!("{}{}", libublk::BDEV_PATH, id);
+        let rdev = std::fs::metadata(&bdev).unwrap().rdev();
+        assert!(libc::major(rdev) == devt.disk_major);
+        assert!(libc::minor(rdev) == devt.disk_minor);
+
+        dev.stop().unwrap();
+        dev.wait();
+    }
+
     fn rd_handle_io(ctx: &UblkQueueCtx, io: &mut UblkIOCtx, start: u64) -> Result<i32, UblkError> {
         let _iod = ctx.get_iod(io.get_tag());
         let iod = unsafe { &*_iod };
@@ -113,10 +573,11 @@ mod tests {
     }
 
     fn __test_ublk_ramdisk(dev_id: i32) {
-        let mut ctrl = UblkCtrl::new(dev_id, 0, 0, 0, 0, false).unwrap();
+        let mut ctrl = UblkCtrl::new_simple(dev_id).unwrap();
         let dev_path = format!("{}{}", libublk::BDEV_PATH, dev_id);
 
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        ctrl.wait_for_state(UblkDevState::Live, Duration::from_secs(2))
+            .unwrap();
 
         //ublk block device should be observed now
         assert!(Path::new(&dev_path).exists() == true);
@@ -151,7 +612,14 @@ mod tests {
     ) -> std::thread::JoinHandle<()> {
         let depth = 128;
         let nr_queues = 1;
-        let mut ctrl = UblkCtrl::new(dev_id, nr_queues, depth, 512 << 10, 0, true).unwrap();
+        let mut ctrl = UblkCtrlBuilder::default()
+            .name("ramdisk")
+            .id(dev_id)
+            .nr_queues(nr_queues)
+            .depth(depth)
+            .io_buf_bytes(512 << 10)
+            .build()
+            .unwrap();
         let ublk_dev = UblkDev::new(
             "ramdisk".to_string(),
             |dev: &mut UblkDev| {
@@ -195,8 +663,114 @@ mod tests {
         qh.join().unwrap();
     }
 
+    /// ramdisk whose data is copied by io_uring read/write on /dev/ublkcN
+    struct UserCopyRd {
+        start: u64,
+        size: u64,
+    }
+
+    struct UserCopyRdQueue {
+        start: u64,
+        q_id: u16,
+    }
+
+    impl UblkQueueHandler for UserCopyRdQueue {
+        fn handle_io(&mut self, ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+            let tag = io.get_tag() as u16;
+            let iod = unsafe { &*ctx.get_iod(tag as u32) };
+            let buf = self.start + (iod.start_sector << 9);
+            let bytes = iod.nr_sectors << 9;
+            let op = iod.op_flags & 0xff;
+
+            let sqe = match op {
+                sys::UBLK_IO_OP_READ => {
+                    UblkIOCtx::user_copy_write_sqe(self.q_id, tag, buf as *const u8, bytes, 0)
+                }
+                sys::UBLK_IO_OP_WRITE => {
+                    UblkIOCtx::user_copy_read_sqe(self.q_id, tag, buf as *mut u8, bytes, 0)
+                }
+                sys::UBLK_IO_OP_FLUSH => {
+                    io.complete_io(0);
+                    return Ok(0);
+                }
+                _ => return Err(UblkError::OtherError(-libc::EINVAL)),
+            };
+            let sqe = sqe.user_data(UblkIOCtx::build_user_data(tag, op, 0, true));
+
+            unsafe { io.get_ring().submission().push(&sqe) }.map_err(UblkError::UringPushError)?;
+            Ok(0)
+        }
+
+        fn handle_tgt_io(
+            &mut self,
+            _ctx: &UblkQueueCtx,
+            io: &mut UblkIOCtx,
+        ) -> Result<i32, UblkError> {
+            io.complete_io(io.result());
+            Ok(0)
+        }
+    }
+
+    impl UblkTarget for UserCopyRd {
+        type Data = ();
+
+        fn init(&mut self, dev: &mut UblkDev) -> Result<(), UblkError> {
+            dev.set_default_params(self.size);
+            Ok(())
+        }
+
+        fn queue_handler(
+            &self,
+            _dev: &UblkDev,
+            q_id: u16,
+        ) -> Result<Box<dyn UblkQueueHandler>, UblkError> {
+            Ok(Box::new(UserCopyRdQueue {
+                start: self.start,
+                q_id,
+            }))
+        }
+    }
+
+    /// make one ublk-ramdisk in UBLK_F_USER_COPY mode, and test
+    /// format/mount/umount over it
+    #[test]
+    fn test_ublk_ramdisk_user_copy() {
+        match UblkCtrl::get_features() {
+            Ok(f) if f.contains(UblkFlags::USER_COPY) => {}
+            _ => return,
+        }
+
+        let size = 32_u64 << 20;
+        let buf = libublk::ublk_alloc_buf(size as usize, 4096);
+
+        libublk::ublk_tgt_worker(
+            UblkCtrlBuilder::default()
+                .name("ramdisk")
+                .nr_queues(2)
+                .depth(64)
+                .io_buf_bytes(512 << 10)
+                .ctrl_flags(UblkFlags::USER_COPY),
+            UserCopyRd {
+                start: buf as u64,
+                size,
+            },
+            __test_ublk_ramdisk,
+        )
+        .unwrap()
+        .join()
+        .unwrap();
+
+        libublk::ublk_dealloc_buf(buf, size as usize, 4096);
+    }
+
     fn __test_fn_mut_io_closure() -> std::thread::JoinHandle<()> {
-        let mut ctrl = UblkCtrl::new(-1, 1, 64, 512 << 10, 0, true).unwrap();
+        let mut ctrl = UblkCtrlBuilder::default()
+            .name("FnMutClosure")
+            .nr_queues(1)
+            .depth(64)
+            .io_buf_bytes(512 << 10)
+            .build()
+            .unwrap();
         let ublk_dev = UblkDev::new(
             "FnMutClosure".to_string(),
             |dev: &mut UblkDev| {
@@ -231,7 +805,7 @@ mod tests {
 
         let dev_id = ctrl.dev_info.dev_id as i32;
         let qh = std::thread::spawn(move || {
-            let mut ctrl = UblkCtrl::new(dev_id, 0, 0, 0, 0, false).unwrap();
+            let mut ctrl = UblkCtrl::new_simple(dev_id).unwrap();
             ctrl.del().unwrap();
         });
 
@@ -261,22 +835,6 @@ mod tests {
         }
     }
 
-    fn ublk_state_wait_until(ctrl: &mut UblkCtrl, state: u16, timeout: u32) {
-        let mut count = 0;
-        let unit = 100_u32;
-        loop {
-            std::thread::sleep(std::time::Duration::from_millis(unit as u64));
-
-            ctrl.get_info().unwrap();
-            if ctrl.dev_info.state == state {
-                std::thread::sleep(std::time::Duration::from_millis(20));
-                break;
-            }
-            count += unit;
-            assert!(count < timeout);
-        }
-    }
-
     /// run examples/ramdisk recovery test
     #[test]
     fn test_ublk_ramdisk_recovery() {
@@ -295,26 +853,30 @@ mod tests {
             .expect("fail to add ublk ramdisk");
         cmd.wait().unwrap();
 
-        //this magic wait makes a difference
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        let buf = std::fs::read_to_string(tmpfile.path()).unwrap();
-
-        let id_regx = regex::Regex::new(r"dev id (\d+)").unwrap();
-        let tid_regx = regex::Regex::new(r"queue 0 tid: (\d+)").unwrap();
-
+        //the ramdisk daemon is forked from the example process, and it
+        //dumps id of the device it created
         let mut id = -1_i32;
-        if let Some(c) = id_regx.captures(&buf.as_str()) {
-            id = c.get(1).unwrap().as_str().parse().unwrap();
+        for _ in 0..20 {
+            let buf = std::fs::read_to_string(tmpfile.path()).unwrap();
+            if let Some(pos) = buf.find("dev id ") {
+                let digits: String = buf[pos + 7..]
+                    .chars()
+                    .take_while(|c| c.is_ascii_digit())
+                    .collect();
+                id = digits.parse().unwrap();
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
         }
+        assert!(id >= 0);
 
-        let mut tid = 0;
-        if let Some(c) = tid_regx.captures(&buf.as_str()) {
-            tid = c.get(1).unwrap().as_str().parse().unwrap();
-        }
-        assert!(tid != 0);
+        let mut ctrl = UblkCtrl::new_simple(id).unwrap();
+        ctrl.wait_for_state(UblkDevState::Live, Duration::from_secs(2))
+            .unwrap();
 
-        let mut ctrl = UblkCtrl::new(id, 0, 0, 0, 0, false).unwrap();
-        ublk_state_wait_until(&mut ctrl, sys::UBLK_S_DEV_LIVE as u16, 2000);
+        ctrl.reload_json().unwrap();
+        let tid = ctrl.get_queue_tid(0).unwrap();
+        assert!(tid != 0);
 
         //ublk block device should be observed now
         let dev_path = format!("{}{}", libublk::BDEV_PATH, id);
@@ -326,7 +888,8 @@ mod tests {
         }
 
         //wait device becomes quiesced
-        ublk_state_wait_until(&mut ctrl, sys::UBLK_S_DEV_QUIESCED as u16, 6000);
+        ctrl.wait_for_state(UblkDevState::Quiesced, Duration::from_secs(6))
+            .unwrap();
 
         let file = std::fs::File::create(tmpfile.path()).unwrap();
         //recover device
@@ -338,7 +901,8 @@ mod tests {
         cmd.wait().unwrap();
         //let buf = std::fs::read_to_string(tmpfile.path()).unwrap();
         //println!("{}", buf);
-        ublk_state_wait_until(&mut ctrl, sys::UBLK_S_DEV_LIVE as u16, 20000);
+        ctrl.wait_for_state(UblkDevState::Live, Duration::from_secs(20))
+            .unwrap();
         ctrl.del_dev().unwrap();
     }
 }
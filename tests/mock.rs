@@ -0,0 +1,1366 @@
+#![cfg(feature = "mock-driver")]
+
+//! Tests over the in-process mock driver, which needn't ublk_drv or root
+
+#[cfg(test)]
+mod tests {
+    use libublk::ctrl::{UblkCtrl, UblkCtrlBuilder, UblkCtrlRing, UblkDevState, UblkRecoveryMode};
+    use libublk::exec::{UblkAsyncHandler, UblkIoTask};
+    use libublk::io::{
+        UblkDev, UblkIOCtx, UblkInflightIo, UblkIoDesc, UblkIoFlags, UblkIoOp, UblkQueueCtx,
+        UblkQueueHandler, UblkSubIoTracker, UblkTarget,
+    };
+    use libublk::{mock, sys, UblkError};
+    use std::future::Future;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+
+    const DEV_SIZE: u64 = 1_u64 << 20;
+
+    type MockSetup = Box<dyn FnMut(&mut UblkDev) -> Result<(), UblkError> + Send + Sync>;
+    type MockHandler =
+        Box<dyn Fn(&UblkDev, u16) -> Result<Box<dyn UblkQueueHandler>, UblkError> + Send + Sync>;
+
+    /// Target of `DEV_SIZE` with default parameters, and each test only
+    /// provides what differs: queue handler, and extra setup in `init()`,
+    /// such as backing files or ring flags
+    struct MockTgt {
+        setup: MockSetup,
+        handler: MockHandler,
+    }
+
+    impl MockTgt {
+        fn new<H>(handler: H) -> Self
+        where
+            H: Fn(&UblkDev, u16) -> Result<Box<dyn UblkQueueHandler>, UblkError>
+                + Send
+                + Sync
+                + 'static,
+        {
+            MockTgt {
+                setup: Box::new(|_| Ok(())),
+                handler: Box::new(handler),
+            }
+        }
+
+        /// `setup` is called from `init()` before setting default params
+        fn setup<S>(mut self, setup: S) -> Self
+        where
+            S: FnMut(&mut UblkDev) -> Result<(), UblkError> + Send + Sync + 'static,
+        {
+            self.setup = Box::new(setup);
+            self
+        }
+    }
+
+    impl UblkTarget for MockTgt {
+        type Data = ();
+
+        fn init(&mut self, dev: &mut UblkDev) -> Result<(), UblkError> {
+            (self.setup)(dev)?;
+            dev.set_default_params(DEV_SIZE);
+            Ok(())
+        }
+
+        fn queue_handler(
+            &self,
+            dev: &UblkDev,
+            q_id: u16,
+        ) -> Result<Box<dyn UblkQueueHandler>, UblkError> {
+            (self.handler)(dev, q_id)
+        }
+    }
+
+    /// Add `files` to target fds, so they are registered as io_uring fixed
+    /// files from index 1
+    fn add_files(dev: &mut UblkDev, files: &[std::fs::File]) {
+        let tgt = &mut dev.tgt;
+
+        for f in files {
+            tgt.fds[tgt.nr_fds as usize] = f.as_raw_fd();
+            tgt.nr_fds += 1;
+        }
+    }
+
+    fn vec_data() -> Arc<Mutex<Vec<u8>>> {
+        Arc::new(Mutex::new(vec![0_u8; DEV_SIZE as usize]))
+    }
+
+    const HOLD_SECTOR: u64 = 8;
+
+    fn vec_handle_io(
+        ctx: &UblkQueueCtx,
+        io: &mut UblkIOCtx,
+        data: &Mutex<Vec<u8>>,
+        hold: bool,
+    ) -> Result<i32, UblkError> {
+        let desc = ctx.io_desc(io.get_tag() as u16);
+        let off = desc.offset() as usize;
+        let bytes = desc.len();
+        let mut d = data.lock().unwrap();
+
+        if hold && desc.start_sector() == HOLD_SECTOR {
+            return Ok(0);
+        }
+
+        match desc.op() {
+            UblkIoOp::Read => io.io_buf_mut().copy_from_slice(&d[off..off + bytes]),
+            UblkIoOp::Write => d[off..off + bytes].copy_from_slice(io.io_buf()),
+            UblkIoOp::Flush => {}
+            _ => return Err(UblkError::OtherError(-libc::EINVAL)),
+        }
+        io.complete_io(bytes as i32);
+        Ok(0)
+    }
+
+    /// ramdisk over `data`, and IO with `start_sector` of `HOLD_SECTOR` is
+    /// never completed if `hold` is set
+    fn vec_tgt(data: &Arc<Mutex<Vec<u8>>>, hold: bool) -> MockTgt {
+        let data = data.clone();
+
+        MockTgt::new(move |_dev, _q_id| {
+            let data = data.clone();
+
+            Ok(Box::new(move |ctx: &UblkQueueCtx, io: &mut UblkIOCtx| {
+                vec_handle_io(ctx, io, &data, hold)
+            }))
+        })
+    }
+
+    /// ramdisk whose queue rings are setup with `flags`
+    fn ring_tgt(data: &Arc<Mutex<Vec<u8>>>, flags: u64, cfg: libublk::io::UblkRingCfg) -> MockTgt {
+        vec_tgt(data, false).setup(move |dev| {
+            dev.tgt.ring_flags = flags;
+            dev.tgt.ring_cfg = cfg.clone();
+            Ok(())
+        })
+    }
+
+    /// MockTgt which records IOs in flight passed to `reconcile()`
+    struct ReconcileTgt {
+        tgt: MockTgt,
+        inflight: Arc<Mutex<Vec<UblkInflightIo>>>,
+    }
+
+    impl UblkTarget for ReconcileTgt {
+        type Data = ();
+
+        fn init(&mut self, dev: &mut UblkDev) -> Result<(), UblkError> {
+            self.tgt.init(dev)
+        }
+
+        fn reconcile(
+            &mut self,
+            _dev: &UblkDev,
+            inflight: &[UblkInflightIo],
+        ) -> Result<(), UblkError> {
+            self.inflight.lock().unwrap().extend_from_slice(inflight);
+            Ok(())
+        }
+
+        fn queue_handler(
+            &self,
+            dev: &UblkDev,
+            q_id: u16,
+        ) -> Result<Box<dyn UblkQueueHandler>, UblkError> {
+            self.tgt.queue_handler(dev, q_id)
+        }
+    }
+
+    /// target which records descriptor of every io command
+    fn desc_tgt(descs: &Arc<Mutex<Vec<UblkIoDesc>>>) -> MockTgt {
+        let descs = descs.clone();
+
+        MockTgt::new(move |_dev, _q_id| {
+            let descs = descs.clone();
+
+            Ok(Box::new(move |_ctx: &UblkQueueCtx, io: &mut UblkIOCtx| {
+                let desc = io.io_desc().unwrap();
+
+                descs.lock().unwrap().push(desc);
+                io.complete_io(desc.len() as i32);
+                Ok(0)
+            }))
+        })
+    }
+
+    async fn file_handle_io(io: UblkIoTask) -> i32 {
+        let desc = io.io_desc();
+        let off = desc.offset();
+        let bytes = desc.len() as u32;
+        let fd = io_uring::types::Fixed(1);
+        let buf = io.io_buf_addr();
+
+        match desc.op() {
+            UblkIoOp::Read => match io.io_buf_index() {
+                Some(i) => unsafe { io.read_fixed(fd, buf, bytes, off, i) }.await,
+                None => unsafe { io.read(fd, buf, bytes, off) }.await,
+            },
+            UblkIoOp::Write => {
+                let res = match io.io_buf_index() {
+                    Some(i) => unsafe { io.write_fixed(fd, buf, bytes, off, i) }.await,
+                    None => unsafe { io.write(fd, buf, bytes, off) }.await,
+                };
+                if res < 0 {
+                    return res;
+                }
+                match io.fsync(fd).await {
+                    r if r < 0 => r,
+                    _ => res,
+                }
+            }
+            UblkIoOp::Flush => io.fsync(fd).await,
+            _ => -libc::EINVAL,
+        }
+    }
+
+    /// file backed target, which handles IO by async task, and `fixed`
+    /// means io buffers are registered as fixed buffers
+    fn file_tgt(file: std::fs::File, fixed: bool) -> MockTgt {
+        MockTgt::new(move |_dev, _q_id| {
+            Ok(Box::new(UblkAsyncHandler::new(move |io: UblkIoTask| {
+                let valid = io.io_buf_index().is_some() == fixed;
+
+                async move {
+                    if !valid {
+                        return -libc::EINVAL;
+                    }
+                    file_handle_io(io).await
+                }
+            })))
+        })
+        .setup(move |dev| {
+            add_files(dev, std::slice::from_ref(&file));
+            Ok(())
+        })
+    }
+
+    /// each IO is split into `STRIPES` sub-IOs
+    const STRIPES: usize = 4;
+
+    struct MirrorHandler {
+        nr_files: u32,
+        tracker: UblkSubIoTracker,
+    }
+
+    impl UblkQueueHandler for MirrorHandler {
+        fn handle_io(&mut self, _ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+            let tag = io.get_tag() as u16;
+            let desc = io.io_desc().unwrap();
+            let nr_files = match desc.op() {
+                UblkIoOp::Read => 1,
+                UblkIoOp::Write => self.nr_files,
+                _ => {
+                    io.complete_io(-libc::EINVAL);
+                    return Ok(0);
+                }
+            };
+            let stripe = desc.len() / STRIPES;
+            let buf = io.io_buf_addr();
+
+            self.tracker.begin(tag)?;
+            for f in 0..nr_files {
+                for i in 0..STRIPES {
+                    let fd = io_uring::types::Fixed(1 + f);
+                    let off = desc.offset() + (i * stripe) as u64;
+                    let addr = unsafe { buf.add(i * stripe) };
+                    let sqe = match desc.op() {
+                        UblkIoOp::Read => io_uring::opcode::Read::new(fd, addr, stripe as u32)
+                            .offset(off)
+                            .build(),
+                        _ => io_uring::opcode::Write::new(fd, addr, stripe as u32)
+                            .offset(off)
+                            .build(),
+                    };
+                    let data = self
+                        .tracker
+                        .sub_io_user_data(tag, desc.op().into(), f == 0)?;
+
+                    unsafe { io.get_ring().submission().push(&sqe.user_data(data)) }
+                        .map_err(UblkError::UringPushError)?;
+                }
+            }
+            Ok(0)
+        }
+
+        fn handle_tgt_io(
+            &mut self,
+            _ctx: &UblkQueueCtx,
+            io: &mut UblkIOCtx,
+        ) -> Result<i32, UblkError> {
+            self.tracker.handle_tgt_io(io)?;
+            Ok(0)
+        }
+    }
+
+    /// mirror over backing files, and each IO is split into `STRIPES`
+    /// sub-IOs on every file, and read is served from the 1st file
+    fn mirror_tgt(files: Vec<std::fs::File>) -> MockTgt {
+        let nr_files = files.len() as u32;
+
+        MockTgt::new(move |dev, _q_id| {
+            Ok(Box::new(MirrorHandler {
+                nr_files,
+                tracker: UblkSubIoTracker::new(dev.dev_info.queue_depth),
+            }))
+        })
+        .setup(move |dev| {
+            add_files(dev, &files);
+
+            // all sub-IOs of one queue can be queued in SQ
+            dev.tgt.sq_depth *= (STRIPES * files.len()) as u16;
+            dev.tgt.cq_depth = dev.tgt.sq_depth;
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn tokio_handle_io(mut io: libublk::tokio_io::UblkTokioIo, data: Arc<Mutex<Vec<u8>>>) {
+        let desc = io.io_desc();
+        let off = desc.offset() as usize;
+        let bytes = desc.len();
+
+        // complete IOs out of order
+        tokio::time::sleep(Duration::from_millis((desc.start_sector() >> 3) % 4)).await;
+
+        let res = match desc.op() {
+            UblkIoOp::Read => {
+                io.buf_mut()
+                    .copy_from_slice(&data.lock().unwrap()[off..off + bytes]);
+                bytes as i32
+            }
+            UblkIoOp::Write => {
+                data.lock().unwrap()[off..off + bytes].copy_from_slice(io.buf());
+                bytes as i32
+            }
+            UblkIoOp::Flush => 0,
+
+            // request without data doesn't have io buffer
+            UblkIoOp::Discard if io.buf().is_empty() => {
+                data.lock().unwrap()[off..off + bytes].fill(0);
+                bytes as i32
+            }
+            _ => -libc::EINVAL,
+        };
+        io.complete(res);
+    }
+
+    /// ramdisk over Vec, and IO is handled in tokio runtime
+    #[cfg(feature = "tokio")]
+    fn tokio_tgt(rt: tokio::runtime::Handle) -> MockTgt {
+        let data = vec_data();
+
+        MockTgt::new(move |dev, q_id| {
+            let data = data.clone();
+            let h = libublk::tokio_io::UblkTokioHandler::new(dev, q_id, rt.clone(), move |io| {
+                tokio_handle_io(io, data.clone())
+            })?;
+
+            Ok(Box::new(h))
+        })
+    }
+
+    /// IO is completed from other thread via queue's completer, and read
+    /// returns zeroed buffer
+    fn thread_tgt() -> MockTgt {
+        MockTgt::new(|dev, q_id| {
+            let completer = dev
+                .completer(q_id)
+                .ok_or(UblkError::OtherError(-libc::EINVAL))?;
+
+            Ok(Box::new(move |_ctx: &UblkQueueCtx, io: &mut UblkIOCtx| {
+                let tag = io.get_tag() as u16;
+                let bytes = io.io_desc().unwrap().len();
+                let buf = io.io_buf_addr() as usize;
+                let c = completer.clone();
+
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(tag as u64 % 4));
+                    unsafe { std::ptr::write_bytes(buf as *mut u8, 0, bytes) };
+                    c.complete(tag, bytes as i32).unwrap();
+                });
+                Ok(0)
+            }))
+        })
+    }
+
+    struct PoolHandler {
+        data: Arc<Mutex<Vec<u8>>>,
+        pool: Vec<Vec<u8>>,
+
+        /// buffer can't be reused until the io command is completed
+        busy: Vec<Option<Vec<u8>>>,
+    }
+
+    impl PoolHandler {
+        fn take_buf(&mut self, tag: usize) -> *mut u8 {
+            if let Some(buf) = self.busy[tag].take() {
+                self.pool.push(buf);
+            }
+            let mut buf = self.pool.pop().unwrap_or_else(|| vec![0_u8; 64 << 10]);
+            let addr = buf.as_mut_ptr();
+
+            self.busy[tag] = Some(buf);
+            addr
+        }
+    }
+
+    impl UblkQueueHandler for PoolHandler {
+        fn handle_io(&mut self, ctx: &UblkQueueCtx, io: &mut UblkIOCtx) -> Result<i32, UblkError> {
+            let desc = ctx.io_desc(io.get_tag() as u16);
+            let off = desc.offset() as usize;
+            let bytes = desc.len();
+            let data = self.data.clone();
+            let mut d = data.lock().unwrap();
+
+            match desc.op() {
+                UblkIoOp::Read => {
+                    let buf = self.take_buf(io.get_tag() as usize);
+
+                    // the pool buffer is held until next io of this tag
+                    unsafe {
+                        io.set_io_buf_addr(buf);
+                        std::ptr::copy_nonoverlapping(d[off..].as_ptr(), buf, bytes);
+                    }
+                }
+                UblkIoOp::Write => {
+                    // buffer owned by target isn't exposed as slice
+                    assert!(io.io_buf().is_empty());
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(
+                            io.io_buf_addr(),
+                            d[off..].as_mut_ptr(),
+                            bytes,
+                        );
+                    }
+                }
+                _ => return Err(UblkError::OtherError(-libc::EINVAL)),
+            }
+            io.complete_io(bytes as i32);
+            Ok(0)
+        }
+
+        fn prep_get_data(
+            &mut self,
+            _ctx: &UblkQueueCtx,
+            io: &mut UblkIOCtx,
+        ) -> Result<i32, UblkError> {
+            let buf = self.take_buf(io.get_tag() as usize);
+
+            unsafe { io.set_io_buf_addr(buf) };
+            Ok(0)
+        }
+    }
+
+    /// ramdisk whose io buffers are taken from pool when UBLK_F_NEED_GET_DATA
+    /// and `UBLK_DEV_F_LAZY_IO_BUF` are enabled
+    fn pool_tgt(data: &Arc<Mutex<Vec<u8>>>) -> MockTgt {
+        let data = data.clone();
+
+        MockTgt::new(move |dev, _q_id| {
+            Ok(Box::new(PoolHandler {
+                data: data.clone(),
+                pool: Vec::new(),
+                busy: (0..dev.dev_info.queue_depth).map(|_| None).collect(),
+            }))
+        })
+    }
+
+    fn builder(run_dir: &tempfile::TempDir) -> UblkCtrlBuilder {
+        mock::enable();
+        UblkCtrlBuilder::default()
+            .name("mock")
+            .nr_queues(2)
+            .depth(16)
+            .io_buf_bytes(64 << 10)
+            .run_dir(run_dir.path().to_str().unwrap())
+    }
+
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_add(seed)).collect()
+    }
+
+    /// control commands work without ublk_drv
+    #[test]
+    fn test_mock_ctrl() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let ctrl = builder(&dir).build().unwrap();
+        let id = ctrl.dev_info.dev_id;
+
+        assert!(ctrl.state() == UblkDevState::Dead);
+        assert!(libublk::ctrl::list_devices()
+            .unwrap()
+            .iter()
+            .any(|d| d.id == id));
+
+        let mut attached = UblkCtrl::new_simple(id as i32).unwrap();
+        assert!(attached.dev_info.nr_hw_queues == 2);
+        assert!(attached.dev_info.queue_depth == 16);
+        assert!(attached.get_devt().is_err());
+
+        // buffer of GET_* command is filled by driver
+        let mut bm = libublk::ctrl::UblkQueueAffinity::new();
+        attached.get_queue_affinity(1, &mut bm).unwrap();
+        assert!(!bm.to_bits_vec().is_empty());
+        assert!(attached.get_queue_affinity(2, &mut bm).is_err());
+
+        // ioctl encoded command is used, and reported by driver
+        assert!(attached.dev_info.flags == ctrl.dev_info.flags);
+        assert!(
+            libublk::ctrl::UblkFlags::from_bits_retain(attached.dev_info.flags)
+                .contains(libublk::ctrl::UblkFlags::CMD_IOCTL_ENCODE)
+        );
+
+        drop(ctrl);
+        assert!(!libublk::ctrl::list_devices()
+            .unwrap()
+            .iter()
+            .any(|d| d.id == id));
+        match attached.get_info() {
+            Err(e @ UblkError::CtrlCmdError { .. }) => assert!(e.errno() == Some(-libc::ENODEV)),
+            _ => panic!("get_info of deleted device should fail"),
+        }
+    }
+
+    /// legacy control and io commands are used on old driver
+    #[test]
+    fn test_mock_legacy_cmd() {
+        let dir = tempfile::TempDir::new().unwrap();
+
+        mock::enable();
+        mock::set_legacy(true);
+        let res = builder(&dir).create_device(vec_tgt(&vec_data(), false));
+        mock::set_legacy(false);
+
+        let mut dev = res.unwrap();
+        let id = dev.dev_id() as u32;
+        let flags = libublk::ctrl::UblkFlags::from_bits_retain(dev.ctrl().dev_info.flags);
+        assert!(!flags.contains(libublk::ctrl::UblkFlags::CMD_IOCTL_ENCODE));
+
+        dev.start().unwrap();
+        write_read(id, 16);
+        dev.stop().unwrap();
+    }
+
+    /// Run all futures until they are completed, and completions of
+    /// control commands are retrieved after the ring's eventfd is readable
+    fn block_on_all<T>(
+        ring: &UblkCtrlRing,
+        mut futs: Vec<Pin<Box<dyn Future<Output = T>>>>,
+    ) -> Vec<T> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut res: Vec<Option<T>> = futs.iter().map(|_| None).collect();
+
+        while res.iter().any(|r| r.is_none()) {
+            for (f, r) in futs.iter_mut().zip(res.iter_mut()) {
+                if r.is_none() {
+                    if let Poll::Ready(v) = f.as_mut().poll(&mut cx) {
+                        *r = Some(v);
+                    }
+                }
+            }
+
+            let mut pfd = libc::pollfd {
+                fd: ring.event_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe { libc::poll(&mut pfd, 1, 10) };
+            ring.reap_events();
+        }
+        res.into_iter().flatten().collect()
+    }
+
+    /// many async control commands are in flight on one shared ring
+    #[test]
+    fn test_mock_ctrl_async() {
+        let dir = tempfile::TempDir::new().unwrap();
+        mock::enable();
+        let ring = UblkCtrlRing::new().unwrap();
+
+        type CtrlFut = Pin<Box<dyn Future<Output = UblkCtrl>>>;
+
+        let futs: Vec<CtrlFut> = (0..8)
+            .map(|_| {
+                let b = builder(&dir).ctrl_ring(&ring);
+                let f: CtrlFut = Box::pin(async move {
+                    let mut ctrl = b.build_async().await.unwrap();
+
+                    ctrl.get_info_async().await.unwrap();
+                    assert!(ctrl.state() == UblkDevState::Dead);
+                    let p = ctrl.get_params_async(Default::default()).await.unwrap();
+                    assert!(p.devt.char_minor == ctrl.dev_info.dev_id);
+                    ctrl
+                });
+                f
+            })
+            .collect();
+        let ctrls = block_on_all(&ring, futs);
+        let mut ids: Vec<u32> = ctrls.iter().map(|c| c.dev_info.dev_id).collect();
+
+        ids.sort();
+        ids.dedup();
+        assert!(ids.len() == 8);
+
+        let futs: Vec<CtrlFut> = ctrls
+            .into_iter()
+            .map(|mut ctrl| {
+                let f: CtrlFut = Box::pin(async move {
+                    ctrl.del_async().await.unwrap();
+                    assert!(ctrl.get_info_async().await.is_err());
+                    ctrl
+                });
+                f
+            })
+            .collect();
+        block_on_all(&ring, futs);
+
+        assert!(ring.nr_inflight() == 0);
+        assert!(!libublk::ctrl::list_devices()
+            .unwrap()
+            .iter()
+            .any(|d| ids.contains(&d.id)));
+    }
+
+    /// sync command blocked on shared ring doesn't block other commands
+    #[test]
+    fn test_mock_ctrl_sync_wait() {
+        let dir = tempfile::TempDir::new().unwrap();
+        mock::enable();
+        let ring = UblkCtrlRing::new().unwrap();
+        let mut ctrl = builder(&dir).ctrl_ring(&ring).build().unwrap();
+        let id = ctrl.dev_info.dev_id;
+
+        //START_DEV isn't completed until all queues are ready
+        let start = std::thread::spawn(move || {
+            let res = ctrl.start(unsafe { libc::getpid() }, false);
+            (ctrl, res)
+        });
+        std::thread::sleep(Duration::from_millis(50));
+
+        let mut other = builder(&dir).ctrl_ring(&ring).build().unwrap();
+        other.get_info().unwrap();
+        assert!(ring.nr_inflight() == 1);
+
+        UblkCtrl::new_simple(id as i32).unwrap().stop().unwrap();
+        let (ctrl, res) = start.join().unwrap();
+        assert!(res.is_err());
+        drop(ctrl);
+        drop(other);
+        assert!(ring.nr_inflight() == 0);
+    }
+
+    /// completions of commands polled by token are demultiplexed
+    #[test]
+    fn test_mock_poll_cmd() {
+        let dir = tempfile::TempDir::new().unwrap();
+        mock::enable();
+        let ring = UblkCtrlRing::new().unwrap();
+        let mut devs: Vec<_> = (0..2)
+            .map(|_| {
+                builder(&dir)
+                    .ctrl_ring(&ring)
+                    .create_device(vec_tgt(&vec_data(), false))
+                    .unwrap()
+            })
+            .collect();
+        let pid = unsafe { libc::getpid() };
+        let tokens: Vec<i32> = devs
+            .iter_mut()
+            .map(|d| d.ctrl().start(pid, true).unwrap())
+            .collect();
+
+        let poll = |ctrl: &mut UblkCtrl, t: i32| loop {
+            match ctrl.poll_cmd(t) {
+                Err(e) if e.errno() == Some(-libc::EAGAIN) => {
+                    std::thread::sleep(Duration::from_millis(1))
+                }
+                res => break res,
+            }
+        };
+
+        for (d, t) in devs.iter_mut().zip(tokens.iter()).rev() {
+            let ctrl = d.ctrl();
+
+            assert!(poll(ctrl, *t).unwrap() == 0);
+            ctrl.get_info().unwrap();
+            assert!(ctrl.state() == UblkDevState::Live);
+
+            // polled result is same with the sync one
+            assert!(ctrl.start(pid, false).unwrap() == -libc::EBUSY);
+            let t = ctrl.start(pid, true).unwrap();
+            assert!(poll(ctrl, t).unwrap() == -libc::EBUSY);
+        }
+        for d in &mut devs {
+            d.stop().unwrap();
+        }
+    }
+
+    /// data written to mock device is read back via queue io handling
+    #[test]
+    fn test_mock_io() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut dev = builder(&dir)
+            .create_device(vec_tgt(&vec_data(), false))
+            .unwrap();
+        let id = dev.dev_id() as u32;
+
+        dev.start().unwrap();
+        dev.ctrl()
+            .wait_for_state(UblkDevState::Live, Duration::from_secs(2))
+            .unwrap();
+        assert!(dev.ctrl().get_devt().unwrap().disk_minor == id);
+        write_read(id, 64);
+
+        dev.stop().unwrap();
+        dev.wait();
+        assert!(
+            mock::submit_io(id, 0, sys::UBLK_IO_OP_READ, 0, 8, &[])
+                .unwrap()
+                .wait()
+                .0
+                == -libc::EIO
+        );
+    }
+
+    fn write_read(id: u32, nr: u32) {
+        let ios: Vec<_> = (0..nr)
+            .map(|i| {
+                let w = pattern(i as u8, 4096);
+                mock::submit_io(
+                    id,
+                    (i % 2) as u16,
+                    sys::UBLK_IO_OP_WRITE,
+                    i as u64 * 8,
+                    8,
+                    &w,
+                )
+                .unwrap()
+            })
+            .collect();
+        for io in ios {
+            assert!(io.wait().0 == 4096);
+        }
+
+        for i in 0..nr {
+            let io = mock::submit_io(id, 1, sys::UBLK_IO_OP_READ, i as u64 * 8, 8, &[]).unwrap();
+            let (res, data) = io.wait();
+
+            assert!(res == 4096);
+            assert!(data == pattern(i as u8, 4096));
+        }
+    }
+
+    /// write data is retrieved by UBLK_IO_NEED_GET_DATA, into queue's own
+    /// buffer or buffer chosen by target
+    #[test]
+    fn test_mock_need_get_data() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut dev = builder(&dir)
+            .ctrl_flags(libublk::ctrl::UblkFlags::NEED_GET_DATA)
+            .create_device(vec_tgt(&vec_data(), false))
+            .unwrap();
+
+        dev.start().unwrap();
+        write_read(dev.dev_id() as u32, 64);
+        dev.stop().unwrap();
+
+        //lazy io buffer depends on UBLK_F_NEED_GET_DATA
+        let data = Arc::new(Mutex::new(vec![0_u8; DEV_SIZE as usize]));
+        assert!(builder(&dir)
+            .dev_flags(libublk::io::UBLK_DEV_F_LAZY_IO_BUF)
+            .create_device(pool_tgt(&data))
+            .is_err());
+
+        let mut dev = builder(&dir)
+            .ctrl_flags(libublk::ctrl::UblkFlags::NEED_GET_DATA)
+            .dev_flags(libublk::io::UBLK_DEV_F_LAZY_IO_BUF)
+            .create_device(pool_tgt(&data))
+            .unwrap();
+
+        dev.start().unwrap();
+        write_read(dev.dev_id() as u32, 64);
+        assert!(data.lock().unwrap()[..4096] == pattern(0, 4096));
+        dev.stop().unwrap();
+    }
+
+    /// io descriptor is decoded into typed op, flags and byte range
+    #[test]
+    fn test_mock_io_desc() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let descs = Arc::new(Mutex::new(Vec::new()));
+        let mut dev = builder(&dir).create_device(desc_tgt(&descs)).unwrap();
+        let id = dev.dev_id() as u32;
+
+        dev.start().unwrap();
+
+        let cases = [
+            (
+                sys::UBLK_IO_OP_WRITE | sys::UBLK_IO_F_FUA,
+                UblkIoOp::Write,
+                UblkIoFlags::FUA,
+            ),
+            (sys::UBLK_IO_OP_READ, UblkIoOp::Read, UblkIoFlags::empty()),
+            (sys::UBLK_IO_OP_FLUSH, UblkIoOp::Flush, UblkIoFlags::empty()),
+            (
+                sys::UBLK_IO_OP_WRITE_ZEROES | sys::UBLK_IO_F_NOUNMAP,
+                UblkIoOp::WriteZeroes,
+                UblkIoFlags::NOUNMAP,
+            ),
+            (
+                sys::UBLK_IO_OP_DISCARD | sys::UBLK_IO_F_FAILFAST_DEV,
+                UblkIoOp::Discard,
+                UblkIoFlags::FAILFAST_DEV,
+            ),
+            (0x7f, UblkIoOp::Unknown(0x7f), UblkIoFlags::empty()),
+        ];
+        for (i, (op_flags, _, _)) in cases.iter().enumerate() {
+            let w = pattern(i as u8, 4096);
+            let io = mock::submit_io(id, 0, *op_flags, 8 * i as u64 + 8, 8, &w).unwrap();
+
+            assert!(io.wait().0 == 4096);
+        }
+
+        let descs = descs.lock().unwrap();
+        assert!(descs.len() == cases.len());
+        for (i, (desc, (op_flags, op, flags))) in descs.iter().zip(cases.iter()).enumerate() {
+            assert!(desc.op() == *op);
+            assert!(u32::from(desc.op()) == op_flags & 0xff);
+            assert!(desc.flags() == *flags);
+            assert!(desc.start_sector() == 8 * i as u64 + 8);
+            assert!(desc.offset() == (8 * i as u64 + 8) << 9);
+            assert!(desc.nr_sectors() == 8 && desc.len() == 4096);
+        }
+        drop(descs);
+        dev.stop().unwrap();
+    }
+
+    /// io buffer slices cover bytes of the request, and request which can't
+    /// be held in io buffer is failed without reaching target
+    #[test]
+    fn test_mock_io_buf() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let descs = Arc::new(Mutex::new(Vec::new()));
+        let mut dev = builder(&dir).create_device(desc_tgt(&descs)).unwrap();
+        let id = dev.dev_id() as u32;
+        let max_sectors = dev.ctrl().dev_info.max_io_buf_bytes >> 9;
+
+        dev.start().unwrap();
+        let io = mock::submit_io_unchecked(id, 0, sys::UBLK_IO_OP_READ, 0, max_sectors + 8, &[])
+            .unwrap();
+        assert!(io.wait().0 == -libc::EIO);
+        assert!(descs.lock().unwrap().is_empty());
+
+        // request without data isn't limited by io buffer
+        let op = sys::UBLK_IO_OP_DISCARD;
+        let io = mock::submit_io_unchecked(id, 0, op, 0, max_sectors + 8, &[]).unwrap();
+        assert!(io.wait().0 == ((max_sectors + 8) << 9) as i32);
+        assert!(descs.lock().unwrap().len() == 1);
+        dev.stop().unwrap();
+
+        let mut dev = builder(&dir)
+            .create_device(vec_tgt(&vec_data(), false))
+            .unwrap();
+        dev.start().unwrap();
+        write_read(dev.dev_id() as u32, 16);
+        dev.stop().unwrap();
+    }
+
+    /// queue rings are setup with ring flags of target
+    #[test]
+    fn test_mock_ring_flags() {
+        use libublk::io::{
+            UblkRingCfg, UBLK_RING_F_DEFER_TASKRUN, UBLK_RING_F_IOPOLL, UBLK_RING_F_SINGLE_ISSUER,
+            UBLK_RING_F_SQPOLL, UBLK_RING_F_SQ_AFF,
+        };
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let ring_tgt = |flags: u64, cfg: UblkRingCfg| ring_tgt(&vec_data(), flags, cfg);
+        let sqpoll = UblkRingCfg {
+            sq_thread_idle: 100,
+            ..Default::default()
+        };
+        let sq_aff = |cpus: Vec<u32>| UblkRingCfg {
+            sq_thread_cpus: cpus,
+            ..sqpoll.clone()
+        };
+
+        for (flags, cfg) in [
+            (
+                UBLK_RING_F_SINGLE_ISSUER | UBLK_RING_F_DEFER_TASKRUN,
+                UblkRingCfg::default(),
+            ),
+            (UBLK_RING_F_SQPOLL, sqpoll.clone()),
+            (UBLK_RING_F_SQPOLL | UBLK_RING_F_SQ_AFF, sq_aff(vec![0, 0])),
+            (UBLK_RING_F_IOPOLL | UBLK_RING_F_SQPOLL, sqpoll.clone()),
+        ] {
+            let mut dev = builder(&dir).create_device(ring_tgt(flags, cfg)).unwrap();
+
+            dev.start().unwrap();
+            write_read(dev.dev_id() as u32, 64);
+            dev.stop().unwrap();
+        }
+
+        // invalid combinations are rejected before queues are started
+        for (flags, cfg) in [
+            (UBLK_RING_F_DEFER_TASKRUN, UblkRingCfg::default()),
+            (
+                UBLK_RING_F_SQPOLL | UBLK_RING_F_SINGLE_ISSUER | UBLK_RING_F_DEFER_TASKRUN,
+                sqpoll.clone(),
+            ),
+            //SQ_AFF without SQPOLL, or without CPU for queue 1
+            (UBLK_RING_F_SQ_AFF, sq_aff(vec![0, 0])),
+            (UBLK_RING_F_SQPOLL | UBLK_RING_F_SQ_AFF, sq_aff(vec![0])),
+            (1_u64 << 63, UblkRingCfg::default()),
+        ] {
+            assert!(builder(&dir).create_device(ring_tgt(flags, cfg)).is_err());
+        }
+    }
+
+    /// backing file opened with O_DIRECT is read and written via IOPOLL
+    /// target ring, and the queue ring still carries io commands
+    #[test]
+    fn test_mock_iopoll_io() {
+        use libublk::io::{UblkRingCfg, UBLK_RING_F_IOPOLL};
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("backing");
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(&path)
+            .unwrap();
+        file.set_len(DEV_SIZE).unwrap();
+        let tgt = MockTgt::new(|_dev, _q_id| {
+            Ok(Box::new(|_ctx: &UblkQueueCtx, io: &mut UblkIOCtx| {
+                if io.is_tgt_io() {
+                    let res = io.result();
+
+                    io.complete_io(res);
+                    return Ok(0);
+                }
+
+                let tag = io.get_tag() as u16;
+                let desc = io.io_desc().unwrap();
+                let fd = io_uring::types::Fixed(1);
+                let (buf, bytes) = (io.io_buf_addr(), desc.len() as u32);
+                let sqe = match desc.op() {
+                    UblkIoOp::Read => io_uring::opcode::Read::new(fd, buf, bytes)
+                        .offset(desc.offset())
+                        .build(),
+                    UblkIoOp::Write => io_uring::opcode::Write::new(fd, buf, bytes)
+                        .offset(desc.offset())
+                        .build(),
+                    _ => {
+                        io.complete_io(-libc::EINVAL);
+                        return Ok(0);
+                    }
+                };
+                let data = UblkIOCtx::build_user_data(tag, desc.op().into(), 0, true);
+
+                unsafe { io.push_tgt_sqe(&sqe.user_data(data)) }?;
+                Ok(0)
+            }))
+        })
+        .setup(move |dev| {
+            add_files(dev, std::slice::from_ref(&file));
+            dev.tgt.ring_flags = UBLK_RING_F_IOPOLL;
+            dev.tgt.ring_cfg = UblkRingCfg::default();
+            Ok(())
+        });
+        let mut dev = builder(&dir).create_device(tgt).unwrap();
+        let id = dev.dev_id() as u32;
+
+        dev.start().unwrap();
+
+        // CQE of IOPOLL ring is handled even though the backing device
+        // can't be polled, and IO fails with -EOPNOTSUPP
+        for op in [sys::UBLK_IO_OP_WRITE, sys::UBLK_IO_OP_READ] {
+            let io = mock::submit_io(id, 0, op, 0, 8, &pattern(0, 4096)).unwrap();
+            let res = io.wait().0;
+
+            assert!(res == 4096 || res == -libc::EOPNOTSUPP);
+            if res < 0 {
+                dev.stop().unwrap();
+                return;
+            }
+        }
+
+        write_read(id, 32);
+        let backing = std::fs::File::open(&path).unwrap();
+        for i in 0..32_u32 {
+            let mut disk = vec![0_u8; 4096];
+
+            backing.read_exact_at(&mut disk, i as u64 * 4096).unwrap();
+            assert!(disk == pattern(i as u8, 4096));
+        }
+        let io = mock::submit_io(id, 0, sys::UBLK_IO_OP_DISCARD, 0, 8, &[]).unwrap();
+        assert!(io.wait().0 == -libc::EINVAL);
+        dev.stop().unwrap();
+    }
+
+    fn __test_mock_file_io(dev_flags: u32) {
+        let dir = tempfile::TempDir::new().unwrap();
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(DEV_SIZE).unwrap();
+        let backing = file.try_clone().unwrap();
+        let mut dev = builder(&dir)
+            .dev_flags(dev_flags)
+            .create_device(file_tgt(
+                file,
+                (dev_flags & libublk::io::UBLK_DEV_F_FIXED_BUF) != 0,
+            ))
+            .unwrap();
+        let id = dev.dev_id() as u32;
+
+        dev.start().unwrap();
+        write_read(id, 32);
+        for i in 0..32_u32 {
+            let mut disk = vec![0_u8; 4096];
+
+            backing.read_exact_at(&mut disk, i as u64 * 4096).unwrap();
+            assert!(disk == pattern(i as u8, 4096));
+        }
+        let io = mock::submit_io(id, 0, sys::UBLK_IO_OP_DISCARD, 0, 8, &[]).unwrap();
+        assert!(io.wait().0 == -libc::EINVAL);
+
+        dev.stop().unwrap();
+    }
+
+    /// IO is fanned out to sub-IOs, and completed after all sub-IOs are
+    /// done with the 1st error or bytes of the 1st mirror
+    #[test]
+    fn test_mock_sub_io() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let files: Vec<_> = (0..2)
+            .map(|_| {
+                let f = tempfile::tempfile().unwrap();
+                f.set_len(DEV_SIZE).unwrap();
+                f
+            })
+            .collect();
+        let backing: Vec<_> = files.iter().map(|f| f.try_clone().unwrap()).collect();
+        let mut dev = builder(&dir).create_device(mirror_tgt(files)).unwrap();
+
+        dev.start().unwrap();
+        write_read(dev.dev_id() as u32, 32);
+        for f in &backing {
+            let mut disk = vec![0_u8; 4096];
+
+            f.read_exact_at(&mut disk, 4096).unwrap();
+            assert!(disk == pattern(1, 4096));
+        }
+        dev.stop().unwrap();
+
+        // write to the read-only mirror fails
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        tmp.as_file().set_len(DEV_SIZE).unwrap();
+        let files = vec![
+            tempfile::tempfile().unwrap(),
+            std::fs::File::open(tmp.path()).unwrap(),
+        ];
+        files[0].set_len(DEV_SIZE).unwrap();
+        let mut dev = builder(&dir).create_device(mirror_tgt(files)).unwrap();
+        let id = dev.dev_id() as u32;
+
+        dev.start().unwrap();
+        let io = mock::submit_io(id, 0, sys::UBLK_IO_OP_WRITE, 0, 8, &pattern(0, 4096)).unwrap();
+        assert!(io.wait().0 == -libc::EBADF);
+        let io = mock::submit_io(id, 0, sys::UBLK_IO_OP_READ, 0, 8, &[]).unwrap();
+        let (res, data) = io.wait();
+        assert!(res == 4096 && data == pattern(0, 4096));
+        dev.stop().unwrap();
+    }
+
+    /// IO is handled by async tasks, which await io_uring ops
+    #[test]
+    fn test_mock_async_io() {
+        __test_mock_file_io(0);
+    }
+
+    /// same with test_mock_async_io, but io buffers are registered as fixed
+    /// buffers, and IO is handled by ReadFixed/WriteFixed
+    #[test]
+    fn test_mock_fixed_buf() {
+        __test_mock_file_io(libublk::io::UBLK_DEV_F_FIXED_BUF);
+    }
+
+    /// target whose task drops one in-flight op, which polls `rfd` of one
+    /// pipe never written
+    fn cancel_tgt(rfd: i32) -> MockTgt {
+        MockTgt::new(move |_dev, _q_id| {
+            Ok(Box::new(UblkAsyncHandler::new(
+                move |io: UblkIoTask| async move {
+                    let sqe = io_uring::opcode::PollAdd::new(
+                        io_uring::types::Fd(rfd),
+                        libc::POLLIN as u32,
+                    )
+                    .build();
+                    let mut op = Box::pin(unsafe { io.submit(sqe) });
+
+                    // submit the op, then drop it before completion
+                    std::future::poll_fn(|cx| {
+                        assert!(op.as_mut().poll(cx).is_pending());
+                        Poll::Ready(())
+                    })
+                    .await;
+                    drop(op);
+                    io.io_desc().len() as i32
+                },
+            )))
+        })
+    }
+
+    /// op dropped before completion is cancelled, and its io command is
+    /// completed after the op's CQE is received
+    #[test]
+    fn test_mock_cancel_op() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut fds = [0_i32; 2];
+
+        assert!(unsafe { libc::pipe(fds.as_mut_ptr()) } == 0);
+        let mut dev = builder(&dir).create_device(cancel_tgt(fds[0])).unwrap();
+        let id = dev.dev_id() as u32;
+
+        dev.start().unwrap();
+        let io = mock::submit_io(id, 0, sys::UBLK_IO_OP_READ, 0, 8, &[]).unwrap();
+        assert!(io.wait_timeout(Duration::from_secs(5)).unwrap().0 == 4096);
+        dev.stop().unwrap();
+        drop(dev);
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    /// IO is completed from other thread via queue's completer, which is
+    /// only available with UBLK_DEV_F_COMP_CHAN
+    #[test]
+    fn test_mock_completer() {
+        let dir = tempfile::TempDir::new().unwrap();
+
+        //completer is only available with UBLK_DEV_F_COMP_CHAN
+        assert!(builder(&dir).create_device(thread_tgt()).is_err());
+
+        let mut dev = builder(&dir)
+            .dev_flags(libublk::io::UBLK_DEV_F_COMP_CHAN)
+            .create_device(thread_tgt())
+            .unwrap();
+        let id = dev.dev_id() as u32;
+
+        dev.start().unwrap();
+        let ios: Vec<_> = (0..64_u32)
+            .map(|i| {
+                mock::submit_io(
+                    id,
+                    (i % 2) as u16,
+                    sys::UBLK_IO_OP_READ,
+                    i as u64 * 8,
+                    8,
+                    &[],
+                )
+                .unwrap()
+            })
+            .collect();
+        for io in ios {
+            let (res, data) = io.wait();
+
+            assert!(res == 4096);
+            assert!(data == vec![0_u8; 4096]);
+        }
+
+        dev.stop().unwrap();
+    }
+
+    /// IO is handled in tokio runtime and completed out of order
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_mock_tokio_io() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_time()
+            .build()
+            .unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let tgt = tokio_tgt(rt.handle().clone());
+        let mut dev = builder(&dir)
+            .dev_flags(libublk::io::UBLK_DEV_F_COMP_CHAN)
+            .create_device(tgt)
+            .unwrap();
+        let id = dev.dev_id() as u32;
+
+        dev.start().unwrap();
+        write_read(id, 32);
+
+        // discard which can't be held in io buffer still reaches target
+        let sectors = (dev.ctrl().dev_info.max_io_buf_bytes >> 9) * 2;
+        let op = sys::UBLK_IO_OP_DISCARD;
+        let io = mock::submit_io_unchecked(id, 0, op, 0, sectors, &[]).unwrap();
+        assert!(io.wait().0 == (sectors << 9) as i32);
+        let io = mock::submit_io(id, 1, sys::UBLK_IO_OP_READ, 8, 8, &[]).unwrap();
+        assert!(io.wait() == (4096, vec![0_u8; 4096]));
+
+        let io = mock::submit_io(id, 0, sys::UBLK_IO_OP_WRITE_SAME, 0, 8, &[]).unwrap();
+        assert!(io.wait().0 == -libc::EINVAL);
+
+        dev.stop().unwrap();
+    }
+
+    /// ramdisk whose queue 1 can't be setup
+    fn bad_queue_tgt() -> MockTgt {
+        let tgt = vec_tgt(&vec_data(), false);
+
+        MockTgt::new(move |dev, q_id| {
+            if q_id == 1 {
+                return Err(UblkError::OtherError(-libc::ENOMEM));
+            }
+            tgt.queue_handler(dev, q_id)
+        })
+    }
+
+    /// queue threads which are setup are joined if any queue fails
+    #[test]
+    fn test_mock_queue_failure() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let res = builder(&dir).create_device(bad_queue_tgt());
+
+        assert!(res.err().and_then(|e| e.errno()) == Some(-libc::ENOMEM));
+    }
+
+    /// queue exits after the device is stopped, even though there is IO
+    /// which isn't completed by target
+    #[test]
+    fn test_mock_stop() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut dev = builder(&dir)
+            .create_device(vec_tgt(&vec_data(), true))
+            .unwrap();
+        let id = dev.dev_id() as u32;
+
+        dev.start().unwrap();
+        let held = mock::submit_io(id, 1, sys::UBLK_IO_OP_READ, HOLD_SECTOR, 8, &[]).unwrap();
+        assert!(held.wait_timeout(Duration::from_millis(100)).is_none());
+
+        dev.stop().unwrap();
+        assert!(dev.ctrl().state() == UblkDevState::Dead);
+        drop(dev);
+
+        //the held IO is failed after the device is deleted
+        assert!(held.wait().0 == -libc::EIO);
+    }
+
+    fn __test_mock_recovery(mode: UblkRecoveryMode) -> i32 {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data = vec_data();
+        let tgt = vec_tgt(&data, true);
+        let mut dev = builder(&dir)
+            .recovery_mode(mode)
+            .dev_flags(libublk::io::UBLK_DEV_F_TRACK_INFLIGHT)
+            .create_device(tgt)
+            .unwrap();
+        let id = dev.dev_id();
+
+        dev.start().unwrap();
+        let held =
+            mock::submit_io(id as u32, 0, sys::UBLK_IO_OP_READ, HOLD_SECTOR, 8, &[]).unwrap();
+        assert!(held.wait_timeout(Duration::from_millis(100)).is_none());
+
+        mock::kill_daemon(id as u32).unwrap();
+        std::mem::forget(dev);
+
+        let mut ctrl = UblkCtrl::new_simple(id).unwrap();
+        assert!(ctrl.state() == UblkDevState::Quiesced);
+
+        //failed recovery keeps the device quiesced, so it can be
+        //recovered again
+        let bad = ring_tgt(&data, 1 << 63, Default::default());
+        assert!(builder(&dir)
+            .id(id)
+            .recover(true)
+            .dev_flags(libublk::io::UBLK_DEV_F_TRACK_INFLIGHT)
+            .create_device(bad)
+            .is_err());
+        ctrl.get_info().unwrap();
+        assert!(ctrl.state() == UblkDevState::Quiesced);
+
+        let mut dev = builder(&dir)
+            .id(id)
+            .recover(true)
+            .dev_flags(libublk::io::UBLK_DEV_F_TRACK_INFLIGHT)
+            .create_device(vec_tgt(&data, false))
+            .unwrap();
+        dev.start().unwrap();
+        ctrl.wait_for_state(UblkDevState::Live, Duration::from_secs(2))
+            .unwrap();
+
+        let res = held.wait().0;
+        dev.stop().unwrap();
+        assert!(!dir.path().join(format!("{:04}.lock", id)).exists());
+        assert!(!dir.path().join(format!("{:04}.json", id)).exists());
+
+        //the recovered device is deleted with its handle
+        drop(dev);
+        assert!(UblkCtrl::new_simple(id).is_err());
+        res
+    }
+
+    /// IO in flight when the daemon crashed is passed to reconcile()
+    #[test]
+    fn test_mock_reconcile() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let data = vec_data();
+        let tgt = vec_tgt(&data, true);
+        let mut dev = builder(&dir)
+            .recovery_mode(UblkRecoveryMode::FailInflight)
+            .dev_flags(libublk::io::UBLK_DEV_F_TRACK_INFLIGHT)
+            .create_device(tgt)
+            .unwrap();
+        let id = dev.dev_id();
+
+        dev.start().unwrap();
+        let buf = pattern(1, 4096);
+        let held =
+            mock::submit_io(id as u32, 1, sys::UBLK_IO_OP_WRITE, HOLD_SECTOR, 8, &buf).unwrap();
+        assert!(held.wait_timeout(Duration::from_millis(100)).is_none());
+
+        mock::kill_daemon(id as u32).unwrap();
+        std::mem::forget(dev);
+
+        let inflight = Arc::new(Mutex::new(Vec::new()));
+        let tgt = ReconcileTgt {
+            tgt: vec_tgt(&data, false),
+            inflight: inflight.clone(),
+        };
+        let mut dev = builder(&dir)
+            .id(id)
+            .recover(true)
+            .dev_flags(libublk::io::UBLK_DEV_F_TRACK_INFLIGHT)
+            .create_device(tgt)
+            .unwrap();
+
+        let ios = inflight.lock().unwrap().clone();
+        assert!(ios.len() == 1);
+        assert!(ios[0].q_id == 1 && ios[0].tag == 0);
+        assert!(ios[0].op() == sys::UBLK_IO_OP_WRITE);
+        assert!(ios[0].start_sector == HOLD_SECTOR && ios[0].nr_sectors == 8);
+
+        dev.start().unwrap();
+        assert!(held.wait().0 == -libc::EIO);
+        dev.stop().unwrap();
+    }
+
+    /// in-flight IO is failed or re-issued after daemon is recovered
+    #[test]
+    fn test_mock_recovery() {
+        assert!(__test_mock_recovery(UblkRecoveryMode::FailInflight) == -libc::EIO);
+        assert!(__test_mock_recovery(UblkRecoveryMode::Reissue) == 4096);
+    }
+}